@@ -1,15 +1,165 @@
 use std::{
-    collections::{HashMap, VecDeque},
+    collections::{HashMap, HashSet, VecDeque},
     fs::File,
-    io::Write,
-    path::PathBuf,
-    process::Command,
+    io::{IsTerminal, Write},
+    path::{Path, PathBuf},
+    process::{Command, ExitCode},
+    sync::{Mutex, OnceLock},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-use clap::Parser;
+use clap::{Parser, ValueEnum};
+use serde::{Deserialize, Serialize};
+use unicode_normalization::UnicodeNormalization;
 
 type GenericResult<T> = Result<T, Box<dyn std::error::Error + 'static>>;
 
+/// Major phases of an analysis, timed with `--benchmark`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Phase {
+    QueryTree,
+    ParseTree,
+    QuerySizes,
+    CalculateGraphProperties,
+    Generate(OutputFormat),
+}
+
+/// Time spent in every phase, only collected with `--benchmark`.
+#[derive(Default)]
+struct PhaseTimer {
+    timings: Vec<(Phase, Duration)>,
+    // Every size query that actually ran nix-store, to report the slowest ones.
+    size_queries: Vec<(String, Duration)>,
+    parsed_packages: usize,
+}
+
+static PHASE_TIMER: OnceLock<Mutex<PhaseTimer>> = OnceLock::new();
+
+impl PhaseTimer {
+    /// Adds `duration` to the time spent in `phase`. Does nothing without `--benchmark`.
+    fn record(phase: Phase, duration: Duration) {
+        let Some(timer) = PHASE_TIMER.get() else {
+            return;
+        };
+
+        let mut timer = timer.lock().unwrap();
+        match timer.timings.iter_mut().find(|(other, _)| *other == phase) {
+            Some((_, total)) => *total += duration,
+            None => timer.timings.push((phase, duration)),
+        }
+    }
+
+    fn record_size_query(path: &str, duration: Duration) {
+        if let Some(timer) = PHASE_TIMER.get() {
+            timer
+                .lock()
+                .unwrap()
+                .size_queries
+                .push((path.to_string(), duration));
+        }
+        Self::record(Phase::QuerySizes, duration);
+    }
+
+    fn elapsed(phase: Phase) -> Duration {
+        PHASE_TIMER.get().map_or(Duration::ZERO, |timer| {
+            timer
+                .lock()
+                .unwrap()
+                .timings
+                .iter()
+                .find(|(other, _)| *other == phase)
+                .map_or(Duration::ZERO, |&(_, duration)| duration)
+        })
+    }
+
+    /// Describes how long `phase` took, e.g. `Query sizes: 15.20s (500×avg 30.40ms)`.
+    fn describe(&self, phase: Phase, duration: Duration) -> String {
+        match phase {
+            Phase::QueryTree => format!("Query tree: {:.2?}", duration),
+            Phase::ParseTree => format!(
+                "Parse tree ({} packages): {:.2?}",
+                self.parsed_packages, duration
+            ),
+            Phase::QuerySizes => format!(
+                "Query sizes: {:.2?} ({}×avg {:.2?})",
+                duration,
+                self.size_queries.len(),
+                duration / self.size_queries.len().max(1) as u32
+            ),
+            Phase::CalculateGraphProperties => {
+                format!("Calculate graph properties: {:.2?}", duration)
+            }
+            Phase::Generate(format) => format!(
+                "Generate {}: {:.2?}",
+                format.to_possible_value().unwrap().get_name(),
+                duration
+            ),
+        }
+    }
+}
+
+/// Adds the time between its creation and being dropped to a phase, like `Span` does for logs.
+struct PhaseSpan {
+    phase: Phase,
+    start: Instant,
+}
+
+impl PhaseSpan {
+    fn enter(phase: Phase) -> Self {
+        Self {
+            phase,
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Drop for PhaseSpan {
+    fn drop(&mut self) {
+        PhaseTimer::record(self.phase, self.start.elapsed());
+    }
+}
+
+/// Starts collecting phase timings, and prints them to stderr once dropped, slowest phase first.
+/// With `RUST_LOG=info` or higher, also prints the 10 slowest size queries.
+struct BenchmarkReport {
+    start: Instant,
+}
+
+impl BenchmarkReport {
+    fn start() -> Self {
+        let _ = PHASE_TIMER.set(Mutex::new(PhaseTimer::default()));
+        Self {
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Drop for BenchmarkReport {
+    fn drop(&mut self) {
+        let Some(timer) = PHASE_TIMER.get() else {
+            return;
+        };
+        let mut timer = timer.lock().unwrap();
+
+        let mut timings = timer.timings.clone();
+        timings.sort_by(|(_, first), (_, second)| second.cmp(first));
+        for (phase, duration) in timings {
+            eprintln!("{}", timer.describe(phase, duration));
+        }
+        eprintln!("Total: {:.2?}", self.start.elapsed());
+
+        if tracing::enabled!(tracing::Level::INFO) && !timer.size_queries.is_empty() {
+            timer
+                .size_queries
+                .sort_by(|(_, first), (_, second)| second.cmp(first));
+            eprintln!("Slowest size queries:");
+            for (path, duration) in timer.size_queries.iter().take(10) {
+                eprintln!("  {}: {:.2?}", path, duration);
+            }
+        }
+    }
+}
+
 #[derive(Clone)]
 struct Package {
     level: usize,
@@ -23,20 +173,136 @@ struct Package {
     short_name: String,
     // Will be used when generating a graphviz file.
     graph_size: f32,
+    // Unix timestamp of when the package was registered in the store, only queried with `--include-registration-time`.
+    registration_time: Option<u64>,
+    // Whether the package isn't in the closure given to `--color-new-packages`.
+    is_new: bool,
+    // Size of the compressed NAR in the binary cache, only queried with `--include-nar-size`.
+    download_size_bytes: Option<usize>,
+    // Where the flake input comes from (e.g. `github:NixOS/nixpkgs/<rev>`), only for trees built with `--from-flake-lock`.
+    flake_url: Option<String>,
+    // Whether this is a derivation (or one of its inputs) used to build the runtime closure, only added with `--include-build-time-deps`.
+    is_build_dep: bool,
+}
+
+/// Packages are equal (and hash the same) when they have the same store path, since store paths uniquely identify packages.
+/// They're ordered by size instead, largest first, so sorting a `Vec<Package>` puts the largest packages at the start.
+/// This means two packages with the same size compare as `Ordering::Equal` without being equal, so don't mix them in a `BTreeSet`
+/// expecting deduplication by path.
+impl PartialEq for Package {
+    fn eq(&self, other: &Self) -> bool {
+        self.path == other.path
+    }
+}
+
+impl Eq for Package {}
+
+impl std::hash::Hash for Package {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.path.hash(state);
+    }
+}
+
+impl PartialOrd for Package {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Package {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        other.size_bytes.cmp(&self.size_bytes)
+    }
+}
+
+/// Directory of the nix store, without a trailing slash. Set once from `NixArgs`, defaulting to `/nix/store`.
+static STORE_PREFIX: OnceLock<String> = OnceLock::new();
+
+fn store_prefix() -> &'static str {
+    STORE_PREFIX.get_or_init(|| "/nix/store".to_string())
+}
+
+/// Splits a store path into its hash part and its symbolic name, e.g. `/nix/store/<hash>-openssl-3.0.13` into `<hash>` and `openssl-3.0.13`.
+fn split_store_path(path: &str) -> (&str, &str) {
+    split_store_path_in(path, store_prefix())
+}
+
+/// Same as `split_store_path`, for a store at `store_prefix` instead of the configured one.
+fn split_store_path_in<'a>(path: &'a str, store_prefix: &str) -> (&'a str, &'a str) {
+    let store_object = path
+        .strip_prefix(store_prefix)
+        .and_then(|store_object| store_object.strip_prefix('/'))
+        .unwrap_or(path);
+    let store_object = store_object.split('/').next().unwrap_or(store_object);
+
+    store_object.split_once('-').unwrap_or(("", store_object))
 }
 
 impl Package {
-    pub fn new(path: String) -> GenericResult<Self> {
-        let size_output = Command::new("nix-store")
-            .arg("--query")
-            .arg("--size")
-            .arg(&path)
-            .output()?
-            .stdout;
+    pub fn new(path: String, nix_extra_args: &[String]) -> GenericResult<Self> {
+        let _span = tracing::debug_span!("process_package", path = %path).entered();
+
+        let mut package = Self::with_size(path, 0);
+        package.query_size(nix_extra_args)?;
+        Ok(package)
+    }
+
+    pub fn query_size(&mut self, nix_extra_args: &[String]) -> GenericResult<()> {
+        if let Some(size_bytes) =
+            size_cache().and_then(|cache| cache.lock().unwrap().get(&self.path))
+        {
+            tracing::debug!("size cache hit for {}", self.path);
+            self.size_bytes = size_bytes;
+            return Ok(());
+        }
+
+        let query_start = Instant::now();
+        let size_output = match run_nix_store_with_retry(
+            &["--query", "--size", &self.path],
+            "nix-store",
+            nix_extra_args,
+        ) {
+            Ok(size_output) => size_output,
+            Err(error) if error.is::<NixStoreTimeout>() && !strict_nix_store() => {
+                tracing::warn!("{}, counting its size as 0", error);
+                TIMED_OUT_SIZE_QUERIES
+                    .lock()
+                    .unwrap()
+                    .push(self.path.clone());
+                self.size_bytes = 0;
+                return Ok(());
+            }
+            Err(error) => return Err(error),
+        };
+        PhaseTimer::record_size_query(&self.path, query_start.elapsed());
         let size_str = std::str::from_utf8(&size_output)?.trim();
-        let size_bytes: usize = size_str.parse()?;
+        self.size_bytes = size_str.parse()?;
+
+        if let Some(cache) = size_cache() {
+            cache
+                .lock()
+                .unwrap()
+                .put(self.path.clone(), self.size_bytes);
+        }
+
+        Ok(())
+    }
+
+    /// Size of the package as a percentage of `total_bytes`, usually the size of the whole closure.
+    pub fn size_percentage(&self, total_bytes: usize) -> f64 {
+        100.0 * self.size_bytes as f64 / total_bytes.max(1) as f64
+    }
+
+    /// The packages this one depends on and/or the ones that depend on it, depending on `direction`.
+    fn edges(&self, direction: EdgeDirection) -> impl Iterator<Item = usize> + '_ {
+        let dependencies = (direction != EdgeDirection::UsedBy).then_some(&self.dependencies);
+        let used_by = (direction != EdgeDirection::Dependencies).then_some(&self.used_by);
+        dependencies.into_iter().chain(used_by).flatten().copied()
+    }
 
-        Ok(Self {
+    /// Creates a package without querying nix-store for its size.
+    pub fn with_size(path: String, size_bytes: usize) -> Self {
+        Self {
             level: 0,
 
             size_bytes,
@@ -45,8 +311,81 @@ impl Package {
 
             graph_size: 0.5,
             short_name: path.clone(),
+            registration_time: None,
+            is_new: false,
+            download_size_bytes: None,
+            flake_url: None,
+            is_build_dep: false,
             path,
-        })
+        }
+    }
+
+    /// Size weighted by a logarithmic factor of how many packages use this one, so packages that are both large and widely used score the highest.
+    pub fn influence_score(&self) -> f64 {
+        self.size_bytes as f64 * (1.0 + (self.used_by.len() as f64 + 1.0).log2())
+    }
+
+    /// How many times smaller the download from the binary cache is than the unpacked package, if the download size is known.
+    pub fn compression_ratio(&self) -> Option<f64> {
+        self.download_size_bytes
+            .filter(|&download_size_bytes| download_size_bytes > 0)
+            .map(|download_size_bytes| self.size_bytes as f64 / download_size_bytes as f64)
+    }
+
+    pub fn query_registration_time(&mut self, nix_extra_args: &[String]) -> GenericResult<()> {
+        let registration_time_output = run_nix_store_with_retry(
+            &["--query", "--registration-time", &self.path],
+            "nix-store",
+            nix_extra_args,
+        )?;
+        let registration_time_str = std::str::from_utf8(&registration_time_output)?.trim();
+        self.registration_time = Some(registration_time_str.parse()?);
+
+        Ok(())
+    }
+
+    /// How long ago the package was registered in the store, if its registration time was queried.
+    pub fn age(&self) -> Option<Duration> {
+        let registration_time = UNIX_EPOCH + Duration::from_secs(self.registration_time?);
+        Some(
+            SystemTime::now()
+                .duration_since(registration_time)
+                .unwrap_or_default(),
+        )
+    }
+
+    /// The hash part of the store path, e.g. `<hash>` for `/nix/store/<hash>-openssl-3.0.13`.
+    /// Content-addressed paths look the same as input-addressed ones, only the way the hash is computed differs.
+    pub fn hash(&self) -> &str {
+        let (hash_part, _) = split_store_path(&self.path);
+        hash_part
+    }
+
+    /// The store path without the store prefix and the hash, e.g. `openssl-3.0.13`.
+    pub fn symbolic_name(&self) -> &str {
+        let (_, symbolic_name) = split_store_path(&self.path);
+        symbolic_name
+    }
+
+    /// The symbolic name up to its version, e.g. `openssl` for `openssl-3.0.13`.
+    /// The version starts at the first `-` followed by a digit, as in nixpkgs.
+    pub fn name_without_version(&self) -> &str {
+        let symbolic_name = self.symbolic_name();
+        symbolic_name
+            .match_indices('-')
+            .find(|&(index, _)| {
+                symbolic_name[index + 1..].starts_with(|c: char| c.is_ascii_digit())
+            })
+            .map_or(symbolic_name, |(index, _)| &symbolic_name[..index])
+    }
+
+    /// Whether the package is only in the closure because of other packages, i.e. nothing at level 0 depends on it directly.
+    pub fn is_indirect_only(&self, tree: &PackageTree) -> bool {
+        self.level > 1
+            && self
+                .used_by
+                .iter()
+                .all(|&parent| tree.package(parent).level != 0)
     }
 
     fn add_dependency(&mut self, pos: usize) {
@@ -58,12 +397,87 @@ impl Package {
     }
 }
 
+/// Trees larger than this only get approximations of their diameter, radius and center, since the exact values need a traversal per package.
+const EXACT_ECCENTRICITY_MAX_NODES: usize = 500;
+
+/// Small xorshift* generator, good enough to pick random packages without pulling in a crate.
+struct XorShiftRng(u64);
+
+impl XorShiftRng {
+    fn from_time() -> Self {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64;
+        // The state must never be zero.
+        Self(nanos | 1)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// Random number in `0..bound`, with a negligible bias for the bounds we use.
+    fn below(&mut self, bound: usize) -> usize {
+        (self.next_u64() % bound as u64) as usize
+    }
+}
+
+#[derive(Debug)]
+struct TreeSummary {
+    total_packages: usize,
+    total_bytes: usize,
+    min_bytes: usize,
+    max_bytes: usize,
+    mean_bytes: f64,
+    // The mean of the two middle sizes when there's an even number of packages.
+    median_bytes: f64,
+    std_dev_bytes: f64,
+    max_level: usize,
+    // Packages without dependencies.
+    leaf_count: usize,
+    // Packages that nothing depends on.
+    root_count: usize,
+    edge_count: usize,
+}
+
+/// Distances between packages ignoring the direction of the edges, from `PackageTree::graph_shape`.
+/// Trees with more than `EXACT_ECCENTRICITY_MAX_NODES` packages get approximations from `double_sweep_path`.
+#[derive(Debug, PartialEq, Eq)]
+struct GraphShape {
+    // Largest number of hops between any two packages.
+    diameter: usize,
+    // Smallest number of hops needed to get from a single package to every other package, approximated as half the diameter.
+    radius: usize,
+    // Packages from which every other package is at most `radius` hops away, in arena order.
+    // Approximated as the middle of the path found by `double_sweep_path`.
+    center: Vec<usize>,
+}
+
+/// What happens to the edges of a package removed with `PackageTree::remove_package`.
+#[cfg(test)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum RemoveMode {
+    // Every package that depended on the removed package depends on its dependencies instead.
+    Rewire,
+    // The edges are dropped, so dependencies only used by the removed package end up without parents.
+    Disconnect,
+}
+
+#[derive(Clone)]
 struct PackageTree {
     // Packages are kept in a Vec as an Arena-style system. Pointers to packages will be done by their position in this Vec.
     nodes: Vec<Package>,
     // The idea of organising packages in levels comes from https://github.com/craigmbooth/nix-visualize. It helps generate nicer graphviz visualisations, but nix-visualize still does it better.
     // Read the documentation of nix-visualize to understand the idea behind levels.
     by_level: Vec<Vec<usize>>,
+    // Packages grouped by their symbolic name (the store path without the hash), calculated in `calculate_graph_properties`.
+    name_clusters: HashMap<String, Vec<usize>>,
+    // Lowercased short names to the packages with that name, calculated in `calculate_graph_properties`.
+    name_index: HashMap<String, Vec<usize>>,
 }
 
 impl PackageTree {
@@ -71,8 +485,15 @@ impl PackageTree {
         Self {
             nodes: vec![root],
             by_level: Vec::new(),
+            name_clusters: HashMap::new(),
+            name_index: HashMap::new(),
         }
     }
+
+    #[cfg(test)]
+    pub fn builder() -> PackageTreeBuilder {
+        PackageTreeBuilder::default()
+    }
     pub fn package(&self, pos: usize) -> &Package {
         &self.nodes[pos]
     }
@@ -102,6 +523,58 @@ impl PackageTree {
         self.package_mut(depends_pos).level = max_parent_level + 1;
     }
 
+    /// Builds a tree from a flat list of store paths (e.g. from `nix-store --query --requisites`), with `paths[0]` as the root.
+    /// Dependencies come from `nix-store --query --references` on every path instead of parsing `nix-store --query --tree`, at the cost of one extra nix-store process per package.
+    pub fn from_requisites(
+        paths: &[&str],
+        nix_binary: &str,
+        nix_extra_args: &[String],
+    ) -> GenericResult<PackageTree> {
+        let (&root, _) = paths
+            .split_first()
+            .ok_or("Got no store paths to build the tree from")?;
+
+        let mut tree = PackageTree::new(Package::new(root.to_string(), nix_extra_args)?);
+        let mut positions: HashMap<&str, usize> = HashMap::from([(root, 0)]);
+        for &path in paths.iter() {
+            if !positions.contains_key(path) {
+                let pos = tree.add_package(Package::new(path.to_string(), nix_extra_args)?);
+                positions.insert(path, pos);
+            }
+        }
+
+        for pos in 0..tree.nodes.len() {
+            let path = tree.package(pos).path.clone();
+            let references_output = run_nix_store_with_retry(
+                &["--query", "--references", &path],
+                nix_binary,
+                nix_extra_args,
+            )?;
+            let references_output = std::str::from_utf8(&references_output)?;
+
+            // Store paths can reference themselves, but the tree never registers a package as a dependency of itself.
+            for reference in references_output
+                .lines()
+                .map(str::trim)
+                .filter(|reference| !reference.is_empty() && *reference != path)
+            {
+                match positions.get(reference) {
+                    Some(&depends_pos) => tree.register_dependency(pos, depends_pos),
+                    None => tracing::warn!(
+                        "{} references {}, which isn't in the list of store paths",
+                        path,
+                        reference
+                    ),
+                }
+            }
+        }
+
+        // References are queried in no particular order, so levels registered along the way may be stale.
+        tree.recalculate_levels();
+
+        Ok(tree)
+    }
+
     pub fn find_path_pos(&self, path: &str) -> usize {
         self.nodes
             .iter()
@@ -111,260 +584,8263 @@ impl PackageTree {
             .0
     }
 
-    pub fn calculate_graph_properties(&mut self) {
+    pub fn calculate_graph_properties(&mut self) -> GenericResult<()> {
+        let _phase = PhaseSpan::enter(Phase::CalculateGraphProperties);
         let mut graph_names: HashMap<String, usize> = HashMap::new();
 
         let mut smallest_size_bytes = usize::MAX;
         let mut largest_size_bytes = usize::MIN;
         let mut largest_level = usize::MIN;
 
+        self.name_clusters.clear();
+
         for (pos, pkg) in self.nodes.iter().enumerate() {
+            // A path from the root can't go through more packages than there are in the tree, so a deeper level must have been miscalculated.
+            if pkg.level >= self.nodes.len() {
+                return Err(format!(
+                    "{} is at level {}, but the tree only has {} packages",
+                    pkg.path,
+                    pkg.level,
+                    self.nodes.len()
+                )
+                .into());
+            }
+
             smallest_size_bytes = smallest_size_bytes.min(pkg.size_bytes);
             largest_size_bytes = largest_size_bytes.max(pkg.size_bytes);
             largest_level = largest_level.max(pkg.level);
 
-            let (_, symbolic_name) = pkg
-                .path
-                .split_at("/nix/store/eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-".len());
+            let symbolic_name = pkg.symbolic_name();
+            self.name_clusters
+                .entry(symbolic_name.to_string())
+                .or_default()
+                .push(pos);
+
             if graph_names.contains_key(symbolic_name) {
                 // Must remove that package and add the full store path.
                 let other_pos = graph_names.remove(symbolic_name).unwrap();
                 let other = &self.nodes[other_pos];
 
                 graph_names.insert(
-                    other.path.trim_start_matches("/nix/store/").to_string(),
+                    format!("{}-{}", other.hash(), other.symbolic_name()),
                     other_pos,
                 );
-                graph_names.insert(pkg.path.trim_start_matches("/nix/store/").to_string(), pos);
+                graph_names.insert(format!("{}-{}", pkg.hash(), pkg.symbolic_name()), pos);
             } else {
                 graph_names.insert(symbolic_name.to_string(), pos);
             }
         }
 
-        for _ in 0..(largest_level + 1) {
-            self.by_level.push(Vec::new());
+        // Arena positions depend on the order nix-store printed the tree in, so dependencies are sorted by path to keep the outputs deterministic.
+        for pos in 0..self.nodes.len() {
+            let mut dependencies = std::mem::take(&mut self.nodes[pos].dependencies);
+            dependencies.sort_by(|&a, &b| self.nodes[a].path.cmp(&self.nodes[b].path));
+            self.nodes[pos].dependencies = dependencies;
         }
 
+        self.by_level = vec![Vec::new(); largest_level + 1];
+
         for (pos, pkg) in self.nodes.iter_mut().enumerate() {
-            pkg.dependencies.sort();
             self.by_level[pkg.level].push(pos);
 
-            pkg.graph_size = 0.2
-                + 2.0
+            // All packages have the same size when there's a single package (e.g. a store path without dependencies).
+            pkg.graph_size = if largest_size_bytes == smallest_size_bytes {
+                0.5
+            } else {
+                0.2 + 2.0
                     * ((pkg.size_bytes - smallest_size_bytes) as f32
                         / (largest_size_bytes - smallest_size_bytes) as f32)
-                        .min(1.0)
-                        .max(0.0);
+                        .clamp(0.0, 1.0)
+            };
         }
 
         for (name, pos) in graph_names.into_iter() {
             self.nodes[pos].short_name = name;
         }
+
+        self.name_index.clear();
+        for (pos, pkg) in self.nodes.iter().enumerate() {
+            self.name_index
+                .entry(pkg.short_name.to_lowercase())
+                .or_default()
+                .push(pos);
+        }
+
+        Ok(())
     }
 
     pub fn sum_package_bytes(&self) -> usize {
         self.nodes.iter().map(|pkg| pkg.size_bytes).sum()
     }
-}
 
-fn process_lines(
-    tree: &mut PackageTree,
-    parent_pos: usize,
-    mut lines: VecDeque<&str>,
-) -> GenericResult<()> {
-    while let Some(line) = lines.pop_front() {
-        if let Some(object_path) = line.strip_prefix("├").or_else(|| line.strip_prefix("└")) {
-            let object_path = object_path.trim_start_matches("─");
+    /// Statistics about the whole tree, shared by the `stats` subcommand and the json statistics.
+    pub fn summary(&self) -> TreeSummary {
+        let mut sizes: Vec<usize> = Vec::with_capacity(self.nodes.len());
+        let mut total_bytes = 0;
+        let mut leaf_count = 0;
+        let mut root_count = 0;
+        let mut edge_count = 0;
 
-            if !object_path.starts_with("/") {
-                return Err(format!("When parsing the output of nix-store, we found a store path with unexpected format: {}", object_path).into());
+        for pkg in self.nodes.iter() {
+            sizes.push(pkg.size_bytes);
+            total_bytes += pkg.size_bytes;
+            leaf_count += usize::from(pkg.dependencies.is_empty());
+            root_count += usize::from(pkg.used_by.is_empty());
+            edge_count += pkg.dependencies.len();
+        }
+        sizes.sort();
+
+        // The tree always has at least the root package, so `sizes` isn't empty.
+        let mean_bytes = total_bytes as f64 / sizes.len() as f64;
+        let median_bytes = if sizes.len().is_multiple_of(2) {
+            (sizes[sizes.len() / 2 - 1] + sizes[sizes.len() / 2]) as f64 / 2.0
+        } else {
+            sizes[sizes.len() / 2] as f64
+        };
+        let std_dev_bytes = (sizes
+            .iter()
+            .map(|&size| (size as f64 - mean_bytes).powi(2))
+            .sum::<f64>()
+            / sizes.len() as f64)
+            .sqrt();
+
+        TreeSummary {
+            total_packages: self.nodes.len(),
+            total_bytes,
+            min_bytes: sizes[0],
+            max_bytes: sizes[sizes.len() - 1],
+            mean_bytes,
+            median_bytes,
+            std_dev_bytes,
+            max_level: self.by_level.len().saturating_sub(1),
+            leaf_count,
+            root_count,
+            edge_count,
+        }
+    }
+
+    /// Looks for internal inconsistencies in the tree: cycles, `dependencies` and `used_by` not being the inverse of each other,
+    /// invalid positions in `by_level` and packages that can't be reached from the root. Returns a description of each one found.
+    pub fn check_consistency(&self) -> Vec<String> {
+        let mut issues = Vec::new();
+
+        for (pos, pkg) in self.nodes.iter().enumerate() {
+            for &dep in pkg.dependencies.iter() {
+                if dep >= self.nodes.len() {
+                    issues.push(format!(
+                        "{} depends on an invalid position {}",
+                        pkg.path, dep
+                    ));
+                } else if !self.package(dep).used_by.contains(&pos) {
+                    issues.push(format!(
+                        "{} depends on {}, which isn't used by it",
+                        pkg.path,
+                        self.package(dep).path
+                    ));
+                }
+            }
+            for &parent in pkg.used_by.iter() {
+                if parent >= self.nodes.len() {
+                    issues.push(format!(
+                        "{} is used by an invalid position {}",
+                        pkg.path, parent
+                    ));
+                } else if !self.package(parent).dependencies.contains(&pos) {
+                    issues.push(format!(
+                        "{} is used by {}, which doesn't depend on it",
+                        pkg.path,
+                        self.package(parent).path
+                    ));
+                }
             }
+        }
+        // The traversals below assume valid positions.
+        if !issues.is_empty() {
+            return issues;
+        }
 
-            if object_path.ends_with("[...]") {
-                // Means we already processed this path, so we can just find it in the package tree.
-                let object_path = object_path.strip_suffix("[...]").unwrap().trim();
-                let object_pos = tree.find_path_pos(object_path);
-                if object_pos != parent_pos {
-                    tree.register_dependency(parent_pos, object_pos);
+        for (level, positions) in self.by_level.iter().enumerate() {
+            for &pos in positions {
+                if pos >= self.nodes.len() {
+                    issues.push(format!("Level {} has an invalid position {}", level, pos));
+                } else if self.package(pos).level != level {
+                    issues.push(format!(
+                        "{} is in level {}, but its level is {}",
+                        self.package(pos).path,
+                        level,
+                        self.package(pos).level
+                    ));
                 }
-            } else {
-                // We have to process this new path.
-                let new_package = Package::new(object_path.into())?;
-                let pos = tree.add_package(new_package);
-                tree.register_dependency(parent_pos, pos);
+            }
+        }
 
-                // Dive into children now. We'll grab all the lines for it and then process them.
-                let mut child_lines = VecDeque::new();
+        // Iterative DFS where packages still in `on_stack` when reached again close a cycle.
+        let mut visited = vec![false; self.nodes.len()];
+        let mut on_stack = vec![false; self.nodes.len()];
+        for start in 0..self.nodes.len() {
+            if visited[start] {
+                continue;
+            }
 
-                while let Some(&child_line) = lines.get(0) {
-                    if let Some(child_line) = child_line
-                        .strip_prefix("│")
-                        .or_else(|| child_line.strip_prefix(" "))
-                    {
-                        child_lines.push_back(child_line.trim_start_matches(" "));
-                        lines.pop_front();
-                    } else {
-                        break;
+            let mut stack = vec![(start, 0)];
+            visited[start] = true;
+            on_stack[start] = true;
+            while let Some(&mut (pos, ref mut next_dep)) = stack.last_mut() {
+                match self.package(pos).dependencies.get(*next_dep) {
+                    Some(&dep) => {
+                        *next_dep += 1;
+                        if on_stack[dep] {
+                            issues.push(format!(
+                                "{} depends on {}, which closes a cycle",
+                                self.package(pos).path,
+                                self.package(dep).path
+                            ));
+                        } else if !visited[dep] {
+                            visited[dep] = true;
+                            on_stack[dep] = true;
+                            stack.push((dep, 0));
+                        }
+                    }
+                    None => {
+                        on_stack[pos] = false;
+                        stack.pop();
                     }
                 }
+            }
+        }
 
-                process_lines(tree, pos, child_lines)?;
+        let reachable = self.reachable_from(0);
+        for (pos, pkg) in self.nodes.iter().enumerate() {
+            if !reachable.contains(&pos) {
+                issues.push(format!("{} can't be reached from the root", pkg.path));
             }
-        } else {
-            return Err("We found an unexpected line when parsing the output of nix-store".into());
         }
-    }
 
-    Ok(())
-}
+        issues
+    }
 
-/// This attempts to generate a dot file with some restrictions to coerce graphviz into generating a graph that won't look super hard to read.
-/// If none of these restrictions are added, the edges will be way too close to each other, making it impossible to follow any edge in particular.
-/// A side-effect of the restrictions is that the graph generated is huge for closures that are large enough.
-fn generate_dot_file(tree: &PackageTree, file_path: &PathBuf) -> std::io::Result<()> {
-    let mut file = File::options()
-        .write(true)
-        .truncate(true)
-        .create(true)
-        .open(file_path)?;
-    file.write_all(b"digraph {\n")?;
+    /// The `n` packages closest to the root (lowest level), which are rebuilt when any of their dependencies change.
+    /// Ties are broken by how many packages use them, most used first.
+    pub fn packages_by_level_ascending(&self, n: usize) -> Vec<usize> {
+        let mut positions: Vec<usize> = (0..self.nodes.len()).collect();
+        positions.sort_by_key(|&pos| {
+            let pkg = self.package(pos);
+            (pkg.level, std::cmp::Reverse(pkg.used_by.len()))
+        });
+        positions.truncate(n);
+        positions
+    }
 
-    for (pos, pkg) in tree.nodes.iter().enumerate() {
-        file.write_all(
-            format!(
-                "{} [fixedsize = true, height = {:.3}, width = {:.3}, penwidth = 2, label = \"{}\"];\n",
-                pos, pkg.graph_size, pkg.graph_size, pkg.short_name
-            )
-            .as_bytes(),
-        )?;
+    /// The `n` packages furthest from the root (highest level), deepest first.
+    pub fn deepest_packages(&self, n: usize) -> Vec<usize> {
+        let mut positions: Vec<usize> = (0..self.nodes.len()).collect();
+        positions.sort_by_key(|&pos| std::cmp::Reverse(self.package(pos).level));
+        positions.truncate(n);
+        positions
+    }
 
-        for dep in pkg.dependencies.iter() {
-            file.write_all(format!("{} -> {} [penwidth = 0.5];\n", pos, *dep).as_bytes())?;
+    /// The longest dependency chain from the root to `pos`, which has as many hops as the level of `pos`.
+    pub fn longest_chain_to(&self, pos: usize) -> Vec<usize> {
+        let mut chain = vec![pos];
+        while let Some(&parent) = self
+            .package(*chain.last().unwrap())
+            .used_by
+            .iter()
+            .max_by_key(|&&parent| self.package(parent).level)
+        {
+            chain.push(parent);
         }
+        chain.reverse();
+        chain
     }
 
-    let mut level_node_hierarchy: Vec<String> = Vec::new();
+    /// Every package with its `Package::influence_score`, highest score first.
+    pub fn influence_scores(&self) -> Vec<(usize, f64)> {
+        let mut scores: Vec<(usize, f64)> = self
+            .nodes
+            .iter()
+            .enumerate()
+            .map(|(pos, pkg)| (pos, pkg.influence_score()))
+            .collect();
+        scores.sort_by(|(_, first), (_, second)| second.total_cmp(first));
+        scores
+    }
 
-    for level in 0..tree.by_level.len() {
-        let total_elements = tree.by_level.len();
-        let chunk_size = 1 + total_elements / (1 + total_elements / 20);
-        let chunk_size = chunk_size.max(20);
+    /// Total size of `pos` and everything it depends on, directly or transitively.
+    pub fn closure_size_of(&self, pos: usize) -> usize {
+        self.reachable_from(pos)
+            .into_iter()
+            .map(|pos| self.package(pos).size_bytes)
+            .sum()
+    }
 
-        for (sublevel, chunk) in tree.by_level[level].chunks(chunk_size).enumerate() {
-            file.write_all(
-                format!("subgraph level_{}_{} {{\nrank = same;\n", level, sublevel).as_bytes(),
-            )?;
+    /// How much smaller the closure of the root would be without the package at `pos`: its own size plus the size of every package only reachable through it.
+    /// Unlike `closure_size_of`, packages that would still be in the closure through some other package aren't counted.
+    pub fn unique_closure_contribution(&self, pos: usize) -> usize {
+        if pos == 0 {
+            return self.sum_package_bytes();
+        }
 
-            for &pos in chunk {
-                file.write_all(format!("{}; ", pos).as_bytes())?;
-            }
+        let remaining_bytes: usize = self
+            .bfs_from(0)
+            .skipping([pos])
+            .map(|pos| self.package(pos).size_bytes)
+            .sum();
+        self.sum_package_bytes() - remaining_bytes
+    }
 
-            file.write_all(
-                format!("lnode{}_{} [style=\"invis\"];\n}}\n", level, sublevel).as_bytes(),
-            )?;
-            level_node_hierarchy.push(format!("lnode{}_{}", level, sublevel));
-        }
+    /// Percentage of the closure of `pos` that would still be in the closure of the root without it, given its `unique_closure_contribution`.
+    /// Close to 100% means removing the package would barely make the closure smaller. `None` for packages whose closure is empty.
+    pub fn shared_percentage(&self, pos: usize, unique_contribution: usize) -> Option<f64> {
+        let closure_size = self.closure_size_of(pos);
+        (closure_size > 0).then(|| 100.0 * (1.0 - unique_contribution as f64 / closure_size as f64))
     }
 
-    for edge in level_node_hierarchy.windows(2) {
-        file.write_all(format!("{} -> {} [style=\"invis\"];\n", edge[0], edge[1]).as_bytes())?;
+    /// Returns `center`, everything it depends on and everything that depends on it.
+    pub fn focus_subgraph(&self, center: usize) -> HashSet<usize> {
+        let mut subgraph = self.reachable_from(center);
+        subgraph.extend(self.reverse_bfs_from(center));
+        subgraph
     }
 
-    file.write_all(b"}\n")?;
-    file.flush()?;
+    /// Returns every package at most `radius` hops away from `center`, following both `dependencies` and `used_by` edges.
+    pub fn subgraph_within_radius(&self, center: usize, radius: usize) -> HashSet<usize> {
+        self.undirected_bfs_from(center).max_depth(radius).collect()
+    }
 
-    Ok(())
-}
+    /// Returns `start` and everything it depends on, directly or transitively.
+    pub fn reachable_from(&self, start: usize) -> HashSet<usize> {
+        self.bfs_from(start).collect()
+    }
 
-fn generate_package_list(tree: &PackageTree, file_path: &PathBuf) -> std::io::Result<()> {
-    let mut file = File::options()
-        .write(true)
-        .truncate(true)
-        .create(true)
-        .open(file_path)?;
+    /// Iterates over `start` and everything it depends on in breadth-first order.
+    pub fn bfs_from(&self, start: usize) -> BfsIter<'_> {
+        BfsIter::new(self, start, EdgeDirection::Dependencies)
+    }
 
-    file.write_all(b"pos,level,package_name,size_bytes,dependencies,path\n")?;
+    /// Iterates over `start` and everything that depends on it in breadth-first order.
+    pub fn reverse_bfs_from(&self, start: usize) -> BfsIter<'_> {
+        BfsIter::new(self, start, EdgeDirection::UsedBy)
+    }
 
-    for level in 0..tree.by_level.len() {
-        for &pkg_pos in tree.by_level[level].iter() {
-            let pkg = tree.package(pkg_pos);
+    /// Iterates over every package connected to `start` in breadth-first order, following both `dependencies` and `used_by` edges.
+    pub fn undirected_bfs_from(&self, start: usize) -> BfsIter<'_> {
+        BfsIter::new(self, start, EdgeDirection::Undirected)
+    }
 
-            file.write_all(
-                format!(
-                    "{},{},{},{},\"{}\",{}\n",
-                    pkg_pos,
-                    level,
-                    pkg.short_name,
-                    pkg.size_bytes,
+    /// Builds a new tree with only the packages in `keep`, preserving their relative order in the arena.
+    /// Levels are recalculated for the new tree, and `calculate_graph_properties` must be called on it again.
+    pub fn induced_subtree(&self, keep: &HashSet<usize>) -> PackageTree {
+        let mut tree = self.clone();
+        tree.reindex(keep);
+        tree
+    }
+
+    /// Queries the size of the packages at `positions`, for trees loaded with `load_tree_without_sizes`.
+    /// `calculate_graph_properties` must be called again afterwards.
+    pub fn query_sizes_for(
+        &mut self,
+        positions: &[usize],
+        nix_extra_args: &[String],
+    ) -> GenericResult<()> {
+        let _span = tracing::info_span!("query_sizes").entered();
+        for &pos in positions {
+            self.package_mut(pos).query_size(nix_extra_args)?;
+        }
+
+        Ok(())
+    }
+
+    /// Removes the package at `pos`, cleaning up every edge to and from it according to `mode`.
+    /// Positions after `pos` shift down by one, and the graph properties are recalculated.
+    /// Nothing removes single packages outside of tests yet.
+    #[cfg(test)]
+    pub fn remove_package(&mut self, pos: usize, mode: RemoveMode) -> GenericResult<()> {
+        if pos == 0 {
+            return Err("The root package can't be removed".into());
+        }
+        if pos >= self.nodes.len() {
+            return Err(format!("There's no package at position {}", pos).into());
+        }
+
+        if mode == RemoveMode::Rewire {
+            let pkg = self.package(pos).clone();
+            for &parent in pkg.used_by.iter() {
+                for &dep in pkg.dependencies.iter() {
+                    if !self.package(parent).dependencies.contains(&dep) {
+                        self.register_dependency(parent, dep);
+                    }
+                }
+            }
+        }
+
+        let keep: HashSet<usize> = (0..self.nodes.len())
+            .filter(|&other| other != pos)
+            .collect();
+        self.reindex(&keep);
+        self.calculate_graph_properties()
+    }
+
+    /// Replaces the packages in `collapsed`, along with every package only reachable through them, with a single package named `name` whose size is their combined size.
+    /// Packages that depended on any of them depend on the new package instead, which has no dependencies itself.
+    /// Returns how many packages were collapsed. `calculate_graph_properties` must be called again.
+    pub fn collapse_packages(&mut self, collapsed: &HashSet<usize>, name: &str) -> usize {
+        let mut keep: HashSet<usize> = self
+            .bfs_from(0)
+            .skipping(collapsed.iter().copied())
+            .collect();
+
+        let removed: Vec<usize> = (0..self.nodes.len())
+            .filter(|pos| !keep.contains(pos))
+            .collect();
+        if removed.is_empty() {
+            return 0;
+        }
+
+        let size_bytes = removed
+            .iter()
+            .map(|&pos| self.package(pos).size_bytes)
+            .sum();
+        let parents: Vec<usize> = keep
+            .iter()
+            .copied()
+            .filter(|&pos| {
+                self.package(pos)
+                    .dependencies
+                    .iter()
+                    .any(|dep| !keep.contains(dep))
+            })
+            .collect();
+
+        let aggregate_pos = self.add_package(Package::with_size(
+            format!("{}/{:032}-{}", store_prefix(), 0, name),
+            size_bytes,
+        ));
+        for parent in parents {
+            self.register_dependency(parent, aggregate_pos);
+        }
+        keep.insert(aggregate_pos);
+        self.reindex(&keep);
+
+        removed.len()
+    }
+
+    /// Removes every package not in `keep`, compacting the arena so the remaining packages keep their relative order without any holes.
+    /// All positions (`dependencies`, `used_by`) are remapped and levels are recalculated, but `calculate_graph_properties` must be called again.
+    pub fn reindex(&mut self, keep: &HashSet<usize>) {
+        let mut new_positions: HashMap<usize, usize> = HashMap::new();
+        let mut nodes = Vec::new();
+
+        for (pos, pkg) in std::mem::take(&mut self.nodes).into_iter().enumerate() {
+            if keep.contains(&pos) {
+                new_positions.insert(pos, nodes.len());
+                nodes.push(pkg);
+            }
+        }
+
+        for pkg in nodes.iter_mut() {
+            pkg.dependencies = pkg
+                .dependencies
+                .iter()
+                .filter_map(|dep| new_positions.get(dep).copied())
+                .collect();
+            pkg.used_by = pkg
+                .used_by
+                .iter()
+                .filter_map(|parent| new_positions.get(parent).copied())
+                .collect();
+        }
+
+        self.nodes = nodes;
+        self.by_level.clear();
+        self.name_clusters.clear();
+        self.name_index.clear();
+        self.recalculate_levels();
+    }
+
+    /// Recalculates every package's level as the longest path to it from a package that nothing else depends on.
+    fn recalculate_levels(&mut self) {
+        let mut pending_parents: Vec<usize> = self.nodes.iter().map(|pkg| pkg.used_by.len()).collect();
+        let mut queue: VecDeque<usize> = (0..self.nodes.len())
+            .filter(|&pos| pending_parents[pos] == 0)
+            .collect();
+
+        for pkg in self.nodes.iter_mut() {
+            pkg.level = 0;
+        }
+
+        while let Some(pos) = queue.pop_front() {
+            let level = self.nodes[pos].level;
+
+            for dep in self.nodes[pos].dependencies.clone() {
+                let dep_pkg = &mut self.nodes[dep];
+                dep_pkg.level = dep_pkg.level.max(level + 1);
+
+                pending_parents[dep] -= 1;
+                if pending_parents[dep] == 0 {
+                    queue.push_back(dep);
+                }
+            }
+        }
+    }
+
+    /// Breadth-first search over `dependencies` for the shortest path from `from` to `to`, including both ends.
+    pub fn shortest_path_between(&self, from: usize, to: usize) -> Option<Vec<usize>> {
+        let mut previous: HashMap<usize, usize> = HashMap::new();
+
+        for visit in self.bfs_from(from).visits() {
+            if let Some(reached_from) = visit.reached_from {
+                previous.insert(visit.pos, reached_from);
+            }
+
+            if visit.pos == to {
+                let mut path = vec![to];
+                while let Some(&prev) = previous.get(path.last().unwrap()) {
+                    path.push(prev);
+                }
+                path.reverse();
+                return Some(path);
+            }
+        }
+
+        None
+    }
+
+    /// Hops from `source` to every package, following both `dependencies` and `used_by` edges, and the package each one was reached from.
+    fn undirected_distances(&self, source: usize) -> (Vec<Option<usize>>, Vec<Option<usize>>) {
+        let mut distances: Vec<Option<usize>> = vec![None; self.nodes.len()];
+        let mut reached_from: Vec<Option<usize>> = vec![None; self.nodes.len()];
+
+        for visit in self.undirected_bfs_from(source).visits() {
+            distances[visit.pos] = Some(visit.depth);
+            reached_from[visit.pos] = visit.reached_from;
+        }
+
+        (distances, reached_from)
+    }
+
+    /// Largest number of hops from each package to any other package, ignoring the direction of the edges.
+    /// Needs a traversal of the whole tree per package.
+    fn eccentricities(&self) -> Vec<usize> {
+        (0..self.nodes.len())
+            .map(|pos| {
+                self.undirected_distances(pos)
+                    .0
+                    .into_iter()
+                    .flatten()
+                    .max()
+                    .unwrap_or(0)
+            })
+            .collect()
+    }
+
+    /// A long shortest path found with two traversals: from the root to the farthest package from it, and from there to the farthest package again.
+    /// Its length is a lower bound of the diameter, and usually very close to it. Starting from the root keeps the result the same between runs.
+    fn double_sweep_path(&self) -> Vec<usize> {
+        let farthest = |distances: &[Option<usize>]| {
+            distances
+                .iter()
+                .enumerate()
+                .filter_map(|(pos, distance)| distance.map(|distance| (distance, pos)))
+                .max()
+                .map_or(0, |(_, pos)| pos)
+        };
+
+        let start = farthest(&self.undirected_distances(0).0);
+        let (distances, reached_from) = self.undirected_distances(start);
+
+        let mut path = vec![farthest(&distances)];
+        while let Some(previous) = reached_from[*path.last().unwrap()] {
+            path.push(previous);
+        }
+        path
+    }
+
+    /// Diameter, radius and center of the tree, all derived from a single set of traversals.
+    pub fn graph_shape(&self) -> GraphShape {
+        if self.nodes.len() > EXACT_ECCENTRICITY_MAX_NODES {
+            let path = self.double_sweep_path();
+            return GraphShape {
+                diameter: path.len() - 1,
+                radius: path.len() / 2,
+                center: vec![path[path.len() / 2]],
+            };
+        }
+
+        let eccentricities = self.eccentricities();
+        let radius = eccentricities.iter().copied().min().unwrap_or(0);
+        GraphShape {
+            diameter: eccentricities.iter().copied().max().unwrap_or(0),
+            radius,
+            center: (0..self.nodes.len())
+                .filter(|&pos| eccentricities[pos] == radius)
+                .collect(),
+        }
+    }
+
+    /// Returns the path from the root to a package without dependencies that has the largest sum of package sizes.
+    pub fn critical_path(&self) -> Vec<usize> {
+        // heaviest[pos] is the largest sum of sizes of a path from `pos` to a leaf, and next[pos] is where that path goes.
+        let mut heaviest: Vec<Option<usize>> = vec![None; self.nodes.len()];
+        let mut next: Vec<Option<usize>> = vec![None; self.nodes.len()];
+
+        // Post-order DFS, so every dependency is done before the packages that depend on it.
+        let mut stack = vec![(0, false)];
+        while let Some((pos, dependencies_done)) = stack.pop() {
+            if heaviest[pos].is_some() {
+                continue;
+            }
+
+            let pkg = self.package(pos);
+            if !dependencies_done {
+                stack.push((pos, true));
+                stack.extend(
                     pkg.dependencies
                         .iter()
-                        .map(usize::to_string)
-                        .collect::<Vec<_>>()
-                        .join(","),
-                    pkg.path
-                )
-                .as_bytes(),
+                        .filter(|&&dep| heaviest[dep].is_none())
+                        .map(|&dep| (dep, false)),
+                );
+                continue;
+            }
+
+            let heaviest_dep = pkg
+                .dependencies
+                .iter()
+                .filter_map(|&dep| heaviest[dep].map(|bytes| (bytes, dep)))
+                .max();
+            heaviest[pos] = Some(pkg.size_bytes + heaviest_dep.map_or(0, |(bytes, _)| bytes));
+            next[pos] = heaviest_dep.map(|(_, dep)| dep);
+        }
+
+        let mut path = vec![0];
+        while let Some(dep) = next[*path.last().unwrap()] {
+            path.push(dep);
+        }
+        path
+    }
+
+    /// Returns up to `max_paths` paths over `dependencies` from `from` to `to` that don't visit any package twice.
+    /// Closures can have an exponential number of paths between two packages, so `max_paths` should be kept small.
+    pub fn all_simple_paths(&self, from: usize, to: usize, max_paths: usize) -> Vec<Vec<usize>> {
+        let mut paths = Vec::new();
+        let mut current_path = vec![from];
+        let mut on_path = HashSet::from([from]);
+
+        self.collect_simple_paths(to, max_paths, &mut current_path, &mut on_path, &mut paths);
+        paths
+    }
+
+    fn collect_simple_paths(
+        &self,
+        to: usize,
+        max_paths: usize,
+        current_path: &mut Vec<usize>,
+        on_path: &mut HashSet<usize>,
+        paths: &mut Vec<Vec<usize>>,
+    ) {
+        if paths.len() >= max_paths {
+            return;
+        }
+
+        let pos = *current_path.last().unwrap();
+        if pos == to {
+            paths.push(current_path.clone());
+            return;
+        }
+
+        for &dep in self.package(pos).dependencies.iter() {
+            if on_path.insert(dep) {
+                current_path.push(dep);
+                self.collect_simple_paths(to, max_paths, current_path, on_path, paths);
+                current_path.pop();
+                on_path.remove(&dep);
+            }
+        }
+    }
+
+    pub fn oldest_package(&self) -> Option<usize> {
+        self.nodes
+            .iter()
+            .enumerate()
+            .filter_map(|(pos, pkg)| Some((pos, pkg.registration_time?)))
+            .min_by_key(|&(_, registration_time)| registration_time)
+            .map(|(pos, _)| pos)
+    }
+
+    pub fn newest_package(&self) -> Option<usize> {
+        self.nodes
+            .iter()
+            .enumerate()
+            .filter_map(|(pos, pkg)| Some((pos, pkg.registration_time?)))
+            .max_by_key(|&(_, registration_time)| registration_time)
+            .map(|(pos, _)| pos)
+    }
+
+    /// Finds the packages that would disconnect some other packages from the root if they were removed, treating dependencies as undirected edges.
+    /// Uses Tarjan's algorithm with an iterative DFS, since closures can be deep enough to overflow the stack when recursing.
+    pub fn find_articulation_points(&self) -> Vec<usize> {
+        if self.nodes.is_empty() {
+            return Vec::new();
+        }
+
+        let neighbours: Vec<Vec<usize>> = self
+            .nodes
+            .iter()
+            .map(|pkg| {
+                pkg.dependencies
+                    .iter()
+                    .chain(pkg.used_by.iter())
+                    .copied()
+                    .collect()
+            })
+            .collect();
+
+        let mut discovery_time = vec![usize::MAX; self.nodes.len()];
+        let mut low_link = vec![0; self.nodes.len()];
+        let mut parent: Vec<Option<usize>> = vec![None; self.nodes.len()];
+        let mut is_articulation_point = vec![false; self.nodes.len()];
+        let mut root_children = 0;
+
+        discovery_time[0] = 0;
+        let mut time = 1;
+        // Each entry is a package and the index of the next neighbour to look at.
+        let mut stack = vec![(0, 0)];
+
+        while let Some((pos, next_neighbour)) = stack.last_mut() {
+            let pos = *pos;
+
+            if let Some(&neighbour) = neighbours[pos].get(*next_neighbour) {
+                *next_neighbour += 1;
+
+                if discovery_time[neighbour] == usize::MAX {
+                    parent[neighbour] = Some(pos);
+                    discovery_time[neighbour] = time;
+                    low_link[neighbour] = time;
+                    time += 1;
+
+                    if pos == 0 {
+                        root_children += 1;
+                    }
+                    stack.push((neighbour, 0));
+                } else if parent[pos] != Some(neighbour) {
+                    low_link[pos] = low_link[pos].min(discovery_time[neighbour]);
+                }
+            } else {
+                stack.pop();
+
+                if let Some(parent_pos) = parent[pos] {
+                    low_link[parent_pos] = low_link[parent_pos].min(low_link[pos]);
+
+                    if parent_pos != 0 && low_link[pos] >= discovery_time[parent_pos] {
+                        is_articulation_point[parent_pos] = true;
+                    }
+                }
+            }
+        }
+
+        // The root is only an articulation point if the DFS had to start more than one subtree from it.
+        is_articulation_point[0] = root_children > 1;
+
+        (0..self.nodes.len())
+            .filter(|&pos| is_articulation_point[pos])
+            .collect()
+    }
+
+    /// Returns `(pos, fanout_count)` for every package with at least `threshold` direct dependencies.
+    pub fn high_fanout_packages(&self, threshold: usize) -> Vec<(usize, usize)> {
+        self.nodes
+            .iter()
+            .enumerate()
+            .filter(|(_, pkg)| pkg.dependencies.len() >= threshold)
+            .map(|(pos, pkg)| (pos, pkg.dependencies.len()))
+            .collect()
+    }
+
+    /// Returns `(level, package_count)` for every level, starting from the root.
+    pub fn level_distribution(&self) -> Vec<(usize, usize)> {
+        self.by_level
+            .iter()
+            .enumerate()
+            .map(|(level, positions)| (level, positions.len()))
+            .collect()
+    }
+
+    /// Returns `(level, total_size_bytes)` for every level, starting from the root.
+    pub fn level_size_distribution(&self) -> Vec<(usize, usize)> {
+        self.by_level
+            .iter()
+            .enumerate()
+            .map(|(level, positions)| {
+                let level_bytes = positions
+                    .iter()
+                    .map(|&pos| self.package(pos).size_bytes)
+                    .sum();
+                (level, level_bytes)
+            })
+            .collect()
+    }
+
+    /// Standard deviation of the number of packages per level.
+    /// It's low for deep trees with few packages per level, and high for flat trees with most packages in a few levels.
+    pub fn level_count_stddev(&self) -> f64 {
+        let counts: Vec<f64> = self
+            .by_level
+            .iter()
+            .map(|positions| positions.len() as f64)
+            .collect();
+        if counts.is_empty() {
+            return 0.0;
+        }
+
+        let mean = counts.iter().sum::<f64>() / counts.len() as f64;
+        (counts
+            .iter()
+            .map(|count| (count - mean).powi(2))
+            .sum::<f64>()
+            / counts.len() as f64)
+            .sqrt()
+    }
+
+    /// Spot-checks `sample_size` random packages against the store, which is much cheaper than `validate_tree_against_references` for trees that weren't just queried from it (e.g. read with `--from-graph`).
+    /// The size of each package must match, and every reference in the store must be a dependency in the tree.
+    pub fn verify_against_store(
+        &self,
+        sample_size: usize,
+        nix_binary: &str,
+        nix_extra_args: &[String],
+    ) -> GenericResult<Vec<VerificationError>> {
+        let mut rng = XorShiftRng::from_time();
+        let mut positions: Vec<usize> = (0..self.nodes.len()).collect();
+        let sample_size = sample_size.min(positions.len());
+        // Partial Fisher-Yates shuffle, only the first `sample_size` positions are shuffled.
+        for i in 0..sample_size {
+            let j = i + rng.below(positions.len() - i);
+            positions.swap(i, j);
+        }
+
+        let mut errors = Vec::new();
+        for &pos in positions[..sample_size].iter() {
+            let pkg = self.package(pos);
+
+            let size_output = run_nix_store_with_retry(
+                &["--query", "--size", &pkg.path],
+                nix_binary,
+                nix_extra_args,
+            )?;
+            let size_bytes: usize = std::str::from_utf8(&size_output)?.trim().parse()?;
+            if size_bytes != pkg.size_bytes {
+                errors.push(VerificationError {
+                    path: pkg.path.clone(),
+                    field: "size".to_string(),
+                    expected: pkg.size_bytes.to_string(),
+                    actual: size_bytes.to_string(),
+                });
+            }
+
+            let references_output = run_nix_store_with_retry(
+                &["--query", "--references", &pkg.path],
+                nix_binary,
+                nix_extra_args,
             )?;
+            let dependencies: HashSet<&str> = pkg
+                .dependencies
+                .iter()
+                .map(|&dep| self.package(dep).path.as_str())
+                .collect();
+            // The tree never registers a package as a dependency of itself, so self-references are ignored.
+            let mut missing: Vec<&str> = std::str::from_utf8(&references_output)?
+                .lines()
+                .map(str::trim)
+                .filter(|reference| {
+                    !reference.is_empty()
+                        && *reference != pkg.path
+                        && !dependencies.contains(reference)
+                })
+                .collect();
+            if !missing.is_empty() {
+                missing.sort();
+                errors.push(VerificationError {
+                    path: pkg.path.clone(),
+                    field: "references".to_string(),
+                    expected: format!("missing {}", missing.join(", ")),
+                    actual: "present".to_string(),
+                });
+            }
         }
+
+        Ok(errors)
     }
 
-    file.flush()?;
+    /// Picks about `n` random packages, taking from every level a number of packages proportional to its package count, and at least one.
+    pub fn stratified_sample(&self, n: usize) -> Vec<usize> {
+        let mut rng = XorShiftRng::from_time();
+        let mut sample = Vec::new();
 
-    Ok(())
+        for positions in self.by_level.iter() {
+            let level_sample_size = (n * positions.len())
+                .div_ceil(self.nodes.len())
+                .clamp(1, positions.len());
+
+            // Partial Fisher-Yates shuffle, only the first `level_sample_size` positions are shuffled.
+            let mut positions = positions.clone();
+            for i in 0..level_sample_size {
+                let j = i + rng.below(positions.len() - i);
+                positions.swap(i, j);
+            }
+            sample.extend_from_slice(&positions[..level_sample_size]);
+        }
+
+        sample
+    }
+
+    /// Returns every package whose short name contains `name`, ignoring case, in arena order.
+    /// Like the other name lookups, this only works after `calculate_graph_properties`.
+    pub fn find_by_name(&self, name: &str) -> Vec<usize> {
+        let name = name.to_lowercase();
+        let mut found: Vec<usize> = self
+            .name_index
+            .iter()
+            .filter(|(short_name, _)| short_name.contains(&name))
+            .flat_map(|(_, positions)| positions.iter().copied())
+            .collect();
+        found.sort();
+        found
+    }
+
+    /// Returns the first package (in arena order) whose short name is exactly `name`.
+    pub fn find_by_exact_name(&self, name: &str) -> Option<usize> {
+        self.name_index
+            .get(&name.to_lowercase())?
+            .iter()
+            .copied()
+            .find(|&pos| self.package(pos).short_name == name)
+    }
+
+    /// Returns the package whose store path has this hash, e.g. `7n2v8dmpf6n4fzhq1wrqqkr71ggdizmk`.
+    pub fn find_by_hash(&self, hash: &str) -> Option<usize> {
+        self.nodes.iter().position(|pkg| {
+            let (pkg_hash, _) = split_store_path(&pkg.path);
+            pkg_hash == hash
+        })
+    }
+
+    /// Returns `(symbolic_name, positions)` for every symbolic name that more than one package in the tree has, sorted by name.
+    pub fn find_duplicates(&self) -> Vec<(&str, &[usize])> {
+        let mut duplicates: Vec<(&str, &[usize])> = self
+            .name_clusters
+            .iter()
+            .filter(|(_, positions)| positions.len() > 1)
+            .map(|(name, positions)| (name.as_str(), positions.as_slice()))
+            .collect();
+        duplicates.sort();
+        duplicates
+    }
+
+    /// Replaces every store path with `pkg_<N>` and every name with `package_<N>`, keeping sizes and the structure of the tree, so it can be shared without revealing what's installed.
+    /// N is the arena position, or with a `seed`, a hash of the seed and the store path, so the same package gets the same name across runs and closures.
+    /// Paths aren't store paths anymore afterwards, so `calculate_graph_properties` must not be called again.
+    pub fn anonymize(&mut self, seed: Option<u64>) {
+        for (pos, pkg) in self.nodes.iter_mut().enumerate() {
+            let id = match seed {
+                Some(seed) => {
+                    // FNV-1a, which is stable across runs unlike `DefaultHasher`.
+                    let hash = seed
+                        .to_le_bytes()
+                        .iter()
+                        .chain(pkg.path.as_bytes())
+                        .fold(0xcbf2_9ce4_8422_2325_u64, |hash, &byte| {
+                            (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+                        });
+                    format!("{:016x}", hash)
+                }
+                None => pos.to_string(),
+            };
+
+            pkg.path = format!("pkg_{}", id);
+            pkg.short_name = format!("package_{}", id);
+            pkg.flake_url = None;
+        }
+
+        // Duplicates are still grouped together, but the group names would give the packages away.
+        let mut clusters: Vec<Vec<usize>> = std::mem::take(&mut self.name_clusters)
+            .into_values()
+            .collect();
+        clusters.sort();
+        self.name_clusters = clusters
+            .into_iter()
+            .enumerate()
+            .map(|(index, positions)| (format!("group_{}", index), positions))
+            .collect();
+
+        self.name_index.clear();
+        for (pos, pkg) in self.nodes.iter().enumerate() {
+            self.name_index
+                .entry(pkg.short_name.to_lowercase())
+                .or_default()
+                .push(pos);
+        }
+    }
+
+    /// Iterates over the packages that depend directly on the package at `pos`.
+    pub fn iter_used_by(&self, pos: usize) -> impl Iterator<Item = &Package> {
+        self.package(pos)
+            .used_by
+            .iter()
+            .map(|&parent| self.package(parent))
+    }
 }
 
-#[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
-struct Args {
-    store_path: PathBuf,
+/// Which edges a traversal follows.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum EdgeDirection {
+    Dependencies,
+    UsedBy,
+    // Both `dependencies` and `used_by`, ignoring the direction of the edges.
+    Undirected,
+}
 
-    /// Path to the graphviz dot file to generate.
-    /// If not specified, no dot file will be generated.
-    #[arg(short, long)]
-    dot_file_path: Option<PathBuf>,
+/// A package reached by a `BfsIter`, and how it was reached.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct BfsVisit {
+    pos: usize,
+    // Hops from the start of the traversal.
+    depth: usize,
+    // The package `pos` was first reached from, `None` for the start.
+    reached_from: Option<usize>,
+}
 
-    /// Path to the csv file to generate.
-    /// If not specified, no csv file will be generated.
-    #[arg(short, long)]
-    csv_file_path: Option<PathBuf>,
+/// Breadth-first traversal of a `PackageTree` following `dependencies` and/or `used_by` edges, yielding each position once.
+/// Created by `PackageTree::bfs_from`, `PackageTree::reverse_bfs_from` and `PackageTree::undirected_bfs_from`.
+struct BfsIter<'a> {
+    tree: &'a PackageTree,
+    queue: VecDeque<BfsVisit>,
+    visited: HashSet<usize>,
+    direction: EdgeDirection,
+    max_depth: Option<usize>,
 }
 
-fn main() -> GenericResult<()> {
-    let args = Args::parse();
+impl<'a> BfsIter<'a> {
+    fn new(tree: &'a PackageTree, start: usize, direction: EdgeDirection) -> Self {
+        Self {
+            tree,
+            queue: VecDeque::from([BfsVisit {
+                pos: start,
+                depth: 0,
+                reached_from: None,
+            }]),
+            visited: HashSet::from([start]),
+            direction,
+            max_depth: None,
+        }
+    }
 
-    let tree_output = Command::new("nix-store")
-        .arg("--query")
-        .arg("--tree")
-        .arg(args.store_path)
-        .output()?
-        .stdout;
-    let tree_output = std::str::from_utf8(&tree_output)?;
+    /// Never yields or goes through the packages at `positions`, as if they weren't in the tree.
+    pub fn skipping(mut self, positions: impl IntoIterator<Item = usize>) -> Self {
+        let skipped: HashSet<usize> = positions.into_iter().collect();
+        self.queue.retain(|visit| !skipped.contains(&visit.pos));
+        self.visited.extend(skipped);
+        self
+    }
 
-    let mut lines = tree_output.lines();
-    let mut tree: PackageTree;
-    let root_path = lines.next().unwrap();
-    if root_path.starts_with("/") {
-        let root = Package::new(root_path.into())?;
-        tree = PackageTree::new(root);
-    } else {
-        return Err("Got an unexpected output from 'nix-store --query --tree'!".into());
+    /// Stops `max_depth` hops away from the start.
+    pub fn max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = Some(max_depth);
+        self
+    }
+
+    /// Yields the depth and the package each package was reached from along with its position.
+    pub fn visits(mut self) -> impl Iterator<Item = BfsVisit> + 'a {
+        std::iter::from_fn(move || self.next_visit())
+    }
+
+    fn next_visit(&mut self) -> Option<BfsVisit> {
+        let visit = self.queue.pop_front()?;
+        if self
+            .max_depth
+            .is_none_or(|max_depth| visit.depth < max_depth)
+        {
+            for next in self.tree.package(visit.pos).edges(self.direction) {
+                if self.visited.insert(next) {
+                    self.queue.push_back(BfsVisit {
+                        pos: next,
+                        depth: visit.depth + 1,
+                        reached_from: Some(visit.pos),
+                    });
+                }
+            }
+        }
+
+        Some(visit)
     }
+}
 
-    process_lines(&mut tree, 0, lines.collect())?;
-    tree.calculate_graph_properties();
+impl Iterator for BfsIter<'_> {
+    type Item = usize;
 
-    if let Some(path) = args.dot_file_path {
-        generate_dot_file(&tree, &path)?;
+    fn next(&mut self) -> Option<usize> {
+        self.next_visit().map(|visit| visit.pos)
     }
+}
+
+/// Builds a tree from package names without running nix-store, to set up trees in tests:
+///
+/// ```ignore
+/// let tree = PackageTree::builder()
+///     .root("pkg-a", 1000)
+///     .depends_on("pkg-a", "pkg-b", 500)
+///     .depends_on("pkg-b", "pkg-c", 200)
+///     .build()?;
+/// ```
+///
+/// Every package gets a fake store path with its name, e.g. `/nix/store/00000000000000000000000000000001-pkg-b`.
+#[cfg(test)]
+#[derive(Default)]
+struct PackageTreeBuilder {
+    root: Option<(String, usize)>,
+    // (parent name, dependency name, dependency size in bytes)
+    dependencies: Vec<(String, String, usize)>,
+}
 
-    if let Some(path) = args.csv_file_path {
-        generate_package_list(&tree, &path)?;
+#[cfg(test)]
+impl PackageTreeBuilder {
+    pub fn root(mut self, name: &str, size_bytes: usize) -> Self {
+        self.root = Some((name.to_string(), size_bytes));
+        self
     }
 
-    println!(
-        "Total bytes calculated for this store path: {}",
-        tree.sum_package_bytes()
-    );
+    /// Makes `parent` depend on `name`, defining `name` with `size_bytes` if it wasn't defined yet.
+    pub fn depends_on(mut self, parent: &str, name: &str, size_bytes: usize) -> Self {
+        self.dependencies
+            .push((parent.to_string(), name.to_string(), size_bytes));
+        self
+    }
 
-    Ok(())
+    pub fn build(self) -> GenericResult<PackageTree> {
+        let (root_name, root_size_bytes) = self.root.ok_or("The tree needs a root package")?;
+        let fake_store_path =
+            |pos: usize, name: &str| format!("{}/{:032}-{}", store_prefix(), pos, name);
+
+        let mut tree = PackageTree::new(Package::with_size(
+            fake_store_path(0, &root_name),
+            root_size_bytes,
+        ));
+        let mut positions: HashMap<String, usize> = HashMap::from([(root_name, 0)]);
+
+        // Dependencies are defined as we go, so every parent must be defined before it's used.
+        for (parent, name, size_bytes) in self.dependencies.iter() {
+            let parent_pos = *positions.get(parent).ok_or_else(|| {
+                format!(
+                    "'{}' must be the root or a dependency of another package before '{}' can depend on it",
+                    parent, name
+                )
+            })?;
+
+            let pos = match positions.get(name) {
+                Some(&pos) => pos,
+                None => {
+                    let pos = tree.add_package(Package::with_size(
+                        fake_store_path(tree.nodes.len(), name),
+                        *size_bytes,
+                    ));
+                    positions.insert(name.clone(), pos);
+                    pos
+                }
+            };
+
+            tree.register_dependency(parent_pos, pos);
+        }
+
+        // Dependencies may be given in any order, so levels registered along the way may be stale.
+        tree.recalculate_levels();
+        tree.calculate_graph_properties()?;
+
+        Ok(tree)
+    }
+}
+
+/// Shows the tree with one package per line, indenting dependencies by 2 spaces and sorting them by name.
+/// Packages that were already shown are marked with `(*)` and their dependencies aren't shown again.
+impl std::fmt::Display for PackageTree {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.nodes.is_empty() {
+            return Ok(());
+        }
+
+        let mut visited = HashSet::new();
+        let mut stack = vec![(0, 0)];
+
+        while let Some((pos, depth)) = stack.pop() {
+            let pkg = self.package(pos);
+            let indent = "  ".repeat(depth);
+
+            if !visited.insert(pos) {
+                writeln!(f, "{}{} (*)", indent, pkg.short_name)?;
+                continue;
+            }
+
+            writeln!(f, "{}{} ({} B)", indent, pkg.short_name, pkg.size_bytes)?;
+
+            let mut dependencies = pkg.dependencies.clone();
+            dependencies.sort_by(|&a, &b| self.package(a).short_name.cmp(&self.package(b).short_name));
+            dependencies.dedup();
+
+            // Pushed in reverse so the first dependency in alphabetical order is shown first.
+            for &dep in dependencies.iter().rev() {
+                stack.push((dep, depth + 1));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn process_lines(
+    tree: &mut PackageTree,
+    parent_pos: usize,
+    mut lines: VecDeque<&str>,
+    new_package: &dyn Fn(String) -> GenericResult<Package>,
+) -> GenericResult<()> {
+    while let Some(line) = lines.pop_front() {
+        if let Some(object_path) = line.strip_prefix("├").or_else(|| line.strip_prefix("└")) {
+            let object_path = object_path.trim_start_matches("─");
+
+            if !object_path.starts_with("/") {
+                return Err(format!("When parsing the output of nix-store, we found a store path with unexpected format: {}", object_path).into());
+            }
+
+            if object_path.ends_with("[...]") {
+                // Means we already processed this path, so we can just find it in the package tree.
+                let object_path = object_path.strip_suffix("[...]").unwrap().trim();
+                let object_pos = tree.find_path_pos(object_path);
+                if object_pos != parent_pos {
+                    tree.register_dependency(parent_pos, object_pos);
+                }
+            } else {
+                // We have to process this new path.
+                let pos = tree.add_package(new_package(object_path.into())?);
+                tree.register_dependency(parent_pos, pos);
+
+                // Dive into children now. We'll grab all the lines for it and then process them.
+                let mut child_lines = VecDeque::new();
+
+                while let Some(&child_line) = lines.front() {
+                    if let Some(child_line) = child_line
+                        .strip_prefix("│")
+                        .or_else(|| child_line.strip_prefix(" "))
+                    {
+                        child_lines.push_back(child_line.trim_start_matches(" "));
+                        lines.pop_front();
+                    } else {
+                        break;
+                    }
+                }
+
+                process_lines(tree, pos, child_lines, new_package)?;
+            }
+        } else {
+            return Err("We found an unexpected line when parsing the output of nix-store".into());
+        }
+    }
+
+    Ok(())
+}
+
+/// A package whose direct dependencies in the tree don't match what `nix-store --query --references` reports.
+struct ValidationError {
+    pos: usize,
+    // References reported by nix-store that aren't dependencies in the tree.
+    missing_in_tree: Vec<String>,
+    // Dependencies in the tree that nix-store doesn't report as references.
+    unexpected_in_tree: Vec<String>,
+}
+
+impl ValidationError {
+    fn describe(&self, tree: &PackageTree) -> String {
+        let mut description = format!(
+            "{} doesn't match its references in the store.",
+            tree.package(self.pos).path
+        );
+
+        if !self.missing_in_tree.is_empty() {
+            description.push_str(&format!(
+                " Missing from the tree: {}.",
+                self.missing_in_tree.join(", ")
+            ));
+        }
+
+        if !self.unexpected_in_tree.is_empty() {
+            description.push_str(&format!(
+                " Not a reference in the store: {}.",
+                self.unexpected_in_tree.join(", ")
+            ));
+        }
+
+        description
+    }
+}
+
+/// A field of a package in the tree that doesn't match what the store reports, found by `PackageTree::verify_against_store`.
+struct VerificationError {
+    path: String,
+    field: String,
+    expected: String,
+    actual: String,
+}
+
+impl VerificationError {
+    fn describe(&self) -> String {
+        format!(
+            "{} of {} is {} in the tree, but {} in the store",
+            self.field, self.path, self.expected, self.actual
+        )
+    }
+}
+
+/// Checks the dependencies parsed from `nix-store --query --tree` against `nix-store --query --references` for every package.
+/// This runs one extra nix-store process per package, so it's only meant to debug `process_lines`.
+fn validate_tree_against_references(
+    tree: &PackageTree,
+    nix_binary: &str,
+    nix_extra_args: &[String],
+) -> GenericResult<Vec<ValidationError>> {
+    let mut errors = Vec::new();
+
+    for (pos, pkg) in tree.nodes.iter().enumerate() {
+        let references_output = run_nix_store_with_retry(
+            &["--query", "--references", &pkg.path],
+            nix_binary,
+            nix_extra_args,
+        )?;
+        let references_output = std::str::from_utf8(&references_output)?;
+
+        // The tree never registers a package as a dependency of itself, so self-references are ignored.
+        let references: HashSet<&str> = references_output
+            .lines()
+            .map(str::trim)
+            .filter(|reference| !reference.is_empty() && *reference != pkg.path)
+            .collect();
+        let dependencies: HashSet<&str> = pkg
+            .dependencies
+            .iter()
+            .map(|&dep| tree.package(dep).path.as_str())
+            .collect();
+
+        let mut missing_in_tree: Vec<String> = references
+            .difference(&dependencies)
+            .map(|path| path.to_string())
+            .collect();
+        let mut unexpected_in_tree: Vec<String> = dependencies
+            .difference(&references)
+            .map(|path| path.to_string())
+            .collect();
+
+        if !missing_in_tree.is_empty() || !unexpected_in_tree.is_empty() {
+            missing_in_tree.sort();
+            unexpected_in_tree.sort();
+
+            errors.push(ValidationError {
+                pos,
+                missing_in_tree,
+                unexpected_in_tree,
+            });
+        }
+    }
+
+    Ok(errors)
+}
+
+/// Sums the sizes of every path in the closure of `path`, as listed by `nix-store --query --requisites`.
+/// This doesn't depend on parsing the output of `nix-store --query --tree`, so it can be used to double check `PackageTree::sum_package_bytes`.
+fn query_closure_size(
+    path: &str,
+    nix_binary: &str,
+    nix_extra_args: &[String],
+) -> GenericResult<usize> {
+    let requisites_output = run_nix_store_with_retry(
+        &["--query", "--requisites", path],
+        nix_binary,
+        nix_extra_args,
+    )?;
+    let requisites_output = std::str::from_utf8(&requisites_output)?;
+    let requisites: Vec<&str> = requisites_output
+        .lines()
+        .map(str::trim)
+        .filter(|requisite| !requisite.is_empty())
+        .collect();
+
+    // nix-store prints one size per line, in the same order as the paths given to it.
+    let size_output = run_nix_store_with_retry(
+        &[&["--query", "--size"], requisites.as_slice()].concat(),
+        nix_binary,
+        nix_extra_args,
+    )?;
+    let size_output = std::str::from_utf8(&size_output)?;
+
+    let mut closure_size = 0;
+    for size_str in size_output.lines() {
+        closure_size += size_str.trim().parse::<usize>()?;
+    }
+
+    Ok(closure_size)
+}
+
+/// Converts a level into a colour going from blue (level 0) to red (`max_level`), interpolating the hue in HSV space.
+fn level_to_color(level: usize, max_level: usize) -> String {
+    let hue = if max_level == 0 {
+        240.0
+    } else {
+        240.0 - (240.0 * level as f32 / max_level as f32)
+    };
+    let saturation = 1.0;
+    let value = 0.8;
+
+    let chroma = value * saturation;
+    let hue_sector = hue / 60.0;
+    let x = chroma * (1.0 - (hue_sector % 2.0 - 1.0).abs());
+    let (r, g, b) = match hue_sector as usize {
+        0 => (chroma, x, 0.0),
+        1 => (x, chroma, 0.0),
+        2 => (0.0, chroma, x),
+        3 => (0.0, x, chroma),
+        _ => (x, 0.0, chroma),
+    };
+    let m = value - chroma;
+
+    format!(
+        "#{:02x}{:02x}{:02x}",
+        ((r + m) * 255.0).round() as u8,
+        ((g + m) * 255.0).round() as u8,
+        ((b + m) * 255.0).round() as u8
+    )
+}
+
+/// Darkens a `#rrggbb` colour to 60% of its brightness, so fills stay readable with light text on a dark background.
+/// Other colours (e.g. X11 colour names) are returned unchanged.
+fn darken_color(color: &str) -> String {
+    let Some(hex) = color.strip_prefix('#').filter(|hex| hex.len() == 6) else {
+        return color.to_string();
+    };
+    let Ok(rgb) = u32::from_str_radix(hex, 16) else {
+        return color.to_string();
+    };
+
+    let darken = |shift: u32| ((((rgb >> shift) & 0xff) as f32) * 0.6).round() as u8;
+    format!("#{:02x}{:02x}{:02x}", darken(16), darken(8), darken(0))
+}
+
+/// Converts a registration time into a colour going from orange (`oldest`) to green (`newest`), interpolating in RGB space.
+fn registration_time_to_color(registration_time: u64, oldest: u64, newest: u64) -> String {
+    let recency = if newest == oldest {
+        1.0
+    } else {
+        (registration_time - oldest) as f32 / (newest - oldest) as f32
+    };
+    let old_color = (255.0, 140.0, 0.0);
+    let recent_color = (46.0, 204.0, 64.0);
+    let interpolate = |old: f32, recent: f32| (old + (recent - old) * recency).round() as u8;
+
+    format!(
+        "#{:02x}{:02x}{:02x}",
+        interpolate(old_color.0, recent_color.0),
+        interpolate(old_color.1, recent_color.1),
+        interpolate(old_color.2, recent_color.2)
+    )
+}
+
+/// Colours for packages in each quartile of size, from the smallest (blue) to the largest (red).
+const SIZE_QUARTILE_COLORS: [&str; 4] = ["#4575b4", "#91bfdb", "#fc8d59", "#d73027"];
+
+/// Picks the colour of every package from the quartile of the closure its size falls into.
+fn size_quartile_colors(tree: &PackageTree) -> Vec<&'static str> {
+    let mut sizes: Vec<usize> = tree.nodes.iter().map(|pkg| pkg.size_bytes).collect();
+    sizes.sort();
+    let quartiles =
+        [1, 2, 3].map(|quarter| sizes[(sizes.len() * quarter / 4).min(sizes.len() - 1)]);
+
+    tree.nodes
+        .iter()
+        .map(|pkg| {
+            let quartile = quartiles
+                .iter()
+                .filter(|&&boundary| pkg.size_bytes > boundary)
+                .count();
+            SIZE_QUARTILE_COLORS[quartile]
+        })
+        .collect()
+}
+
+/// Formats a Unix timestamp as an ISO 8601 date and time in UTC, e.g. `2024-03-28T14:05:09Z`.
+fn unix_timestamp_to_iso8601(timestamp: u64) -> String {
+    let days = (timestamp / 86400) as i64;
+    let seconds_in_day = timestamp % 86400;
+
+    // Converts days since the Unix epoch into a civil date, following http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        seconds_in_day / 3600,
+        seconds_in_day % 3600 / 60,
+        seconds_in_day % 60
+    )
+}
+
+fn is_fifo(path: &Path) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::FileTypeExt;
+
+        path.metadata()
+            .is_ok_and(|metadata| metadata.file_type().is_fifo())
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = path;
+        false
+    }
+}
+
+/// Opens an output file for writing, creating or truncating it.
+/// Named pipes can't be truncated, so they're only opened for writing, which lets other processes (e.g. `dot`) read the output as it's generated.
+fn open_output_file(path: &Path) -> std::io::Result<File> {
+    if is_fifo(path) {
+        return File::options().write(true).open(path);
+    }
+
+    File::options()
+        .write(true)
+        .truncate(true)
+        .create(true)
+        .open(path)
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum OutputEncoding {
+    #[default]
+    Utf8,
+    Ascii,
+}
+
+static OUTPUT_ENCODING: OnceLock<OutputEncoding> = OnceLock::new();
+
+/// Whether strings in output files must be escaped to ASCII, set with `--output-encoding`.
+fn ascii_only_output() -> bool {
+    OUTPUT_ENCODING.get() == Some(&OutputEncoding::Ascii)
+}
+
+/// NFC-normalizes `value`, so the same name is always written with the same characters, e.g. `é` instead of `e` followed by a combining accent.
+/// With `ascii_only`, every non-ASCII character is then replaced with its `\uXXXX` escape, using surrogate pairs outside the Basic Multilingual Plane like json does.
+fn sanitize_for_encoding(value: &str, ascii_only: bool) -> String {
+    let normalized = value.nfc();
+    if !ascii_only {
+        return normalized.collect();
+    }
+
+    let mut sanitized = String::with_capacity(value.len());
+    for c in normalized {
+        if c.is_ascii() {
+            sanitized.push(c);
+        } else {
+            for unit in c.encode_utf16(&mut [0; 2]) {
+                sanitized.push_str(&format!("\\u{:04x}", unit));
+            }
+        }
+    }
+
+    sanitized
+}
+
+/// Formats a size with binary units, e.g. `1.5 MiB`.
+fn format_size_human(size_bytes: usize) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+    let mut size = size_bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} B", size_bytes)
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+/// Escapes a name that comes from the user or the store for a quoted dot string, so it's shown as is.
+fn escape_dot_string(value: &str) -> String {
+    sanitize_for_encoding(
+        &value.replace('\\', "\\\\").replace('"', "\\\""),
+        ascii_only_output(),
+    )
+}
+
+/// Replaces the `{name}`, `{size}`, `{size_human}`, `{level}`, `{path}`, `{hash}`, `{fan_in}` and `{fan_out}` placeholders in a dot label template.
+/// Quotes are escaped, but other escapes (like `\n` for a new line) are left for graphviz to interpret.
+fn apply_label_template(template: &str, pkg: &Package) -> String {
+    template
+        .replace("{name}", &pkg.short_name)
+        .replace("{size_human}", &format_size_human(pkg.size_bytes))
+        .replace("{size}", &pkg.size_bytes.to_string())
+        .replace("{level}", &pkg.level.to_string())
+        .replace("{path}", &pkg.path)
+        .replace("{hash}", pkg.hash())
+        .replace("{fan_in}", &pkg.used_by.len().to_string())
+        .replace("{fan_out}", &pkg.dependencies.len().to_string())
+        .replace('"', "\\\"")
+}
+
+/// Replaces the `{total_bytes}`, `{total_bytes_human}`, `{package_count}`, `{max_level}`, `{root_name}`, `{leaf_count}` and `{analysis_time_ms}` placeholders in a `--print-format` template.
+fn format_summary_line(
+    template: &str,
+    summary: &TreeSummary,
+    root_name: &str,
+    analysis_time: Duration,
+) -> String {
+    template
+        .replace(
+            "{total_bytes_human}",
+            &format_size_human(summary.total_bytes),
+        )
+        .replace("{total_bytes}", &summary.total_bytes.to_string())
+        .replace("{package_count}", &summary.total_packages.to_string())
+        .replace("{max_level}", &summary.max_level.to_string())
+        .replace("{root_name}", root_name)
+        .replace("{leaf_count}", &summary.leaf_count.to_string())
+        .replace("{analysis_time_ms}", &analysis_time.as_millis().to_string())
+}
+
+/// Truncates a dot label to `max_len` characters followed by `...`, without leaving half of an escape sequence (e.g. `\n`) at the end.
+fn truncate_label(label: &str, max_len: usize) -> String {
+    if label.chars().count() <= max_len {
+        return label.to_string();
+    }
+
+    let mut truncated: String = label.chars().take(max_len).collect();
+    let trailing_backslashes = truncated.len() - truncated.trim_end_matches('\\').len();
+    if trailing_backslashes % 2 == 1 {
+        truncated.pop();
+    }
+    truncated.push_str("...");
+    truncated
+}
+
+/// Longest prefix shared by all strings, never splitting a character.
+fn common_prefix<'a>(strs: &[&'a str]) -> &'a str {
+    let Some((first, rest)) = strs.split_first() else {
+        return "";
+    };
+
+    let mut prefix_len = first.len();
+    for other in rest {
+        prefix_len = first[..prefix_len]
+            .char_indices()
+            .zip(other.chars())
+            .find(|((_, a), b)| a != b)
+            .map(|((index, _), _)| index)
+            .unwrap_or(prefix_len.min(other.len()));
+    }
+
+    &first[..prefix_len]
+}
+
+/// Everything except the unreserved characters from RFC 3986 is percent-encoded in `--dot-node-url`.
+const URL_ENCODE_SET: &percent_encoding::AsciiSet = &percent_encoding::NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+#[derive(Default)]
+struct DotOptions {
+    color_by_level: bool,
+    color_by_age: bool,
+    // URL template with `{path}` and `{name}` placeholders.
+    node_url: Option<String>,
+    tooltip: bool,
+    group_by_name: bool,
+    // Groups packages into clusters by the package set inferred from these name prefixes (see `infer_package_set`).
+    package_set_prefixes: Option<Vec<String>>,
+    // How packages in the same level are grouped, `None` skips the groupings and the invisible edges between them.
+    subgraph_style: DotSubgraphStyle,
+    trim_common_prefix: bool,
+    // Fills the packages that aren't new in gray, so the new ones stand out.
+    gray_existing: bool,
+    // (package, dependency) edges drawn thicker and in red.
+    highlighted_edges: HashSet<(usize, usize)>,
+    // Node label with the placeholders understood by `apply_label_template`.
+    label_template: String,
+    // Draws at most this many incoming edges per package, keeping the ones from the largest parents.
+    max_used_by: Option<usize>,
+    // Labels longer than this are truncated, with the full name kept in the tooltip.
+    max_label_len: Option<usize>,
+    // Adds a visible "Level N" node to every level, and a legend explaining node sizes and levels.
+    level_labels: bool,
+    // Sizes nodes by `Package::influence_score` instead of by package size.
+    size_by_influence: bool,
+    // Graph attributes, left to graphviz defaults when not set.
+    concentrate: bool,
+    nodesep: Option<f64>,
+    ranksep: Option<f64>,
+    // Adds the size of each package as a percentage of the closure size to its label.
+    normalize_sizes: bool,
+    // Graph-wide colours, left to graphviz defaults when not set.
+    background_color: Option<String>,
+    font_color: Option<String>,
+    edge_color: Option<String>,
+    // Darkens the fill colours of the nodes.
+    dark_mode: bool,
+    edge_labels: DotEdgeLabels,
+}
+
+/// Infers the nixpkgs package set (e.g. `python` for `python3.11-requests-2.28.0`) from a package name, given the known package set prefixes.
+/// The prefix must be followed by something other than a letter, so `perl` matches `perl5.38.2-URI-5.21` but not `perlite-1.0`.
+fn infer_package_set(name: &str, prefixes: &[String]) -> Option<String> {
+    let name = name.to_ascii_lowercase();
+    prefixes
+        .iter()
+        .find(|prefix| {
+            name.strip_prefix(prefix.to_ascii_lowercase().as_str())
+                .is_some_and(|rest| !rest.starts_with(|c: char| c.is_ascii_alphabetic()))
+        })
+        .cloned()
+}
+
+/// Rough estimate of how big the dot file for this tree will be, so we can warn before generating something graphviz can't handle.
+fn estimate_dot_size_bytes(tree: &PackageTree) -> usize {
+    let edges: usize = tree.nodes.iter().map(|pkg| pkg.dependencies.len()).sum();
+    200 * tree.nodes.len() + 50 * edges
+}
+
+/// This attempts to generate a dot file with some restrictions to coerce graphviz into generating a graph that won't look super hard to read.
+/// If none of these restrictions are added, the edges will be way too close to each other, making it impossible to follow any edge in particular.
+/// A side-effect of the restrictions is that the graph generated is huge for closures that are large enough.
+fn generate_dot_file(
+    tree: &PackageTree,
+    file_path: &Path,
+    options: &DotOptions,
+) -> std::io::Result<()> {
+    let mut file = open_output_file(file_path)?;
+
+    let prefix = if options.trim_common_prefix {
+        let names: Vec<&str> = tree
+            .nodes
+            .iter()
+            .map(|pkg| pkg.short_name.as_str())
+            .collect();
+        common_prefix(&names)
+    } else {
+        ""
+    };
+    if !prefix.is_empty() {
+        file.write_all(format!("// Common prefix removed from labels: {}\n", prefix).as_bytes())?;
+    }
+
+    file.write_all(b"digraph {\n")?;
+    if options.concentrate {
+        file.write_all(b"concentrate = true;\n")?;
+    }
+    if let Some(nodesep) = options.nodesep {
+        file.write_all(format!("nodesep = {};\n", nodesep).as_bytes())?;
+    }
+    if let Some(ranksep) = options.ranksep {
+        file.write_all(format!("ranksep = {};\n", ranksep).as_bytes())?;
+    }
+    if let Some(background_color) = &options.background_color {
+        file.write_all(format!("bgcolor = \"{}\";\n", background_color).as_bytes())?;
+    }
+    // Node outlines use the font colour too, otherwise they'd disappear on dark backgrounds.
+    if let Some(font_color) = &options.font_color {
+        file.write_all(
+            format!(
+                "node [fontcolor = \"{}\", color = \"{}\"];\n",
+                font_color, font_color
+            )
+            .as_bytes(),
+        )?;
+    }
+    if let Some(edge_color) = &options.edge_color {
+        file.write_all(format!("edge [color = \"{}\"];\n", edge_color).as_bytes())?;
+    }
+
+    if options.group_by_name {
+        for (name, positions) in tree.find_duplicates() {
+            let cluster_id: String = name
+                .chars()
+                .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+                .collect();
+
+            file.write_all(
+                format!(
+                    "subgraph cluster_{} {{\nlabel = \"{} ({} versions)\";\n",
+                    cluster_id,
+                    escape_dot_string(name),
+                    positions.len()
+                )
+                .as_bytes(),
+            )?;
+
+            for &pos in positions {
+                file.write_all(format!("{}; ", pos).as_bytes())?;
+            }
+
+            file.write_all(b"\n}\n")?;
+        }
+    }
+
+    if let Some(prefixes) = &options.package_set_prefixes {
+        let mut clusters: Vec<(String, Vec<usize>)> = prefixes
+            .iter()
+            .map(|prefix| (prefix.clone(), Vec::new()))
+            .chain(std::iter::once(("other".to_string(), Vec::new())))
+            .collect();
+        for (pos, pkg) in tree.nodes.iter().enumerate() {
+            let package_set = infer_package_set(pkg.symbolic_name(), prefixes)
+                .unwrap_or_else(|| "other".to_string());
+            if let Some((_, positions)) = clusters.iter_mut().find(|(name, _)| *name == package_set)
+            {
+                positions.push(pos);
+            }
+        }
+
+        for (name, positions) in clusters
+            .iter()
+            .filter(|(_, positions)| !positions.is_empty())
+        {
+            let cluster_id: String = name
+                .chars()
+                .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+                .collect();
+
+            file.write_all(
+                format!(
+                    "subgraph cluster_{} {{\nlabel = \"{}\";\n",
+                    cluster_id,
+                    escape_dot_string(name)
+                )
+                .as_bytes(),
+            )?;
+
+            for &pos in positions {
+                file.write_all(format!("{}; ", pos).as_bytes())?;
+            }
+
+            file.write_all(b"\n}\n")?;
+        }
+    }
+
+    let mut suppressed_edges: HashSet<(usize, usize)> = HashSet::new();
+    if let Some(max_used_by) = options.max_used_by {
+        for (pos, pkg) in tree.nodes.iter().enumerate() {
+            let mut parents = pkg.used_by.clone();
+            parents.sort_by(|&a, &b| {
+                tree.package(b)
+                    .graph_size
+                    .total_cmp(&tree.package(a).graph_size)
+            });
+            suppressed_edges.extend(
+                parents
+                    .into_iter()
+                    .skip(max_used_by)
+                    .map(|parent| (parent, pos)),
+            );
+        }
+    }
+
+    // Influence scores are scaled to the same range as `graph_size`.
+    let node_sizes: Vec<f32> = if options.size_by_influence {
+        let scores: Vec<f64> = tree.nodes.iter().map(Package::influence_score).collect();
+        let lowest = scores.iter().copied().fold(f64::INFINITY, f64::min);
+        let highest = scores.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+        scores
+            .into_iter()
+            .map(|score| {
+                if highest == lowest {
+                    0.5
+                } else {
+                    0.2 + 2.0 * ((score - lowest) / (highest - lowest)) as f32
+                }
+            })
+            .collect()
+    } else {
+        tree.nodes.iter().map(|pkg| pkg.graph_size).collect()
+    };
+
+    let total_bytes = tree.sum_package_bytes();
+    let build_tools = AuditConfig::default().build_tools;
+    let max_level = tree.by_level.len().saturating_sub(1);
+    let oldest_registration_time = tree
+        .oldest_package()
+        .and_then(|pos| tree.package(pos).registration_time);
+    let newest_registration_time = tree
+        .newest_package()
+        .and_then(|pos| tree.package(pos).registration_time);
+
+    let fill_color = |color: String| {
+        if options.dark_mode {
+            darken_color(&color)
+        } else {
+            color
+        }
+    };
+
+    for (pos, pkg) in tree.nodes.iter().enumerate() {
+        let mut extra_attributes = String::new();
+        if options.color_by_level {
+            extra_attributes.push_str(&format!(
+                ", style = \"filled\", fillcolor = \"{}\"",
+                fill_color(level_to_color(pkg.level, max_level))
+            ));
+        }
+        if options.color_by_age {
+            if let (Some(registration_time), Some(oldest), Some(newest)) = (
+                pkg.registration_time,
+                oldest_registration_time,
+                newest_registration_time,
+            ) {
+                extra_attributes.push_str(&format!(
+                    ", style = \"filled\", fillcolor = \"{}\"",
+                    fill_color(registration_time_to_color(
+                        registration_time,
+                        oldest,
+                        newest
+                    ))
+                ));
+            }
+        }
+
+        if pkg.is_build_dep {
+            extra_attributes.push_str(", shape = diamond");
+        }
+
+        // Comes after the other colors so that it takes precedence over them.
+        if pkg.is_new {
+            extra_attributes.push_str(&format!(
+                ", style = \"filled\", fillcolor = \"{}\"",
+                fill_color("#00ff00".to_string())
+            ));
+        } else if options.gray_existing {
+            extra_attributes.push_str(&format!(
+                ", style = \"filled\", fillcolor = \"{}\"",
+                fill_color("#d3d3d3".to_string())
+            ));
+        }
+
+        if let Some(node_url) = &options.node_url {
+            extra_attributes.push_str(&format!(
+                ", URL = \"{}\"",
+                node_url
+                    .replace(
+                        "{path}",
+                        &percent_encoding::utf8_percent_encode(&pkg.path, URL_ENCODE_SET)
+                            .to_string()
+                    )
+                    .replace(
+                        "{name}",
+                        &percent_encoding::utf8_percent_encode(&pkg.short_name, URL_ENCODE_SET)
+                            .to_string()
+                    )
+            ));
+        }
+        if options.tooltip {
+            let url = pkg
+                .flake_url
+                .as_ref()
+                .map(|url| format!("\\nurl: {}", url))
+                .unwrap_or_default();
+            extra_attributes.push_str(&format!(
+                ", tooltip = \"{}\\nsize: {} bytes{}\"",
+                sanitize_for_encoding(&pkg.path, ascii_only_output()),
+                pkg.size_bytes,
+                sanitize_for_encoding(&url, ascii_only_output())
+            ));
+        }
+
+        // Packages whose whole name is the prefix keep their name, instead of ending up without a label.
+        let name = match pkg.short_name.strip_prefix(prefix) {
+            Some(trimmed) if !trimmed.is_empty() => trimmed,
+            _ => &pkg.short_name,
+        };
+        let name = sanitize_for_encoding(name, ascii_only_output());
+        let mut label = apply_label_template(&options.label_template.replace("{name}", &name), pkg);
+        if options.normalize_sizes {
+            label.push_str(&format!(" ({:.2}%)", pkg.size_percentage(total_bytes)));
+        }
+        if let Some(max_used_by) = options.max_used_by {
+            let suppressed_count = pkg.used_by.len().saturating_sub(max_used_by);
+            if suppressed_count > 0 {
+                label.push_str(&format!("\\n+{} more", suppressed_count));
+            }
+        }
+        if let Some(max_label_len) = options.max_label_len {
+            label = truncate_label(&label, max_label_len);
+            if !options.tooltip {
+                extra_attributes.push_str(&format!(
+                    ", tooltip = \"{}\"",
+                    sanitize_for_encoding(&pkg.short_name, ascii_only_output())
+                ));
+            }
+        }
+
+        file.write_all(
+            format!(
+                "{} [fixedsize = true, height = {:.3}, width = {:.3}, penwidth = 2, label = \"{}\"{}];\n",
+                pos, node_sizes[pos], node_sizes[pos], label, extra_attributes
+            )
+            .as_bytes(),
+        )?;
+
+        for &dep in pkg.dependencies.iter() {
+            if suppressed_edges.contains(&(pos, dep)) {
+                continue;
+            }
+
+            let mut edge_attributes = if options.highlighted_edges.contains(&(pos, dep)) {
+                "color = red, penwidth = 3".to_string()
+            } else {
+                "penwidth = 0.5".to_string()
+            };
+            if tree.package(dep).is_build_dep {
+                edge_attributes.push_str(", style = dashed");
+            }
+            match options.edge_labels {
+                DotEdgeLabels::None => (),
+                DotEdgeLabels::Type => edge_attributes.push_str(
+                    if is_build_dependency(tree.package(dep), &build_tools) {
+                        ", label = \"build\""
+                    } else {
+                        ", label = \"runtime\""
+                    },
+                ),
+                DotEdgeLabels::Size => edge_attributes.push_str(&format!(
+                    ", label = \"{}\"",
+                    format_size_human(tree.package(dep).size_bytes)
+                )),
+            }
+            file.write_all(format!("{} -> {} [{}];\n", pos, dep, edge_attributes).as_bytes())?;
+        }
+    }
+
+    if options.level_labels {
+        file.write_all(
+            b"subgraph cluster_legend {\nlabel = \"Legend\";\nlegend [shape = plaintext, label = \"Node size = package size\\lLevel = dependency depth from root (root at level 0)\\l\"];\n}\n",
+        )?;
+    }
+
+    if options.subgraph_style == DotSubgraphStyle::None {
+        file.write_all(b"}\n")?;
+        file.flush()?;
+
+        return Ok(());
+    }
+
+    let mut level_node_hierarchy: Vec<String> = Vec::new();
+
+    for level in 0..tree.by_level.len() {
+        let total_elements = tree.by_level.len();
+        let chunk_size = 1 + total_elements / (1 + total_elements / 20);
+        let chunk_size = chunk_size.max(20);
+
+        for (sublevel, chunk) in tree.by_level[level].chunks(chunk_size).enumerate() {
+            let subgraph_header = match options.subgraph_style {
+                DotSubgraphStyle::Cluster => format!(
+                    "subgraph cluster_level_{}_{} {{\nlabel = \"Level {}\";\n",
+                    level, sublevel, level
+                ),
+                _ => format!("subgraph level_{}_{} {{\nrank = same;\n", level, sublevel),
+            };
+            file.write_all(subgraph_header.as_bytes())?;
+
+            for &pos in chunk {
+                file.write_all(format!("{}; ", pos).as_bytes())?;
+            }
+
+            // Being in the same rank as the first chunk of the level puts the label beside it.
+            if options.level_labels && sublevel == 0 {
+                file.write_all(
+                    format!(
+                        "level_label_{} [label = \"Level {}\", shape = plaintext];\n",
+                        level, level
+                    )
+                    .as_bytes(),
+                )?;
+            }
+
+            file.write_all(
+                format!("lnode{}_{} [style=\"invis\"];\n}}\n", level, sublevel).as_bytes(),
+            )?;
+            level_node_hierarchy.push(format!("lnode{}_{}", level, sublevel));
+        }
+    }
+
+    for edge in level_node_hierarchy.windows(2) {
+        file.write_all(format!("{} -> {} [style=\"invis\"];\n", edge[0], edge[1]).as_bytes())?;
+    }
+
+    if options.level_labels {
+        for level in 1..tree.by_level.len() {
+            file.write_all(
+                format!(
+                    "level_label_{} -> level_label_{} [arrowhead = none, style = dotted];\n",
+                    level - 1,
+                    level
+                )
+                .as_bytes(),
+            )?;
+        }
+    }
+
+    file.write_all(b"}\n")?;
+    file.flush()?;
+
+    Ok(())
+}
+
+/// Finds the package to focus on, either by its exact short name, its store path hash, or its name without the version (e.g. `openssl` for `openssl-3.0.13`).
+fn find_focus_package(tree: &PackageTree, name: &str) -> GenericResult<usize> {
+    if let Some(pos) = tree
+        .find_by_exact_name(name)
+        .or_else(|| tree.find_by_hash(name))
+    {
+        return Ok(pos);
+    }
+
+    // Short names may include the store path hash when names clash, so we look at the name from the store path instead.
+    let prefix = format!("{}-", name);
+    let candidates: Vec<usize> = tree
+        .nodes
+        .iter()
+        .enumerate()
+        .filter(|(_, pkg)| {
+            let symbolic_name = pkg.symbolic_name();
+            symbolic_name == name || symbolic_name.starts_with(&prefix)
+        })
+        .map(|(pos, _)| pos)
+        .collect();
+
+    match candidates.as_slice() {
+        [] => {
+            let similar = tree.find_by_name(name);
+            if similar.is_empty() {
+                return Err(
+                    format!("Couldn't find any package named '{}' to focus on", name).into(),
+                );
+            }
+
+            Err(format!(
+                "Couldn't find any package named '{}' to focus on, but these packages have it in their names: {}",
+                name,
+                similar
+                    .iter()
+                    .map(|&pos| tree.package(pos).short_name.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )
+            .into())
+        }
+        [pos] => Ok(*pos),
+        _ => Err(format!(
+            "Found multiple packages named '{}' to focus on, use one of these names instead: {}",
+            name,
+            candidates
+                .iter()
+                .map(|&pos| tree.package(pos).short_name.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        )
+        .into()),
+    }
+}
+
+fn format_package_path(tree: &PackageTree, path: &[usize]) -> String {
+    path.iter()
+        .map(|&pos| tree.package(pos).short_name.as_str())
+        .collect::<Vec<_>>()
+        .join(" -> ")
+}
+
+/// How `generate_dot_file` groups the packages in each level.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum DotSubgraphStyle {
+    /// Invisible `rank = same` subgraphs, which keep every level in its own row.
+    #[default]
+    Rank,
+    /// Clusters drawn as boxes labelled with the level.
+    Cluster,
+    /// No grouping, graphviz lays out the packages freely.
+    None,
+}
+
+/// What the edges in the dot file are labelled with.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum DotEdgeLabels {
+    /// Edges aren't labelled.
+    #[default]
+    None,
+    /// `build` or `runtime`, guessed from the name of the dependency with the same build tools as `--audit` (see `is_build_dependency`).
+    Type,
+    /// The size of the dependency.
+    Size,
+}
+
+/// Guesses whether a package is only needed to build the packages depending on it: development outputs and `build_tools` (names without the version).
+/// Nix only records runtime references, so this is a heuristic for packages that ended up in the closure anyway.
+fn is_build_dependency(pkg: &Package, build_tools: &[String]) -> bool {
+    pkg.short_name.contains("-dev")
+        || build_tools
+            .iter()
+            .any(|tool| pkg.name_without_version() == tool)
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum DotEngine {
+    Dot,
+    Neato,
+    Fdp,
+    Sfdp,
+    Circo,
+    Twopi,
+}
+
+impl DotEngine {
+    fn binary_name(&self) -> &'static str {
+        match self {
+            DotEngine::Dot => "dot",
+            DotEngine::Neato => "neato",
+            DotEngine::Fdp => "fdp",
+            DotEngine::Sfdp => "sfdp",
+            DotEngine::Circo => "circo",
+            DotEngine::Twopi => "twopi",
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum RenderFormat {
+    Png,
+    Svg,
+    Pdf,
+}
+
+impl RenderFormat {
+    fn extension(&self) -> &'static str {
+        match self {
+            RenderFormat::Png => "png",
+            RenderFormat::Svg => "svg",
+            RenderFormat::Pdf => "pdf",
+        }
+    }
+}
+
+/// Adds a comment at the top of an already generated dot file recommending which graphviz engine to render it with.
+fn add_dot_engine_comment(file_path: &PathBuf, engine: DotEngine) -> std::io::Result<()> {
+    if is_fifo(file_path) {
+        tracing::warn!(
+            "{} is a named pipe, so the dot engine comment can't be added to it",
+            file_path.display()
+        );
+        return Ok(());
+    }
+
+    let contents = std::fs::read(file_path)?;
+
+    let mut file = open_output_file(file_path)?;
+    file.write_all(format!("// Recommended engine: {}\n", engine.binary_name()).as_bytes())?;
+    file.write_all(&contents)?;
+    file.flush()?;
+
+    Ok(())
+}
+
+/// Path of the dot file for `level` when splitting `file_path` by level, e.g. `graph_level2.dot` for `graph.dot`.
+fn level_dot_file_path(file_path: &Path, level: usize) -> PathBuf {
+    let stem = file_path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    file_path.with_file_name(format!("{}_level{}.dot", stem, level))
+}
+
+/// Generates a dot file with only the packages at `level` and their edges to the packages at the level below, which are drawn dashed.
+/// Edges to packages further down are left out, but their count is kept in a comment at the top of the file.
+fn generate_dot_file_for_level(
+    tree: &PackageTree,
+    level: usize,
+    file_path: &Path,
+) -> std::io::Result<()> {
+    let mut file = open_output_file(file_path)?;
+
+    let mut next_level_deps: Vec<usize> = Vec::new();
+    let mut cross_level_edges = 0;
+    for &pos in tree.by_level[level].iter() {
+        for &dep in tree.package(pos).dependencies.iter() {
+            if tree.package(dep).level == level + 1 {
+                next_level_deps.push(dep);
+            } else {
+                cross_level_edges += 1;
+            }
+        }
+    }
+    next_level_deps.sort();
+    next_level_deps.dedup();
+
+    file.write_all(
+        format!(
+            "// Level {} of {}: {} packages, {} edges to packages more than one level below not shown\n",
+            level,
+            tree.by_level.len() - 1,
+            tree.by_level[level].len(),
+            cross_level_edges
+        )
+        .as_bytes(),
+    )?;
+    file.write_all(b"digraph {\n")?;
+
+    for &pos in tree.by_level[level].iter() {
+        let pkg = tree.package(pos);
+        file.write_all(
+            format!(
+                "{} [fixedsize = true, height = {:.3}, width = {:.3}, penwidth = 2, label = \"{}\"];\n",
+                pos,
+                pkg.graph_size,
+                pkg.graph_size,
+                sanitize_for_encoding(&pkg.short_name, ascii_only_output())
+            )
+            .as_bytes(),
+        )?;
+    }
+
+    for &pos in next_level_deps.iter() {
+        file.write_all(
+            format!(
+                "{} [style = dashed, label = \"{}\"];\n",
+                pos,
+                sanitize_for_encoding(&tree.package(pos).short_name, ascii_only_output())
+            )
+            .as_bytes(),
+        )?;
+    }
+
+    for &pos in tree.by_level[level].iter() {
+        for &dep in tree.package(pos).dependencies.iter() {
+            if tree.package(dep).level == level + 1 {
+                file.write_all(format!("{} -> {} [penwidth = 0.5];\n", pos, dep).as_bytes())?;
+            }
+        }
+    }
+
+    file.write_all(b"}\n")?;
+    file.flush()?;
+
+    Ok(())
+}
+
+/// Runs the graphviz binary for `engine` on an already generated dot file.
+fn render_dot_file(
+    dot_file_path: &PathBuf,
+    engine: DotEngine,
+    format: RenderFormat,
+    output_path: &PathBuf,
+) -> GenericResult<()> {
+    let status = Command::new(engine.binary_name())
+        .arg(format!("-T{}", format.extension()))
+        .arg("-o")
+        .arg(output_path)
+        .arg(dot_file_path)
+        .status()?;
+
+    if !status.success() {
+        return Err(format!(
+            "'{}' failed to render the dot file ({})",
+            engine.binary_name(),
+            status
+        )
+        .into());
+    }
+
+    Ok(())
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum FormulaToken {
+    Number(f64),
+    Field(FormulaField),
+    Operator(FormulaOperator),
+    OpenParen,
+    CloseParen,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum FormulaField {
+    SizeBytes,
+    Level,
+    MaxLevel,
+    FanIn,
+    FanOut,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum FormulaOperator {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+    Equal,
+    NotEqual,
+}
+
+impl FormulaOperator {
+    fn apply(&self, left: f64, right: f64) -> f64 {
+        match self {
+            FormulaOperator::Add => left + right,
+            FormulaOperator::Subtract => left - right,
+            FormulaOperator::Multiply => left * right,
+            FormulaOperator::Divide => left / right,
+            FormulaOperator::Less => (left < right) as u8 as f64,
+            FormulaOperator::LessOrEqual => (left <= right) as u8 as f64,
+            FormulaOperator::Greater => (left > right) as u8 as f64,
+            FormulaOperator::GreaterOrEqual => (left >= right) as u8 as f64,
+            FormulaOperator::Equal => (left == right) as u8 as f64,
+            FormulaOperator::NotEqual => (left != right) as u8 as f64,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Formula {
+    Number(f64),
+    Field(FormulaField),
+    Negate(Box<Formula>),
+    Binary(Box<Formula>, FormulaOperator, Box<Formula>),
+}
+
+impl Formula {
+    fn evaluate(&self, tree: &PackageTree, pkg: &Package) -> f64 {
+        match self {
+            Formula::Number(value) => *value,
+            Formula::Field(FormulaField::SizeBytes) => pkg.size_bytes as f64,
+            Formula::Field(FormulaField::Level) => pkg.level as f64,
+            Formula::Field(FormulaField::MaxLevel) => tree.by_level.len().saturating_sub(1) as f64,
+            Formula::Field(FormulaField::FanIn) => pkg.used_by.len() as f64,
+            Formula::Field(FormulaField::FanOut) => pkg.dependencies.len() as f64,
+            Formula::Negate(formula) => -formula.evaluate(tree, pkg),
+            Formula::Binary(left, operator, right) => {
+                operator.apply(left.evaluate(tree, pkg), right.evaluate(tree, pkg))
+            }
+        }
+    }
+}
+
+fn tokenize_formula(formula: &str) -> Result<Vec<FormulaToken>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = formula.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c.is_ascii_digit() || c == '.' {
+            let mut number = String::new();
+            while let Some(&c) = chars.peek().filter(|c| c.is_ascii_digit() || **c == '.') {
+                number.push(c);
+                chars.next();
+            }
+            let number = number
+                .parse()
+                .map_err(|_| format!("invalid number '{}'", number))?;
+            tokens.push(FormulaToken::Number(number));
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let mut name = String::new();
+            while let Some(&c) = chars
+                .peek()
+                .filter(|c| c.is_ascii_alphanumeric() || **c == '_')
+            {
+                name.push(c);
+                chars.next();
+            }
+            let field = match name.as_str() {
+                "size_bytes" => FormulaField::SizeBytes,
+                "level" => FormulaField::Level,
+                "max_level" => FormulaField::MaxLevel,
+                "fan_in" => FormulaField::FanIn,
+                "fan_out" => FormulaField::FanOut,
+                _ => {
+                    return Err(format!(
+                        "unknown field '{}', expected one of: size_bytes, level, max_level, fan_in, fan_out",
+                        name
+                    ))
+                }
+            };
+            tokens.push(FormulaToken::Field(field));
+        } else {
+            chars.next();
+            let followed_by_equals = chars.next_if_eq(&'=').is_some();
+            let token = match (c, followed_by_equals) {
+                ('(', false) => FormulaToken::OpenParen,
+                (')', false) => FormulaToken::CloseParen,
+                ('+', false) => FormulaToken::Operator(FormulaOperator::Add),
+                ('-', false) => FormulaToken::Operator(FormulaOperator::Subtract),
+                ('*', false) => FormulaToken::Operator(FormulaOperator::Multiply),
+                ('/', false) => FormulaToken::Operator(FormulaOperator::Divide),
+                ('<', false) => FormulaToken::Operator(FormulaOperator::Less),
+                ('<', true) => FormulaToken::Operator(FormulaOperator::LessOrEqual),
+                ('>', false) => FormulaToken::Operator(FormulaOperator::Greater),
+                ('>', true) => FormulaToken::Operator(FormulaOperator::GreaterOrEqual),
+                ('=', true) => FormulaToken::Operator(FormulaOperator::Equal),
+                ('!', true) => FormulaToken::Operator(FormulaOperator::NotEqual),
+                _ => return Err(format!("unexpected character '{}'", c)),
+            };
+            tokens.push(token);
+        }
+    }
+
+    Ok(tokens)
+}
+
+/// Recursive descent parser for csv formulas. From the lowest to the highest precedence:
+/// comparisons (`<`, `<=`, `>`, `>=`, `==`, `!=`), then `+` and `-`, then `*` and `/`, then negation, numbers, fields and parentheses.
+struct FormulaParser {
+    tokens: Vec<FormulaToken>,
+    pos: usize,
+}
+
+impl FormulaParser {
+    fn parse(formula: &str) -> Result<Formula, String> {
+        let mut parser = FormulaParser {
+            tokens: tokenize_formula(formula)?,
+            pos: 0,
+        };
+
+        let parsed = parser.parse_comparison()?;
+        if parser.pos < parser.tokens.len() {
+            return Err(format!(
+                "unexpected {:?} in '{}'",
+                parser.tokens[parser.pos], formula
+            ));
+        }
+
+        Ok(parsed)
+    }
+
+    fn next_operator_in(&mut self, operators: &[FormulaOperator]) -> Option<FormulaOperator> {
+        match self.tokens.get(self.pos) {
+            Some(FormulaToken::Operator(operator)) if operators.contains(operator) => {
+                self.pos += 1;
+                Some(*operator)
+            }
+            _ => None,
+        }
+    }
+
+    fn parse_comparison(&mut self) -> Result<Formula, String> {
+        let left = self.parse_additive()?;
+
+        match self.next_operator_in(&[
+            FormulaOperator::Less,
+            FormulaOperator::LessOrEqual,
+            FormulaOperator::Greater,
+            FormulaOperator::GreaterOrEqual,
+            FormulaOperator::Equal,
+            FormulaOperator::NotEqual,
+        ]) {
+            Some(operator) => Ok(Formula::Binary(
+                Box::new(left),
+                operator,
+                Box::new(self.parse_additive()?),
+            )),
+            None => Ok(left),
+        }
+    }
+
+    fn parse_additive(&mut self) -> Result<Formula, String> {
+        let mut formula = self.parse_multiplicative()?;
+
+        while let Some(operator) =
+            self.next_operator_in(&[FormulaOperator::Add, FormulaOperator::Subtract])
+        {
+            formula = Formula::Binary(
+                Box::new(formula),
+                operator,
+                Box::new(self.parse_multiplicative()?),
+            );
+        }
+
+        Ok(formula)
+    }
+
+    fn parse_multiplicative(&mut self) -> Result<Formula, String> {
+        let mut formula = self.parse_unary()?;
+
+        while let Some(operator) =
+            self.next_operator_in(&[FormulaOperator::Multiply, FormulaOperator::Divide])
+        {
+            formula = Formula::Binary(Box::new(formula), operator, Box::new(self.parse_unary()?));
+        }
+
+        Ok(formula)
+    }
+
+    fn parse_unary(&mut self) -> Result<Formula, String> {
+        let token = self
+            .tokens
+            .get(self.pos)
+            .copied()
+            .ok_or("unexpected end of formula")?;
+        self.pos += 1;
+
+        match token {
+            FormulaToken::Number(value) => Ok(Formula::Number(value)),
+            FormulaToken::Field(field) => Ok(Formula::Field(field)),
+            FormulaToken::Operator(FormulaOperator::Subtract) => {
+                Ok(Formula::Negate(Box::new(self.parse_unary()?)))
+            }
+            FormulaToken::OpenParen => {
+                let formula = self.parse_comparison()?;
+                match self.tokens.get(self.pos) {
+                    Some(FormulaToken::CloseParen) => {
+                        self.pos += 1;
+                        Ok(formula)
+                    }
+                    _ => Err("missing a closing parenthesis".into()),
+                }
+            }
+            token => Err(format!("unexpected {:?}", token)),
+        }
+    }
+}
+
+/// An extra csv column requested with `--csv-extra-columns <NAME>=<FORMULA>`.
+#[derive(Clone, Debug)]
+struct CsvExtraColumn {
+    name: String,
+    formula: Formula,
+}
+
+fn parse_csv_extra_column(value: &str) -> Result<CsvExtraColumn, String> {
+    let (name, formula) = value
+        .split_once('=')
+        .filter(|(name, _)| !name.trim().is_empty())
+        .ok_or_else(|| format!("expected <NAME>=<FORMULA>, got '{}'", value))?;
+
+    Ok(CsvExtraColumn {
+        name: name.trim().to_string(),
+        formula: FormulaParser::parse(formula)?,
+    })
+}
+
+#[derive(Default)]
+struct CsvOptions<'a> {
+    // Prepends a UTF-8 BOM to the file.
+    bom: bool,
+    crlf: bool,
+    // Appends rows instead of overwriting the file, with the time of the run in this column.
+    timestamp_column: Option<&'a str>,
+    // Computes the unique closure contribution (and shared percentage) of every package, not only the direct dependencies of the root.
+    all_unique_contributions: bool,
+}
+
+/// Generates a csv file with one row per package, ordered by level.
+/// With a `timestamp_column`, rows are appended to the file (the header is only written if it's empty) with the time of this run in that column.
+fn generate_package_list(
+    tree: &PackageTree,
+    file_path: &Path,
+    fanout_threshold: usize,
+    extra_columns: &[CsvExtraColumn],
+    options: &CsvOptions,
+) -> std::io::Result<()> {
+    let timestamp_column = options.timestamp_column;
+    let appending = timestamp_column.is_some()
+        && file_path
+            .metadata()
+            .is_ok_and(|metadata| metadata.len() > 0);
+    let mut file = if timestamp_column.is_some() {
+        File::options().append(true).create(true).open(file_path)?
+    } else {
+        open_output_file(file_path)?
+    };
+    // Excel only detects UTF-8 with the BOM, and prefers CRLF line endings on Windows.
+    if options.bom && !appending {
+        file.write_all(b"\xef\xbb\xbf")?;
+    }
+    let newline: &[u8] = if options.crlf { b"\r\n" } else { b"\n" };
+    let run_timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| unix_timestamp_to_iso8601(now.as_secs()))
+        .unwrap_or_default();
+
+    let articulation_points: HashSet<usize> = tree.find_articulation_points().into_iter().collect();
+    let total_bytes = tree.sum_package_bytes();
+    // Only computed for the direct dependencies of the root unless asked for, since it needs a traversal of the whole tree per package.
+    let unique_contributions: HashMap<usize, usize> = if options.all_unique_contributions {
+        (0..tree.nodes.len())
+            .map(|pos| (pos, tree.unique_closure_contribution(pos)))
+            .collect()
+    } else {
+        tree.package(0)
+            .dependencies
+            .iter()
+            .map(|&dep| (dep, tree.unique_closure_contribution(dep)))
+            .collect()
+    };
+    let shared_percentages: HashMap<usize, f64> = unique_contributions
+        .iter()
+        .filter_map(|(&pos, &unique_contribution)| {
+            Some((pos, tree.shared_percentage(pos, unique_contribution)?))
+        })
+        .collect();
+
+    if !appending {
+        file.write_all(
+            b"pos,level,package_name,size_bytes,dependencies,path,fanout_warning,registered_at,is_spf,download_size_bytes,compression_ratio,unique_closure_contribution_bytes,influence_score,size_pct,is_build_dep,shared_pct",
+        )?;
+        for column in extra_columns.iter() {
+            file.write_all(format!(",{}", column.name).as_bytes())?;
+        }
+        if let Some(timestamp_column) = timestamp_column {
+            file.write_all(format!(",{}", timestamp_column).as_bytes())?;
+        }
+        file.write_all(newline)?;
+    }
+
+    for level in 0..tree.by_level.len() {
+        for &pkg_pos in tree.by_level[level].iter() {
+            let pkg = tree.package(pkg_pos);
+
+            file.write_all(
+                format!(
+                    "{},{},{},{},\"{}\",{},{},{},{},{},{},{},{:.2},{:.2},{},{}",
+                    pkg_pos,
+                    level,
+                    sanitize_for_encoding(&pkg.short_name, ascii_only_output()),
+                    pkg.size_bytes,
+                    pkg.dependencies
+                        .iter()
+                        .map(usize::to_string)
+                        .collect::<Vec<_>>()
+                        .join(","),
+                    sanitize_for_encoding(&pkg.path, ascii_only_output()),
+                    (pkg.dependencies.len() >= fanout_threshold) as u8,
+                    pkg.registration_time
+                        .map(unix_timestamp_to_iso8601)
+                        .unwrap_or_default(),
+                    articulation_points.contains(&pkg_pos) as u8,
+                    pkg.download_size_bytes
+                        .map(|download_size_bytes| download_size_bytes.to_string())
+                        .unwrap_or_default(),
+                    pkg.compression_ratio()
+                        .map(|ratio| format!("{:.3}", ratio))
+                        .unwrap_or_default(),
+                    unique_contributions
+                        .get(&pkg_pos)
+                        .map(usize::to_string)
+                        .unwrap_or_default(),
+                    pkg.influence_score(),
+                    pkg.size_percentage(total_bytes),
+                    pkg.is_build_dep as u8,
+                    shared_percentages
+                        .get(&pkg_pos)
+                        .map(|percentage| format!("{:.2}", percentage))
+                        .unwrap_or_default()
+                )
+                .as_bytes(),
+            )?;
+
+            for column in extra_columns.iter() {
+                file.write_all(format!(",{}", column.formula.evaluate(tree, pkg)).as_bytes())?;
+            }
+            if timestamp_column.is_some() {
+                file.write_all(format!(",{}", run_timestamp).as_bytes())?;
+            }
+            file.write_all(newline)?;
+        }
+    }
+
+    file.flush()?;
+
+    Ok(())
+}
+
+/// A parsed json value, only used to read the output of other tools (e.g. `nix path-info --json`).
+#[derive(Debug)]
+enum JsonValue {
+    Null,
+    #[allow(dead_code)]
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<JsonValue>),
+    // Keys are kept in the order they appear.
+    Object(Vec<(String, JsonValue)>),
+}
+
+impl JsonValue {
+    fn get(&self, key: &str) -> Option<&JsonValue> {
+        match self {
+            JsonValue::Object(entries) => entries
+                .iter()
+                .find(|(entry_key, _)| entry_key == key)
+                .map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> Option<&str> {
+        match self {
+            JsonValue::String(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    fn as_f64(&self) -> Option<f64> {
+        match self {
+            JsonValue::Number(value) => Some(*value),
+            _ => None,
+        }
+    }
+}
+
+/// Parses a complete json document.
+fn parse_json(input: &str) -> GenericResult<JsonValue> {
+    let mut chars = input.chars().peekable();
+    let value = parse_json_value(&mut chars)?;
+
+    if chars.any(|c| !c.is_whitespace()) {
+        return Err("Found trailing characters after the json value".into());
+    }
+
+    Ok(value)
+}
+
+fn parse_json_value(chars: &mut std::iter::Peekable<std::str::Chars>) -> GenericResult<JsonValue> {
+    while chars.next_if(|c| c.is_whitespace()).is_some() {}
+
+    let literal = match chars.peek() {
+        Some('n') => Some(("null", JsonValue::Null)),
+        Some('t') => Some(("true", JsonValue::Bool(true))),
+        Some('f') => Some(("false", JsonValue::Bool(false))),
+        _ => None,
+    };
+    if let Some((literal, value)) = literal {
+        if !literal
+            .chars()
+            .all(|expected| chars.next() == Some(expected))
+        {
+            return Err(format!("Expected '{}' in the json value", literal).into());
+        }
+        return Ok(value);
+    }
+
+    match chars.peek() {
+        None => Err("Unexpected end of the json value".into()),
+        Some('"') => Ok(JsonValue::String(parse_json_string(chars)?)),
+        Some('[') => {
+            chars.next();
+            let mut values = Vec::new();
+            loop {
+                while chars.next_if(|c| c.is_whitespace()).is_some() {}
+                if values.is_empty() && chars.next_if_eq(&']').is_some() {
+                    return Ok(JsonValue::Array(values));
+                }
+
+                values.push(parse_json_value(chars)?);
+
+                while chars.next_if(|c| c.is_whitespace()).is_some() {}
+                match chars.next() {
+                    Some(',') => continue,
+                    Some(']') => return Ok(JsonValue::Array(values)),
+                    _ => return Err("Expected ',' or ']' in a json array".into()),
+                }
+            }
+        }
+        Some('{') => {
+            chars.next();
+            let mut entries = Vec::new();
+            loop {
+                while chars.next_if(|c| c.is_whitespace()).is_some() {}
+                if entries.is_empty() && chars.next_if_eq(&'}').is_some() {
+                    return Ok(JsonValue::Object(entries));
+                }
+
+                let key = parse_json_string(chars)?;
+                while chars.next_if(|c| c.is_whitespace()).is_some() {}
+                if chars.next() != Some(':') {
+                    return Err("Expected ':' after a key in a json object".into());
+                }
+                entries.push((key, parse_json_value(chars)?));
+
+                while chars.next_if(|c| c.is_whitespace()).is_some() {}
+                match chars.next() {
+                    Some(',') => continue,
+                    Some('}') => return Ok(JsonValue::Object(entries)),
+                    _ => return Err("Expected ',' or '}' in a json object".into()),
+                }
+            }
+        }
+        Some(_) => {
+            let mut number = String::new();
+            while let Some(c) = chars.next_if(|c| c.is_ascii_digit() || "+-.eE".contains(*c)) {
+                number.push(c);
+            }
+            number
+                .parse()
+                .map(JsonValue::Number)
+                .map_err(|_| format!("Couldn't parse '{}' as a json number", number).into())
+        }
+    }
+}
+
+fn parse_json_string(chars: &mut std::iter::Peekable<std::str::Chars>) -> GenericResult<String> {
+    if chars.next() != Some('"') {
+        return Err("Expected a json string".into());
+    }
+
+    let mut value = String::new();
+    loop {
+        match chars.next() {
+            None => return Err("Unterminated json string".into()),
+            Some('"') => return Ok(value),
+            Some('\\') => match chars.next() {
+                Some('n') => value.push('\n'),
+                Some('r') => value.push('\r'),
+                Some('t') => value.push('\t'),
+                Some('b') => value.push('\u{8}'),
+                Some('f') => value.push('\u{c}'),
+                Some('u') => {
+                    let hex: String = chars.by_ref().take(4).collect();
+                    let code = u32::from_str_radix(&hex, 16)
+                        .map_err(|_| format!("Invalid json escape '\\u{}'", hex))?;
+                    // Surrogate pairs don't show up in store paths, so they're replaced instead of being combined.
+                    value.push(char::from_u32(code).unwrap_or(char::REPLACEMENT_CHARACTER));
+                }
+                Some(c) => value.push(c),
+                None => return Err("Unterminated json string".into()),
+            },
+            Some(c) => value.push(c),
+        }
+    }
+}
+
+/// Adds the derivation of every package in the runtime closure, and the inputs of those derivations, as a build layer below the runtime packages.
+/// Every package depends on its derivation, and every derivation on its inputs (other derivations and sources), without querying their sizes.
+/// Packages whose derivation isn't in the store anymore (e.g. substituted from a binary cache) don't get a build layer.
+/// This runs two nix-store processes per package, and `calculate_graph_properties` must be called again afterwards.
+fn add_build_time_deps(tree: &mut PackageTree, nix_extra_args: &[String]) -> GenericResult<()> {
+    let _span = tracing::info_span!("query_build_time_deps").entered();
+    let mut positions: HashMap<String, usize> = tree
+        .nodes
+        .iter()
+        .enumerate()
+        .map(|(pos, pkg)| (pkg.path.clone(), pos))
+        .collect();
+    let mut add_build_dep = |tree: &mut PackageTree, path: &str| {
+        *positions.entry(path.to_string()).or_insert_with(|| {
+            let mut pkg = Package::with_size(path.to_string(), 0);
+            pkg.is_build_dep = true;
+            tree.add_package(pkg)
+        })
+    };
+
+    for pos in 0..tree.nodes.len() {
+        if tree.package(pos).is_build_dep {
+            continue;
+        }
+
+        let deriver_output = run_nix_store_with_retry(
+            &["--query", "--deriver", &tree.package(pos).path],
+            "nix-store",
+            nix_extra_args,
+        )?;
+        let deriver = std::str::from_utf8(&deriver_output)?.trim().to_string();
+        if !deriver.ends_with(".drv") || !Path::new(&deriver).exists() {
+            tracing::debug!("no derivation in the store for {}", tree.package(pos).path);
+            continue;
+        }
+
+        let drv_pos = add_build_dep(tree, &deriver);
+        tree.register_dependency(pos, drv_pos);
+
+        // Derivations shared by several packages (e.g. multiple outputs) only need their inputs added once.
+        if !tree.package(drv_pos).dependencies.is_empty() {
+            continue;
+        }
+        let inputs_output = run_nix_store_with_retry(
+            &["--query", "--references", &deriver],
+            "nix-store",
+            nix_extra_args,
+        )?;
+        for input in std::str::from_utf8(&inputs_output)?
+            .lines()
+            .map(str::trim)
+            .filter(|input| !input.is_empty() && *input != deriver)
+        {
+            let input_pos = add_build_dep(tree, input);
+            tree.register_dependency(drv_pos, input_pos);
+        }
+    }
+
+    Ok(())
+}
+
+/// Queries the download size (the size of the compressed NAR in the binary cache) of every package with `nix path-info`.
+/// Packages the binary cache doesn't know about keep `download_size_bytes` as `None`.
+fn query_download_sizes(tree: &mut PackageTree, nix_extra_args: &[String]) -> GenericResult<()> {
+    let path_info_output = run_nix_store_with_retry(
+        &[
+            "path-info",
+            "--extra-experimental-features",
+            "nix-command",
+            "--json",
+            "--recursive",
+            &tree.package(0).path,
+        ],
+        "nix",
+        nix_extra_args,
+    )?;
+    let path_info = parse_json(std::str::from_utf8(&path_info_output)?)?;
+
+    // Older versions of nix print an array of objects with a `path` key, newer ones an object keyed by path.
+    let infos: Vec<(&str, &JsonValue)> = match &path_info {
+        JsonValue::Array(infos) => infos
+            .iter()
+            .filter_map(|info| Some((info.get("path")?.as_str()?, info)))
+            .collect(),
+        JsonValue::Object(entries) => entries
+            .iter()
+            .map(|(path, info)| (path.as_str(), info))
+            .collect(),
+        _ => return Err("Got an unexpected output from 'nix path-info --json'".into()),
+    };
+    let download_sizes: HashMap<&str, usize> = infos
+        .into_iter()
+        .filter_map(|(path, info)| Some((path, info.get("downloadSize")?.as_f64()? as usize)))
+        .collect();
+
+    for pkg in tree.nodes.iter_mut() {
+        pkg.download_size_bytes = download_sizes.get(pkg.path.as_str()).copied();
+    }
+
+    Ok(())
+}
+
+/// Lays out json generated by this tool again, either compact (`indent` is `None`) or with `indent` spaces per nesting level.
+/// This expects valid json, and only changes whitespace outside strings.
+fn reformat_json(json: &str, indent: Option<usize>) -> String {
+    // First remove all whitespace outside strings, so we only need to add it back where we want it.
+    let mut compact = String::with_capacity(json.len());
+    let mut in_string = false;
+    let mut escaped = false;
+    for c in json.chars() {
+        if in_string {
+            in_string = escaped || c != '"';
+            escaped = !escaped && c == '\\';
+        } else if c == '"' {
+            in_string = true;
+        } else if c.is_whitespace() {
+            continue;
+        }
+        compact.push(c);
+    }
+
+    let Some(indent) = indent else {
+        return compact;
+    };
+
+    let mut pretty = String::with_capacity(compact.len() * 2);
+    let mut depth = 0;
+    let mut chars = compact.chars().peekable();
+    let newline = |pretty: &mut String, depth: usize| {
+        pretty.push('\n');
+        pretty.push_str(&" ".repeat(depth * indent));
+    };
+    in_string = false;
+    escaped = false;
+    while let Some(c) = chars.next() {
+        if in_string {
+            in_string = escaped || c != '"';
+            escaped = !escaped && c == '\\';
+            pretty.push(c);
+            continue;
+        }
+
+        match c {
+            '"' => {
+                in_string = true;
+                pretty.push(c);
+            }
+            '{' | '[' => {
+                pretty.push(c);
+                // Empty objects and arrays stay on one line.
+                if matches!(chars.peek(), Some('}' | ']')) {
+                    pretty.push(chars.next().unwrap());
+                } else {
+                    depth += 1;
+                    newline(&mut pretty, depth);
+                }
+            }
+            '}' | ']' => {
+                depth -= 1;
+                newline(&mut pretty, depth);
+                pretty.push(c);
+            }
+            ',' => {
+                pretty.push(c);
+                newline(&mut pretty, depth);
+            }
+            ':' => pretty.push_str(": "),
+            c => pretty.push(c),
+        }
+    }
+
+    pretty
+}
+
+fn json_escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+
+    sanitize_for_encoding(&escaped, ascii_only_output())
+}
+
+#[derive(Serialize)]
+struct PackageBytes<'a> {
+    name: &'a str,
+    bytes: usize,
+}
+
+#[derive(Serialize)]
+struct LevelCount {
+    level: usize,
+    count: usize,
+}
+
+#[derive(Serialize)]
+struct LevelBytes {
+    level: usize,
+    bytes: usize,
+}
+
+/// The object printed by `--print-stats-json`.
+#[derive(Serialize)]
+struct StatsJson<'a> {
+    total_packages: usize,
+    total_bytes: usize,
+    min_bytes: usize,
+    max_bytes: usize,
+    mean_bytes: f64,
+    median_bytes: f64,
+    stddev_bytes: f64,
+    max_level: usize,
+    leaf_count: usize,
+    root_count: usize,
+    edge_count: usize,
+    most_referenced_name: &'a str,
+    most_referenced_fanin: usize,
+    top_5_by_size: Vec<PackageBytes<'a>>,
+    level_distribution: Vec<LevelCount>,
+    level_size_distribution: Vec<LevelBytes>,
+    level_count_stddev: f64,
+    analysis_timestamp: String,
+}
+
+/// Statistics about the whole tree as a pretty-printed json object, meant to be consumed by scripts.
+fn stats_json(tree: &PackageTree) -> GenericResult<String> {
+    let summary = tree.summary();
+
+    let most_referenced = tree
+        .nodes
+        .iter()
+        .max_by_key(|pkg| pkg.used_by.len())
+        .unwrap();
+
+    let mut by_size: Vec<&Package> = tree.nodes.iter().collect();
+    by_size.sort();
+
+    let analysis_timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+
+    let stats = StatsJson {
+        total_packages: summary.total_packages,
+        total_bytes: summary.total_bytes,
+        min_bytes: summary.min_bytes,
+        max_bytes: summary.max_bytes,
+        mean_bytes: summary.mean_bytes,
+        median_bytes: summary.median_bytes,
+        stddev_bytes: summary.std_dev_bytes,
+        max_level: summary.max_level,
+        leaf_count: summary.leaf_count,
+        root_count: summary.root_count,
+        edge_count: summary.edge_count,
+        most_referenced_name: &most_referenced.short_name,
+        most_referenced_fanin: most_referenced.used_by.len(),
+        top_5_by_size: by_size
+            .iter()
+            .take(5)
+            .map(|pkg| PackageBytes {
+                name: &pkg.short_name,
+                bytes: pkg.size_bytes,
+            })
+            .collect(),
+        level_distribution: tree
+            .level_distribution()
+            .into_iter()
+            .map(|(level, count)| LevelCount { level, count })
+            .collect(),
+        level_size_distribution: tree
+            .level_size_distribution()
+            .into_iter()
+            .map(|(level, bytes)| LevelBytes { level, bytes })
+            .collect(),
+        level_count_stddev: tree.level_count_stddev(),
+        analysis_timestamp: unix_timestamp_to_iso8601(analysis_timestamp),
+    };
+
+    // serde_json only escapes what json requires, so non-ASCII characters (which can only be in strings) are escaped here.
+    Ok(sanitize_for_encoding(
+        &serde_json::to_string_pretty(&stats)?,
+        ascii_only_output(),
+    ))
+}
+
+/// Distance (in Cytoscape.js pixels) between two neighbouring nodes, both within a level and between levels.
+const CYTOSCAPE_NODE_SPACING: usize = 100;
+
+fn cytoscape_json(tree: &PackageTree) -> String {
+    let mut nodes: Vec<String> = Vec::new();
+    let mut edges: Vec<String> = Vec::new();
+
+    for level in 0..tree.by_level.len() {
+        for (index_in_level, &pos) in tree.by_level[level].iter().enumerate() {
+            let pkg = tree.package(pos);
+
+            nodes.push(format!(
+                "{{\"data\": {{\"id\": \"{}\", \"short_name\": \"{}\", \"size_bytes\": {}, \"level\": {}, \"path\": \"{}\"}}, \"position\": {{\"x\": {}, \"y\": {}}}}}",
+                pos,
+                json_escape(&pkg.short_name),
+                pkg.size_bytes,
+                level,
+                json_escape(&pkg.path),
+                index_in_level * CYTOSCAPE_NODE_SPACING,
+                level * CYTOSCAPE_NODE_SPACING,
+            ));
+
+            for &dep in pkg.dependencies.iter() {
+                edges.push(format!(
+                    "{{\"data\": {{\"id\": \"{}-{}\", \"source\": \"{}\", \"target\": \"{}\"}}}}",
+                    pos, dep, pos, dep
+                ));
+            }
+        }
+    }
+
+    format!(
+        "{{\"elements\": {{\"nodes\": [\n{}\n], \"edges\": [\n{}\n]}}}}\n",
+        nodes.join(",\n"),
+        edges.join(",\n")
+    )
+}
+
+/// Generates a Cytoscape.js JSON file, and a companion html file at `<file_path>.html` which renders the same JSON with Cytoscape.js loaded from a CDN.
+/// Node positions follow the same level organisation used for the dot file, so the `preset` layout already gives something readable.
+fn generate_cytoscape_file(
+    tree: &PackageTree,
+    file_path: &Path,
+    json_indent: Option<usize>,
+) -> std::io::Result<()> {
+    let json = cytoscape_json(tree);
+
+    let mut file = open_output_file(file_path)?;
+    file.write_all(reformat_json(&json, json_indent).as_bytes())?;
+    file.flush()?;
+
+    let mut html_path = file_path.as_os_str().to_owned();
+    html_path.push(".html");
+
+    let mut html_file = open_output_file(Path::new(&html_path))?;
+    html_file.write_all(
+        format!(
+            r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<script src="https://cdnjs.cloudflare.com/ajax/libs/cytoscape/3.28.1/cytoscape.min.js"></script>
+<style>
+html, body, #cy {{ width: 100%; height: 100%; margin: 0; }}
+</style>
+</head>
+<body>
+<div id="cy"></div>
+<script>
+const graph = {};
+cytoscape({{
+  container: document.getElementById("cy"),
+  elements: graph.elements,
+  layout: {{ name: "preset" }},
+  style: [
+    {{ selector: "node", style: {{ "label": "data(short_name)" }} }},
+    {{ selector: "edge", style: {{ "width": 0.5, "curve-style": "straight", "target-arrow-shape": "triangle" }} }}
+  ]
+}});
+</script>
+</body>
+</html>
+"#,
+            json.trim_end()
+        )
+        .as_bytes(),
+    )?;
+    html_file.flush()?;
+
+    Ok(())
+}
+
+/// Builds the `{ name, size, fan_in, shared, children }` hierarchy used by D3 for the subtree rooted at `pos`.
+/// D3 only draws trees, so packages used by more than one package only show up under their first parent, marked as `shared`.
+fn d3_tree_json(tree: &PackageTree, pos: usize) -> String {
+    let pkg = tree.package(pos);
+    let children: Vec<_> = pkg
+        .dependencies
+        .iter()
+        .filter(|&&dep| tree.package(dep).used_by.first() == Some(&pos))
+        .map(|&dep| d3_tree_json(tree, dep))
+        .collect();
+
+    format!(
+        "{{\"name\": \"{}\", \"size\": {}, \"fan_in\": {}, \"shared\": {}, \"children\": [{}]}}",
+        json_escape(&pkg.short_name),
+        pkg.size_bytes,
+        pkg.used_by.len(),
+        pkg.used_by.len() > 1,
+        children.join(", ")
+    )
+}
+
+/// Generates an html file rendering the closure as a D3.js collapsible tree, loaded from a CDN.
+/// The tree starts with only the root and its direct dependencies, and clicking a package expands or collapses it.
+/// Circle areas follow package sizes, colors follow how many packages use each package, and shared packages have a red border.
+fn generate_d3_tree_file(tree: &PackageTree, file_path: &Path) -> std::io::Result<()> {
+    let mut file = open_output_file(file_path)?;
+    file.write_all(
+        format!(
+            r##"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<script src="https://cdn.jsdelivr.net/npm/d3@7"></script>
+<style>
+html, body {{ margin: 0; font: 12px sans-serif; }}
+.link {{ fill: none; stroke: #ccc; }}
+.node {{ cursor: pointer; }}
+</style>
+</head>
+<body>
+<svg id="tree"></svg>
+<script>
+const data = {};
+const root = d3.hierarchy(data);
+const nodeHeight = 20, levelWidth = 200;
+const maxSize = d3.max(root.descendants(), d => d.data.size) || 1;
+const maxFanIn = d3.max(root.descendants(), d => d.data.fan_in) || 1;
+const radius = d3.scaleSqrt().domain([0, maxSize]).range([2, 12]);
+const color = d3.scaleSequential(d3.interpolateYlOrRd).domain([0, maxFanIn]);
+const layout = d3.tree().nodeSize([nodeHeight, levelWidth]);
+const svg = d3.select("#tree");
+const g = svg.append("g");
+
+root.descendants().forEach((d, i) => {{
+  d.id = i;
+  d._children = d.children;
+  if (d.depth > 0) d.children = null;
+}});
+
+function update() {{
+  layout(root);
+  const nodes = root.descendants();
+  const [minX, maxX] = d3.extent(nodes, d => d.x);
+  svg.attr("width", (d3.max(nodes, d => d.y) || 0) + 2 * levelWidth)
+    .attr("height", maxX - minX + 2 * nodeHeight);
+  g.attr("transform", `translate(${{levelWidth / 2}},${{nodeHeight - minX}})`);
+
+  g.selectAll(".link").data(root.links(), d => d.target.id).join("path")
+    .attr("class", "link")
+    .attr("d", d3.linkHorizontal().x(d => d.y).y(d => d.x));
+
+  const node = g.selectAll(".node").data(nodes, d => d.id).join(enter => {{
+    const node = enter.append("g").attr("class", "node").on("click", (event, d) => {{
+      [d.children, d._children] = [d.children ? null : d._children, d.children];
+      update();
+    }});
+    node.append("circle")
+      .attr("r", d => radius(d.data.size))
+      .attr("fill", d => color(d.data.fan_in))
+      .attr("stroke", d => d.data.shared ? "#d62728" : "#555")
+      .attr("stroke-width", d => d.data.shared ? 2.5 : 1);
+    node.append("text").attr("dx", 14).attr("dy", 4).text(d => d.data.name);
+    node.append("title").text(d => `${{d.data.name}}\n${{d.data.size}} bytes\nused by ${{d.data.fan_in}} packages`);
+    return node;
+  }});
+  node.attr("transform", d => `translate(${{d.y}},${{d.x}})`);
+}}
+
+update();
+</script>
+</body>
+</html>
+"##,
+            d3_tree_json(tree, 0)
+        )
+        .as_bytes(),
+    )?;
+    file.flush()?;
+
+    Ok(())
+}
+
+/// Escapes a string for a TOML basic string.
+/// Unlike json, TOML doesn't allow surrogates in `\u` escapes, so characters outside the Basic Multilingual Plane use `\UXXXXXXXX` with `--output-encoding ascii`,
+/// and DEL has to be escaped as well.
+fn toml_escape(value: &str, ascii_only: bool) -> String {
+    let mut escaped = String::with_capacity(value.len());
+
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 || c == '\x7f' => {
+                escaped.push_str(&format!("\\u{:04x}", c as u32))
+            }
+            c if ascii_only && (c as u32) > 0xffff => {
+                escaped.push_str(&format!("\\U{:08x}", c as u32))
+            }
+            c if ascii_only && !c.is_ascii() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+/// The `[metadata]` table of the file written by `generate_toml_file`.
+#[derive(Serialize)]
+#[cfg_attr(test, derive(Deserialize))]
+struct TomlMetadata {
+    total_packages: usize,
+    total_bytes: usize,
+    max_level: usize,
+}
+
+/// One `[[packages]]` table of the file written by `generate_toml_file`.
+#[derive(Serialize)]
+#[cfg_attr(test, derive(Deserialize))]
+struct TomlPackage {
+    path: String,
+    short_name: String,
+    size_bytes: usize,
+    level: usize,
+    dependencies: Vec<usize>,
+    used_by: Vec<usize>,
+}
+
+#[derive(Serialize)]
+#[cfg_attr(test, derive(Deserialize))]
+struct TomlOutput {
+    metadata: TomlMetadata,
+    packages: Vec<TomlPackage>,
+}
+
+/// Rewrites every string with non-ASCII characters in `document` as a basic string with `toml_escape`.
+/// `toml` picks literal strings for values with quotes or backslashes, and those can't have escapes at all.
+fn toml_ascii_only(document: &str) -> Result<String, toml_edit::TomlError> {
+    struct AsciiStrings;
+
+    impl toml_edit::visit_mut::VisitMut for AsciiStrings {
+        fn visit_string_mut(&mut self, node: &mut toml_edit::Formatted<String>) {
+            if node.value().is_ascii() {
+                return;
+            }
+            // Parsing the escaped string is the only way to choose how toml_edit writes a value.
+            if let Ok(toml_edit::Value::String(escaped)) =
+                format!("\"{}\"", toml_escape(node.value(), true)).parse()
+            {
+                *node = escaped;
+            }
+        }
+    }
+
+    let mut document: toml_edit::DocumentMut = document.parse()?;
+    toml_edit::visit_mut::VisitMut::visit_document_mut(&mut AsciiStrings, &mut document);
+    Ok(document.to_string())
+}
+
+/// Generates a TOML file with a `[metadata]` table followed by one `[[packages]]` table per package, in the same order as the arena.
+fn generate_toml_file(tree: &PackageTree, file_path: &Path) -> GenericResult<()> {
+    let output = TomlOutput {
+        metadata: TomlMetadata {
+            total_packages: tree.nodes.len(),
+            total_bytes: tree.sum_package_bytes(),
+            max_level: tree.by_level.len().saturating_sub(1),
+        },
+        packages: tree
+            .nodes
+            .iter()
+            .map(|pkg| TomlPackage {
+                path: pkg.path.nfc().collect(),
+                short_name: pkg.short_name.nfc().collect(),
+                size_bytes: pkg.size_bytes,
+                level: pkg.level,
+                dependencies: pkg.dependencies.clone(),
+                used_by: pkg.used_by.clone(),
+            })
+            .collect(),
+    };
+
+    let mut document = toml::to_string(&output)?;
+    if ascii_only_output() {
+        document = toml_ascii_only(&document)?;
+    }
+
+    let mut file = open_output_file(file_path)?;
+    file.write_all(document.as_bytes())?;
+    file.flush()?;
+
+    Ok(())
+}
+
+/// Files bundled into a zip archive with `--zip-output`, along with a README.txt describing the analysis.
+struct ZipOutput {
+    files: Vec<(String, Vec<u8>)>,
+}
+
+impl ZipOutput {
+    fn new(tree: &PackageTree) -> Self {
+        let nix_version = Command::new("nix-store")
+            .arg("--version")
+            .output()
+            .ok()
+            .and_then(|output| String::from_utf8(output.stdout).ok())
+            .map(|version| version.trim().to_string())
+            .unwrap_or_else(|| "unknown".to_string());
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+
+        let readme = format!(
+            "Generated by nix-tree-sizes.\n\nnix version: {}\nTimestamp: {}\nRoot path: {}\nTotal bytes: {}\n",
+            nix_version,
+            unix_timestamp_to_iso8601(timestamp),
+            tree.package(0).path,
+            tree.sum_package_bytes()
+        );
+
+        Self {
+            files: vec![("README.txt".to_string(), readme.into_bytes())],
+        }
+    }
+
+    /// Adds a file named `<stem>.<extension>`, or `<stem>-<n>.<extension>` if a file with that name was already added (e.g. two dot outputs).
+    fn add(&mut self, stem: &str, extension: &str, contents: Vec<u8>) {
+        let mut name = format!("{}.{}", stem, extension);
+        let mut copy = 1;
+        while self.files.iter().any(|(existing, _)| *existing == name) {
+            copy += 1;
+            name = format!("{}-{}.{}", stem, copy, extension);
+        }
+
+        self.files.push((name, contents));
+    }
+
+    /// The whole archive, with every file compressed with deflate.
+    fn to_bytes(&self) -> zip::result::ZipResult<Vec<u8>> {
+        let mut archive = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+
+        for (name, contents) in self.files.iter() {
+            let options = zip::write::SimpleFileOptions::default()
+                .compression_method(zip::CompressionMethod::Deflated)
+                // Files of 4 GiB or more need the ZIP64 extensions.
+                .large_file(contents.len() as u64 >= u32::MAX as u64);
+            archive.start_file(name.as_str(), options)?;
+            archive.write_all(contents)?;
+        }
+
+        Ok(archive.finish()?.into_inner())
+    }
+
+    fn write(&self, file_path: &Path) -> GenericResult<()> {
+        // The archive is built in memory because the zip writer needs to seek, and named pipes can't.
+        let archive = self.to_bytes()?;
+
+        let mut file = open_output_file(file_path)?;
+        file.write_all(&archive)?;
+        file.flush()?;
+
+        Ok(())
+    }
+}
+
+/// Generates a SARIF 2.1.0 file, so CI systems can annotate pull requests with packages that are too large or have too many dependencies.
+fn generate_sarif_file(
+    tree: &PackageTree,
+    file_path: &Path,
+    size_threshold: usize,
+    fanout_threshold: usize,
+    json_indent: Option<usize>,
+) -> std::io::Result<()> {
+    let sarif_result = |rule_id: &str, message: String, pkg: &Package| {
+        format!(
+            "        {{\"ruleId\": \"{}\", \"level\": \"warning\", \"message\": {{\"text\": \"{}\"}}, \"locations\": [{{\"physicalLocation\": {{\"artifactLocation\": {{\"uri\": \"file://{}\"}}}}}}]}}",
+            rule_id,
+            json_escape(&message),
+            json_escape(&pkg.path)
+        )
+    };
+
+    let mut results: Vec<String> = Vec::new();
+    for pkg in tree.nodes.iter() {
+        if pkg.size_bytes > size_threshold {
+            results.push(sarif_result(
+                "NIX001",
+                format!(
+                    "Package {} is {} bytes, exceeding threshold",
+                    pkg.short_name, pkg.size_bytes
+                ),
+                pkg,
+            ));
+        }
+
+        if pkg.dependencies.len() >= fanout_threshold {
+            results.push(sarif_result(
+                "NIX002",
+                format!(
+                    "Package {} has {} direct dependencies, exceeding threshold",
+                    pkg.short_name,
+                    pkg.dependencies.len()
+                ),
+                pkg,
+            ));
+        }
+    }
+
+    let sarif = format!(
+        r#"{{
+  "$schema": "https://json.schemastore.org/sarif-2.1.0.json",
+  "version": "2.1.0",
+  "runs": [
+    {{
+      "tool": {{
+        "driver": {{
+          "name": "nix-tree-sizes",
+          "version": "{}",
+          "rules": [
+            {{"id": "NIX001", "name": "PackageSizeThreshold", "shortDescription": {{"text": "Package is larger than {} bytes"}}}},
+            {{"id": "NIX002", "name": "PackageFanoutThreshold", "shortDescription": {{"text": "Package has at least {} direct dependencies"}}}}
+          ]
+        }}
+      }},
+      "results": [
+{}
+      ]
+    }}
+  ]
+}}
+"#,
+        env!("CARGO_PKG_VERSION"),
+        size_threshold,
+        fanout_threshold,
+        results.join(",\n")
+    );
+
+    let mut file = open_output_file(file_path)?;
+    file.write_all(reformat_json(&sarif, json_indent).as_bytes())?;
+    file.flush()?;
+
+    Ok(())
+}
+
+/// Writes the subtree rooted at `pos` in the Newick format, with branch lengths being the package sizes in KB.
+/// Newick can only represent trees, so packages that were already written somewhere else (in `visited`) show up again as a leaf with a `(*)` suffix.
+fn to_newick(tree: &PackageTree, pos: usize, visited: &mut HashSet<usize>) -> String {
+    let pkg = tree.package(pos);
+    let branch_length = pkg.size_bytes as f64 / 1000.0;
+
+    if !visited.insert(pos) {
+        return format!(
+            "{}:{}",
+            newick_label(&format!("{} (*)", pkg.short_name)),
+            branch_length
+        );
+    }
+
+    let children: Vec<String> = pkg
+        .dependencies
+        .iter()
+        .map(|&dep| to_newick(tree, dep, visited))
+        .collect();
+    let label = newick_label(&pkg.short_name);
+
+    if children.is_empty() {
+        format!("{}:{}", label, branch_length)
+    } else {
+        format!("({}){}:{}", children.join(","), label, branch_length)
+    }
+}
+
+/// Quotes a Newick label if it has any character that has a meaning in the format.
+fn newick_label(name: &str) -> String {
+    let name = sanitize_for_encoding(name, ascii_only_output());
+    if name.contains(|c: char| c.is_whitespace() || "()[]':;,".contains(c)) {
+        format!("'{}'", name.replace('\'', "''"))
+    } else {
+        name.to_string()
+    }
+}
+
+fn generate_newick_file(tree: &PackageTree, file_path: &Path) -> std::io::Result<()> {
+    let mut file = open_output_file(file_path)?;
+    file.write_all(format!("{};\n", to_newick(tree, 0, &mut HashSet::new())).as_bytes())?;
+    file.flush()?;
+
+    Ok(())
+}
+
+/// Builds the `{ nodes, edges }` graph used by Sigma.js, with the same level organisation used for the dot file as the initial positions.
+fn sigma_json(tree: &PackageTree) -> String {
+    let colors = size_quartile_colors(tree);
+    let mut nodes: Vec<String> = Vec::new();
+    let mut edges: Vec<String> = Vec::new();
+
+    for (level, positions) in tree.by_level.iter().enumerate() {
+        for (index_in_level, &pos) in positions.iter().enumerate() {
+            let pkg = tree.package(pos);
+            nodes.push(format!(
+                "{{\"id\": \"{}\", \"label\": \"{}\", \"x\": {}, \"y\": {}, \"size\": {:.3}, \"color\": \"{}\"}}",
+                pos,
+                json_escape(&pkg.short_name),
+                index_in_level * 80,
+                level * 100,
+                pkg.graph_size,
+                colors[pos]
+            ));
+
+            for &dep in pkg.dependencies.iter() {
+                edges.push(format!(
+                    "{{\"id\": \"{}-{}\", \"source\": \"{}\", \"target\": \"{}\"}}",
+                    pos, dep, pos, dep
+                ));
+            }
+        }
+    }
+
+    format!(
+        "{{\"nodes\": [\n{}\n], \"edges\": [\n{}\n]}}",
+        nodes.join(",\n"),
+        edges.join(",\n")
+    )
+}
+
+/// Generates an html file rendering the closure with Sigma.js (loaded from a CDN), which uses WebGL and stays responsive for closures with thousands of packages.
+/// Nodes can be dragged, and hovering over a node highlights it along with its direct dependencies and dependents.
+fn generate_sigma_file(tree: &PackageTree, file_path: &Path) -> std::io::Result<()> {
+    let mut file = open_output_file(file_path)?;
+    file.write_all(
+        format!(
+            r##"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<script src="https://cdnjs.cloudflare.com/ajax/libs/graphology/0.25.4/graphology.umd.min.js"></script>
+<script src="https://cdnjs.cloudflare.com/ajax/libs/sigma.js/2.4.0/sigma.min.js"></script>
+<style>
+html, body, #graph {{ width: 100%; height: 100%; margin: 0; }}
+</style>
+</head>
+<body>
+<div id="graph"></div>
+<script>
+const data = {};
+const graph = new graphology.Graph();
+data.nodes.forEach(node => graph.addNode(node.id, {{ ...node, size: node.size * 8 }}));
+data.edges.forEach(edge => graph.addEdgeWithKey(edge.id, edge.source, edge.target, {{ type: "arrow" }}));
+
+const renderer = new Sigma(graph, document.getElementById("graph"));
+
+let hoveredNode = null;
+renderer.on("enterNode", ({{ node }}) => {{ hoveredNode = node; renderer.refresh(); }});
+renderer.on("leaveNode", () => {{ hoveredNode = null; renderer.refresh(); }});
+renderer.setSetting("nodeReducer", (node, attributes) => {{
+  if (hoveredNode && node !== hoveredNode && !graph.areNeighbors(node, hoveredNode)) {{
+    return {{ ...attributes, color: "#eee", label: "" }};
+  }}
+  return attributes;
+}});
+renderer.setSetting("edgeReducer", (edge, attributes) => {{
+  if (hoveredNode && !graph.hasExtremity(edge, hoveredNode)) {{
+    return {{ ...attributes, hidden: true }};
+  }}
+  return attributes;
+}});
+
+let draggedNode = null;
+renderer.on("downNode", ({{ node }}) => {{
+  draggedNode = node;
+  renderer.getCamera().disable();
+}});
+renderer.getMouseCaptor().on("mousemovebody", event => {{
+  if (!draggedNode) return;
+  const position = renderer.viewportToGraph(event);
+  graph.mergeNodeAttributes(draggedNode, {{ x: position.x, y: position.y }});
+  event.preventSigmaDefault();
+  event.original.preventDefault();
+  event.original.stopPropagation();
+}});
+renderer.getMouseCaptor().on("mouseup", () => {{
+  draggedNode = null;
+  renderer.getCamera().enable();
+}});
+</script>
+</body>
+</html>
+"##,
+            sigma_json(tree)
+        )
+        .as_bytes(),
+    )?;
+    file.flush()?;
+
+    Ok(())
+}
+
+/// Writes a ctags file with one tag per package, so editors can jump to a package's store path by name (e.g. `vim -t openssl`).
+/// Tags are sorted by name, as required by the format.
+fn generate_tags_file(tree: &PackageTree, file_path: &Path) -> std::io::Result<()> {
+    let mut tags: Vec<_> = tree
+        .nodes
+        .iter()
+        .map(|pkg| format!("{}\t{}\t1\n", pkg.short_name, pkg.path))
+        .collect();
+    tags.sort_unstable();
+
+    let mut file = open_output_file(file_path)?;
+    file.write_all(tags.concat().as_bytes())?;
+    file.flush()?;
+
+    Ok(())
+}
+
+/// Escapes a string to be used inside a double-quoted nix string, where `${` would otherwise start an interpolation.
+fn nix_string_escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+        .replace("${", "\\${")
+}
+
+/// Nix attribute name for `name`, quoted unless it's a plain identifier (names with hyphens or dots always need quotes).
+fn nix_attr_name(name: &str) -> String {
+    const KEYWORDS: [&str; 10] = [
+        "if", "then", "else", "assert", "with", "let", "in", "rec", "inherit", "or",
+    ];
+
+    let is_identifier = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if is_identifier && !KEYWORDS.contains(&name) {
+        name.to_string()
+    } else {
+        format!("\"{}\"", nix_string_escape(name))
+    }
+}
+
+/// Writes the closure as a nix attribute set keyed by package name, e.g. `{ "openssl-3.0.1" = { path = "/nix/store/..."; size = 12345; dependencies = [ "glibc-2.38" ]; }; }`,
+/// so it can be imported from other nix expressions. Packages sharing a name get the hash appended to keep attribute names unique.
+/// The file is checked with `nix-instantiate --parse` when it's available.
+fn generate_nix_expr(tree: &PackageTree, file_path: &Path) -> GenericResult<()> {
+    let mut name_counts: HashMap<&str, usize> = HashMap::new();
+    for pkg in tree.nodes.iter() {
+        *name_counts.entry(&pkg.short_name).or_default() += 1;
+    }
+    let attr_names: Vec<String> = tree
+        .nodes
+        .iter()
+        .map(|pkg| {
+            if name_counts[pkg.short_name.as_str()] > 1 {
+                format!("{}-{}", pkg.short_name, pkg.hash())
+            } else {
+                pkg.short_name.clone()
+            }
+        })
+        .collect();
+
+    let mut expr = String::from("{\n");
+    for positions in tree.by_level.iter() {
+        for &pos in positions {
+            let pkg = tree.package(pos);
+            let dependencies: String = pkg
+                .dependencies
+                .iter()
+                .map(|&dep| format!("\"{}\" ", nix_string_escape(&attr_names[dep])))
+                .collect();
+            expr.push_str(&format!(
+                "  {} = {{\n    path = \"{}\";\n    size = {};\n    dependencies = [ {}];\n  }};\n",
+                nix_attr_name(&attr_names[pos]),
+                nix_string_escape(&pkg.path),
+                pkg.size_bytes,
+                dependencies
+            ));
+        }
+    }
+    expr.push_str("}\n");
+
+    let mut file = open_output_file(file_path)?;
+    file.write_all(expr.as_bytes())?;
+    file.flush()?;
+
+    if is_on_path("nix-instantiate") {
+        let output = Command::new("nix-instantiate")
+            .arg("--parse")
+            .arg(file_path)
+            .output()?;
+        if !output.status.success() {
+            return Err(format!(
+                "the generated nix expression doesn't parse: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            )
+            .into());
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes a Markdown report meant for humans (e.g. in PR descriptions), with GitHub-flavored tables for the largest and most used packages,
+/// along with the duplicate packages and the critical path.
+fn generate_markdown_report(tree: &PackageTree, file_path: &Path) -> std::io::Result<()> {
+    let summary = tree.summary();
+    let today = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| unix_timestamp_to_iso8601(now.as_secs())[..10].to_string())
+        .unwrap_or_default();
+
+    let mut report = format!(
+        "# Closure of {} ({})\n\n## Summary\n\n| Metric | Value |\n| --- | --- |\n| Total packages | {} |\n| Total size | {} |\n| Max depth | {} |\n",
+        tree.package(0).short_name,
+        today,
+        summary.total_packages,
+        format_size_human(summary.total_bytes),
+        summary.max_level
+    );
+
+    let mut by_size: Vec<&Package> = tree.nodes.iter().collect();
+    by_size.sort();
+    report.push_str("\n## Top 10 Packages by Size\n\n| Package | Size |\n| --- | --- |\n");
+    for pkg in by_size.into_iter().take(10) {
+        report.push_str(&format!(
+            "| {} | {} |\n",
+            pkg.short_name,
+            format_size_human(pkg.size_bytes)
+        ));
+    }
+
+    let mut by_references: Vec<&Package> = tree.nodes.iter().collect();
+    by_references.sort_by_key(|pkg| std::cmp::Reverse(pkg.used_by.len()));
+    report
+        .push_str("\n## Top 10 Most Referenced Packages\n\n| Package | Used by |\n| --- | --- |\n");
+    for pkg in by_references.into_iter().take(10) {
+        report.push_str(&format!("| {} | {} |\n", pkg.short_name, pkg.used_by.len()));
+    }
+
+    report.push_str("\n## Duplicate Packages\n\n");
+    let duplicates = tree.find_duplicates();
+    if duplicates.is_empty() {
+        report.push_str("No package has more than one version.\n");
+    }
+    for (name, positions) in duplicates {
+        let versions: Vec<&str> = positions
+            .iter()
+            .map(|&pos| tree.package(pos).short_name.as_str())
+            .collect();
+        report.push_str(&format!("- **{}**: {}\n", name, versions.join(", ")));
+    }
+
+    let critical_path = tree.critical_path();
+    let critical_path_bytes: usize = critical_path
+        .iter()
+        .map(|&pos| tree.package(pos).size_bytes)
+        .sum();
+    report.push_str(&format!(
+        "\n## Critical Path\n\nThe heaviest dependency chain adds up to {}:\n\n",
+        format_size_human(critical_path_bytes)
+    ));
+    for (index, &pos) in critical_path.iter().enumerate() {
+        let pkg = tree.package(pos);
+        report.push_str(&format!(
+            "{}. {} ({})\n",
+            index + 1,
+            pkg.short_name,
+            format_size_human(pkg.size_bytes)
+        ));
+    }
+
+    let mut file = open_output_file(file_path)?;
+    file.write_all(report.as_bytes())?;
+    file.flush()?;
+
+    Ok(())
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum OutputFormat {
+    Dot,
+    Csv,
+    Cytoscape,
+    Toml,
+    Sarif,
+    Newick,
+    Tags,
+    D3Tree,
+    Markdown,
+    Sigma,
+    NixExpr,
+}
+
+impl OutputFormat {
+    /// Every file generated for this format when asked to write it to `path`, along with the extension to give it in a zip archive.
+    fn generated_files(&self, path: &Path) -> Vec<(&'static str, PathBuf)> {
+        match self {
+            OutputFormat::Dot => vec![("dot", path.to_path_buf())],
+            OutputFormat::Csv => vec![("csv", path.to_path_buf())],
+            OutputFormat::Cytoscape => {
+                let mut html_path = path.to_path_buf().into_os_string();
+                html_path.push(".html");
+                vec![
+                    ("json", path.to_path_buf()),
+                    ("html", PathBuf::from(html_path)),
+                ]
+            }
+            OutputFormat::Toml => vec![("toml", path.to_path_buf())],
+            OutputFormat::Sarif => vec![("sarif", path.to_path_buf())],
+            OutputFormat::Newick => vec![("nwk", path.to_path_buf())],
+            OutputFormat::Tags => vec![("tags", path.to_path_buf())],
+            OutputFormat::D3Tree => vec![("html", path.to_path_buf())],
+            OutputFormat::Markdown => vec![("md", path.to_path_buf())],
+            OutputFormat::Sigma => vec![("html", path.to_path_buf())],
+            OutputFormat::NixExpr => vec![("nix", path.to_path_buf())],
+        }
+    }
+}
+
+/// An output file requested with `--output <FORMAT>:<PATH>`.
+#[derive(Clone, Debug)]
+struct OutputSpec {
+    format: OutputFormat,
+    path: PathBuf,
+}
+
+fn parse_output_spec(value: &str) -> Result<OutputSpec, String> {
+    let (format, path) = value
+        .split_once(':')
+        .ok_or_else(|| format!("expected <FORMAT>:<PATH>, got '{}'", value))?;
+
+    if path.is_empty() {
+        return Err(format!("missing the path for the '{}' output", format));
+    }
+
+    let format = OutputFormat::from_str(format, true).map_err(|_| {
+        format!(
+            "unknown output format '{}', expected one of: {}",
+            format,
+            OutputFormat::value_variants()
+                .iter()
+                .filter_map(|variant| variant.to_possible_value())
+                .map(|value| value.get_name().to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        )
+    })?;
+
+    Ok(OutputSpec {
+        format,
+        path: PathBuf::from(path),
+    })
+}
+
+/// Finds the store path containing the running executable, e.g. `/nix/store/<hash>-nix-tree-sizes-0.1.0` for `/nix/store/<hash>-nix-tree-sizes-0.1.0/bin/nix-tree-sizes`.
+fn get_own_store_path() -> GenericResult<PathBuf> {
+    let exe_path = std::fs::canonicalize(std::env::current_exe()?)?;
+
+    let relative_path = exe_path.strip_prefix(store_prefix()).map_err(|_| {
+        format!(
+            "This executable ({}) isn't in the nix store, so it can't analyze itself",
+            exe_path.display()
+        )
+    })?;
+    let store_object = relative_path
+        .components()
+        .next()
+        .ok_or("Couldn't find the store path of this executable")?;
+
+    Ok(PathBuf::from(store_prefix()).join(store_object))
+}
+
+/// Finds every nix profile on this system: the profiles in `/nix/var/nix/profiles` (and its `per-user` directories), `~/.nix-profile` and `/run/current-system`.
+/// Returns `(profile name, store path)` pairs, skipping profiles pointing to a store path already found (e.g. `~/.nix-profile` usually points to a profile in `per-user`).
+fn find_all_profiles() -> Vec<(String, PathBuf)> {
+    let profiles_dir = Path::new("/nix/var/nix/profiles");
+    let mut profile_dirs = vec![(String::new(), profiles_dir.to_path_buf())];
+    if let Ok(entries) = std::fs::read_dir(profiles_dir.join("per-user")) {
+        for entry in entries.flatten() {
+            profile_dirs.push((
+                format!("per-user/{}/", entry.file_name().to_string_lossy()),
+                entry.path(),
+            ));
+        }
+    }
+
+    let mut candidates: Vec<(String, PathBuf)> = Vec::new();
+    for (name_prefix, dir) in profile_dirs {
+        let Ok(entries) = std::fs::read_dir(&dir) else {
+            continue;
+        };
+
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().to_string();
+            // Generations of a profile are `<profile>-<N>-link`, and only the current one is analyzed.
+            if entry.path().is_symlink() && !name.ends_with("-link") {
+                candidates.push((format!("{}{}", name_prefix, name), entry.path()));
+            }
+        }
+    }
+    candidates.sort();
+    if let Some(home) = std::env::var_os("HOME") {
+        candidates.push((
+            "~/.nix-profile".to_string(),
+            Path::new(&home).join(".nix-profile"),
+        ));
+    }
+    candidates.push((
+        "/run/current-system".to_string(),
+        PathBuf::from("/run/current-system"),
+    ));
+
+    let mut seen_store_paths = HashSet::new();
+    candidates
+        .into_iter()
+        .filter_map(|(name, path)| {
+            let store_path = std::fs::canonicalize(path).ok()?;
+            (store_path.starts_with(store_prefix()) && seen_store_paths.insert(store_path.clone()))
+                .then_some((name, store_path))
+        })
+        .collect()
+}
+
+/// Flags (and their short forms) that this tool already passes to nix-store.
+const NIX_STORE_FLAGS_SET: &[&str] = &["--query", "-q", "--size", "--tree", "--references"];
+
+/// How many times a failed nix-store invocation is retried, and how long to wait before the first retry.
+/// Set once from `NixArgs`, the wait doubles after every failed attempt.
+static NIX_STORE_RETRY_POLICY: OnceLock<(u32, Duration)> = OnceLock::new();
+
+/// Least recently used cache with a fixed capacity.
+/// Evicting is linear in the capacity, which is fine since it only happens when a closure has more packages than the capacity.
+struct LruCache<V> {
+    capacity: usize,
+    // Values along with when they were last used.
+    entries: HashMap<String, (V, u64)>,
+    clock: u64,
+}
+
+impl<V: Copy> LruCache<V> {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: HashMap::new(),
+            clock: 0,
+        }
+    }
+
+    fn get(&mut self, key: &str) -> Option<V> {
+        self.clock += 1;
+        let (value, last_used) = self.entries.get_mut(key)?;
+        *last_used = self.clock;
+        Some(*value)
+    }
+
+    fn put(&mut self, key: String, value: V) {
+        self.clock += 1;
+        if self.entries.len() >= self.capacity && !self.entries.contains_key(&key) {
+            let least_recently_used = self
+                .entries
+                .iter()
+                .min_by_key(|(_, (_, last_used))| *last_used)
+                .map(|(key, _)| key.clone());
+            if let Some(least_recently_used) = least_recently_used {
+                self.entries.remove(&least_recently_used);
+            }
+        }
+        self.entries.insert(key, (value, self.clock));
+    }
+}
+
+/// Sizes already queried from nix-store during this run, by store path. `None` when disabled with `--no-cache`.
+static NIX_STORE_SIZE_CACHE: OnceLock<Option<Mutex<LruCache<usize>>>> = OnceLock::new();
+
+fn size_cache() -> Option<&'static Mutex<LruCache<usize>>> {
+    NIX_STORE_SIZE_CACHE
+        .get_or_init(|| Some(Mutex::new(LruCache::new(10_000))))
+        .as_ref()
+}
+
+/// How long a single nix-store invocation can run before it's killed. Set once from `NixArgs`.
+static NIX_STORE_TIMEOUT: OnceLock<Duration> = OnceLock::new();
+
+/// Whether a size query that times out aborts the run instead of counting the package's size as 0. Set once from `NixArgs`.
+static NIX_STORE_STRICT: OnceLock<bool> = OnceLock::new();
+
+fn strict_nix_store() -> bool {
+    NIX_STORE_STRICT.get().copied().unwrap_or(false)
+}
+
+/// Store paths whose size query timed out, which were counted as 0 bytes.
+static TIMED_OUT_SIZE_QUERIES: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Error for a nix-store invocation killed after running for longer than `--nix-timeout-seconds`.
+struct NixStoreTimeout {
+    command: String,
+    timeout: Duration,
+}
+
+// `main` prints errors with `Debug`, so this shows the same message as `Display`.
+impl std::fmt::Debug for NixStoreTimeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "\"{}\"", self)
+    }
+}
+
+impl std::fmt::Display for NixStoreTimeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "'{}' didn't finish within {} seconds",
+            self.command,
+            self.timeout.as_secs()
+        )
+    }
+}
+
+impl std::error::Error for NixStoreTimeout {}
+
+/// Runs `command` like `Command::output`, but kills it and returns `None` if it runs for longer than `timeout`.
+fn output_with_timeout(
+    command: &mut Command,
+    timeout: Duration,
+) -> std::io::Result<Option<std::process::Output>> {
+    let mut child = command
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()?;
+
+    // The pipes are read in the background, otherwise a command with a lot of output would block on a full pipe while we wait for it.
+    let read_pipe = |pipe: Option<Box<dyn std::io::Read + Send>>| {
+        std::thread::spawn(move || {
+            let mut contents = Vec::new();
+            if let Some(mut pipe) = pipe {
+                let _ = pipe.read_to_end(&mut contents);
+            }
+            contents
+        })
+    };
+    let stdout = read_pipe(child.stdout.take().map(|pipe| Box::new(pipe) as _));
+    let stderr = read_pipe(child.stderr.take().map(|pipe| Box::new(pipe) as _));
+
+    // Most nix-store queries finish in a few milliseconds, so polling starts fast and slows down for longer ones.
+    let deadline = Instant::now() + timeout;
+    let mut poll_interval = Duration::from_millis(1);
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Ok(None);
+        }
+
+        std::thread::sleep(poll_interval);
+        poll_interval = (poll_interval * 2).min(Duration::from_millis(100));
+    };
+
+    Ok(Some(std::process::Output {
+        status,
+        stdout: stdout.join().unwrap_or_default(),
+        stderr: stderr.join().unwrap_or_default(),
+    }))
+}
+
+/// Runs nix-store with `args` followed by `nix_extra_args`, returning its stdout.
+/// Failed runs (IO errors or non-zero exit codes) are retried with exponential backoff, since nix-store can fail transiently with "database is locked" or connection errors.
+/// Runs that time out aren't retried, since they'd most likely hang again, and return a `NixStoreTimeout` error.
+fn run_nix_store_with_retry(
+    args: &[&str],
+    nix_binary: &str,
+    nix_extra_args: &[String],
+) -> GenericResult<Vec<u8>> {
+    let (max_retries, retry_delay) =
+        *NIX_STORE_RETRY_POLICY.get_or_init(|| (3, Duration::from_secs(1)));
+
+    let timeout = *NIX_STORE_TIMEOUT.get_or_init(|| Duration::from_secs(30));
+
+    let mut attempt = 0;
+    loop {
+        let error: Box<dyn std::error::Error> = match output_with_timeout(
+            Command::new(nix_binary).args(args).args(nix_extra_args),
+            timeout,
+        ) {
+            Ok(None) => {
+                return Err(NixStoreTimeout {
+                    command: format!("{} {}", nix_binary, args.join(" ")),
+                    timeout,
+                }
+                .into())
+            }
+            Ok(Some(output)) if output.status.success() => return Ok(output.stdout),
+            Ok(Some(output)) => format!(
+                "'{} {}' failed ({}): {}",
+                nix_binary,
+                args.join(" "),
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )
+            .into(),
+            Err(err) => format!("Couldn't run '{}': {}", nix_binary, err).into(),
+        };
+
+        if attempt >= max_retries {
+            return Err(error);
+        }
+
+        let delay = retry_delay * 2u32.saturating_pow(attempt);
+        attempt += 1;
+        tracing::warn!(
+            "{} (retrying in {} ms, attempt {} of {})",
+            error,
+            delay.as_millis(),
+            attempt,
+            max_retries
+        );
+        std::thread::sleep(delay);
+    }
+}
+
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None, subcommand_negates_reqs = true)]
+struct Args {
+    #[command(subcommand)]
+    command: Option<Subcommand>,
+
+    // Running without a subcommand is the same as running `analyze`.
+    #[command(flatten)]
+    analyze: AnalyzeArgs,
+
+    /// Prints everything to stdout as `KEY=VALUE` lines (with `KEY[INDEX]=VALUE` for lists), which is easier to use from shell scripts.
+    #[arg(long, global = true)]
+    machine_readable: bool,
+
+    /// Indents json output with 2 spaces. This is the default when printing json to a terminal, otherwise json is compact.
+    #[arg(long, global = true)]
+    json_pretty: bool,
+
+    /// Indents json output with this many spaces, or makes it compact with 0.
+    #[arg(long, global = true)]
+    json_indent: Option<usize>,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Subcommand {
+    /// Analyzes a closure, optionally generating output files. This is the default if no subcommand is given.
+    Analyze(Box<AnalyzeArgs>),
+
+    /// Shows the packages that are only in one of the two closures.
+    Compare {
+        first_store_path: PathBuf,
+        second_store_path: PathBuf,
+
+        #[command(flatten)]
+        nix: NixArgs,
+    },
+
+    /// Prints statistics about a closure without generating any files.
+    Stats {
+        store_path: PathBuf,
+
+        /// Prints the statistics as a json object.
+        #[arg(long)]
+        json: bool,
+
+        /// Also lists the packages that nothing at the root level depends on directly.
+        #[arg(long)]
+        include_indirect_only: bool,
+
+        #[command(flatten)]
+        nix: NixArgs,
+    },
+
+    /// Lists the packages in a closure whose store path contains the pattern.
+    Find {
+        store_path: PathBuf,
+        pattern: String,
+
+        #[command(flatten)]
+        nix: NixArgs,
+    },
+
+    /// Prints the shortest dependency path between two packages in a closure.
+    /// Packages can be named the same way as with `--focus`.
+    Path {
+        store_path: PathBuf,
+        from: String,
+        to: String,
+
+        #[command(flatten)]
+        nix: NixArgs,
+    },
+
+    /// Prints up to 10 different dependency paths from the root of a closure to a package, showing why it's in the closure.
+    /// The package can be named the same way as with `--focus`.
+    Why {
+        store_path: PathBuf,
+        package: String,
+
+        #[command(flatten)]
+        nix: NixArgs,
+    },
+
+    /// Lists the largest packages in a closure.
+    Largest {
+        store_path: PathBuf,
+
+        /// How many packages to list.
+        #[arg(short = 'n', long, default_value_t = 10)]
+        count: usize,
+
+        #[command(flatten)]
+        nix: NixArgs,
+    },
+}
+
+#[derive(clap::Args, Clone, Debug)]
+struct NixArgs {
+    /// Extra arguments appended to every nix-store invocation, e.g. `--nix-extra-args "--store ssh://machine"`.
+    /// Each value is split like a shell would, so quotes keep spaces and empty values, e.g. `--nix-extra-args '--option substituters ""'`.
+    /// The flag can be given multiple times.
+    #[arg(long, allow_hyphen_values = true)]
+    nix_extra_args: Vec<String>,
+
+    /// How many times a failed nix-store invocation is retried before giving up.
+    #[arg(long, default_value_t = 3)]
+    max_retries: u32,
+
+    /// How long to wait before retrying a failed nix-store invocation, doubled after every retry.
+    #[arg(long, default_value_t = 1000)]
+    retry_delay_ms: u64,
+
+    /// Kills nix-store invocations that run for longer than this.
+    /// Packages whose size query times out are counted as 0 bytes with a warning, unless `--strict` is given.
+    #[arg(long, default_value_t = 30)]
+    nix_timeout_seconds: u64,
+
+    /// Aborts the run when a size query times out, instead of counting the package as 0 bytes.
+    #[arg(long)]
+    strict: bool,
+
+    /// Queries nix-store again for every package, instead of reusing sizes already queried during this run.
+    #[arg(long)]
+    no_cache: bool,
+
+    /// Directory of the nix store, for stores that aren't at `/nix/store` (e.g. when using `--store /tmp/nix-store`).
+    #[arg(
+        long,
+        env = "NIX_INVESTIGATION_STORE_PREFIX",
+        default_value = "/nix/store"
+    )]
+    store_prefix: String,
+}
+
+impl AnalyzeArgs {
+    /// Outputs requested with the per-format flags (e.g. `--dot-file-path`) instead of `--output`.
+    fn legacy_outputs(&self) -> Vec<OutputSpec> {
+        let legacy_outputs = [
+            (OutputFormat::Dot, &self.dot_file_path),
+            (OutputFormat::Csv, &self.csv_file_path),
+            (OutputFormat::Cytoscape, &self.cytoscape_output),
+            (OutputFormat::Toml, &self.toml_output),
+            (OutputFormat::Sarif, &self.sarif_output),
+            (OutputFormat::Newick, &self.newick_output),
+            (OutputFormat::Tags, &self.tags_output),
+            (OutputFormat::D3Tree, &self.d3_tree_output),
+            (OutputFormat::Markdown, &self.report_markdown),
+            (OutputFormat::Sigma, &self.sigma_output),
+            (OutputFormat::NixExpr, &self.nix_expr_output),
+        ];
+
+        legacy_outputs
+            .into_iter()
+            .filter_map(|(format, path)| {
+                Some(OutputSpec {
+                    format,
+                    path: path.clone()?,
+                })
+            })
+            .collect()
+    }
+}
+
+impl NixArgs {
+    /// Sets the retry policy and timeout used by every nix-store invocation, the store prefix and whether sizes are cached.
+    /// These are global, so this must be called once before running any command.
+    fn init_globals(&self) {
+        let _ = NIX_STORE_RETRY_POLICY
+            .set((self.max_retries, Duration::from_millis(self.retry_delay_ms)));
+        let _ = NIX_STORE_TIMEOUT.set(Duration::from_secs(self.nix_timeout_seconds));
+        let _ = NIX_STORE_STRICT.set(self.strict);
+        let _ = STORE_PREFIX.set(self.store_prefix.trim_end_matches('/').to_string());
+        if self.no_cache {
+            let _ = NIX_STORE_SIZE_CACHE.set(None);
+        }
+    }
+
+    /// Splits the extra arguments with shell quoting rules, warning about any flag this tool already sets.
+    fn split_extra_args(&self) -> GenericResult<Vec<String>> {
+        let mut nix_extra_args = Vec::new();
+        for value in self.nix_extra_args.iter() {
+            nix_extra_args.extend(
+                shlex::split(value)
+                    .ok_or_else(|| format!("Unbalanced quotes in --nix-extra-args '{}'", value))?,
+            );
+        }
+
+        for arg in nix_extra_args.iter() {
+            if NIX_STORE_FLAGS_SET.contains(&arg.as_str()) {
+                tracing::warn!(
+                    "'{}' in --nix-extra-args is already set by this tool and may break the nix-store invocations",
+                    arg
+                );
+            }
+        }
+
+        Ok(nix_extra_args)
+    }
+}
+
+/// Environment variables that can be used instead of some flags. Flags given in the command line take precedence.
+const ENV_VARS: [&str; 4] = [
+    "NIX_INVESTIGATION_DOT_PATH",
+    "NIX_INVESTIGATION_CSV_PATH",
+    "NIX_INVESTIGATION_JSON_PATH",
+    "NIX_INVESTIGATION_STORE_PREFIX",
+];
+
+/// Every flag of `analyze` that generates a file, for the modes that only print a number.
+/// Flags that can be set with an environment variable aren't here, since clap counts those as given even when they only come from the environment.
+/// These modes ignore them instead.
+const FILE_OUTPUT_ARGS: [&str; 10] = [
+    "output",
+    "zip_output",
+    "toml_output",
+    "sarif_output",
+    "newick_output",
+    "tags_output",
+    "d3_tree_output",
+    "report_markdown",
+    "sigma_output",
+    "nix_expr_output",
+];
+
+#[derive(clap::Args, Clone, Debug)]
+struct AnalyzeArgs {
+    #[arg(
+        required_unless_present_any = ["self_analyze", "from_graph", "from_flake_lock", "list_system_packages", "all_profiles"],
+        conflicts_with_all = ["self_analyze", "from_graph", "from_flake_lock", "all_profiles"]
+    )]
+    store_path: Option<PathBuf>,
+
+    /// Builds the tree from a dot file generated by `nix-store --query --graph` instead of running `nix-store --query --tree`.
+    /// Package sizes are still queried from nix-store.
+    #[arg(long, conflicts_with = "self_analyze")]
+    from_graph: Option<PathBuf>,
+
+    /// Builds the tree from the inputs of a flake in its `flake.lock` file instead of a store path, with every input depending on its own inputs.
+    /// Input sizes are 0 unless `--query-narsize` is given.
+    #[arg(long, conflicts_with_all = ["self_analyze", "from_graph", "from_requisites", "sample", "all_profiles"])]
+    from_flake_lock: Option<PathBuf>,
+
+    /// Fetches every input of `--from-flake-lock` with `nix flake prefetch` to query its size. This downloads inputs that aren't in the store yet.
+    #[arg(long, requires = "from_flake_lock")]
+    query_narsize: bool,
+
+    /// Only queries the size of about this many packages, sampled from every level, and estimates the size of the others.
+    /// Much faster for large closures, but every size in the outputs is then an estimate, except for the sampled packages.
+    #[arg(long, conflicts_with_all = ["from_graph", "from_requisites"])]
+    sample: Option<usize>,
+
+    /// Builds the tree from `nix-store --query --requisites` and the references of every package instead of parsing `nix-store --query --tree`.
+    /// This runs one extra nix-store process per package.
+    #[arg(long, conflicts_with = "from_graph")]
+    from_requisites: bool,
+
+    /// Analyzes the closure of this tool's own store path instead of `STORE_PATH`.
+    #[arg(long)]
+    self_analyze: bool,
+
+    /// Analyzes every nix profile on this system (in `/nix/var/nix/profiles`, `~/.nix-profile` and `/run/current-system`) instead of `STORE_PATH`,
+    /// reporting which packages are shared between them and which are unique to one. The report is written to `--report-markdown` if given.
+    #[arg(long, conflicts_with_all = ["self_analyze", "from_graph", "from_requisites", "sample"])]
+    all_profiles: bool,
+
+    /// Prints how long every phase of the analysis took to stderr, slowest first.
+    /// With `RUST_LOG=info` or higher, also prints the packages whose size took the longest to query.
+    #[arg(long)]
+    benchmark: bool,
+
+    /// Replaces common base packages (glibc, bash, coreutils and others, see `--list-system-packages`) with a single `(system)` package with their combined size.
+    /// Packages only used by them are included in `(system)` as well.
+    #[arg(long)]
+    ignore_system_packages: bool,
+
+    /// Prints the packages ignored by `--ignore-system-packages` by default, and exits.
+    #[arg(long)]
+    list_system_packages: bool,
+
+    /// Also ignores this package with `--ignore-system-packages`, given by name without the version (e.g. `openssl`). Can be specified multiple times.
+    #[arg(long, requires = "ignore_system_packages")]
+    system_package: Vec<String>,
+
+    /// Prints every environment variable recognised in place of a flag, and its current value, to stderr.
+    #[arg(long)]
+    print_env: bool,
+
+    /// Only prints the number of packages in the closure and exits, without querying any package size.
+    #[arg(
+        long,
+        conflicts_with_all = FILE_OUTPUT_ARGS,
+        conflicts_with_all = ["from_graph", "from_requisites", "sample", "byte_count_only"]
+    )]
+    count_only: bool,
+
+    /// Checks the tree for internal inconsistencies (cycles, mismatched edges, packages unreachable from the root) without generating any output,
+    /// exiting with an error if any is found. With `--validate-references`, also compares the tree size with `nix-store --query --requisites`.
+    #[arg(long, conflicts_with_all = FILE_OUTPUT_ARGS)]
+    check: bool,
+
+    /// Only prints the total bytes of the closure and exits.
+    #[arg(long, conflicts_with_all = FILE_OUTPUT_ARGS, conflicts_with = "sample")]
+    byte_count_only: bool,
+
+    /// Generates an output file, given as `<FORMAT>:<PATH>` (e.g. `dot:/tmp/graph.dot`).
+    /// Can be specified multiple times. Formats: dot, csv, cytoscape, toml, sarif, newick, tags, d3-tree, markdown, sigma, nix-expr.
+    /// The cytoscape format also generates a companion html file rendering the graph at `<PATH>.html`.
+    #[arg(long, value_parser = parse_output_spec)]
+    output: Vec<OutputSpec>,
+
+    /// Encoding of strings in output files. With `ascii`, non-ASCII characters in names, labels and paths are replaced with `\uXXXX` escapes,
+    /// for tools that can't handle UTF-8.
+    #[arg(long, value_enum, default_value_t)]
+    output_encoding: OutputEncoding,
+
+    /// Also bundles every generated output file into a zip archive at this path, named after the root package, along with a README.txt describing the analysis.
+    #[arg(long)]
+    zip_output: Option<PathBuf>,
+
+    /// Path to the SARIF file to generate, same as `--output sarif:<PATH>`.
+    /// Packages larger than `--warn-size` or with more direct dependencies than `--warn-fanout` are reported as results.
+    #[arg(long)]
+    sarif_output: Option<PathBuf>,
+
+    /// Path to the Newick file to generate, same as `--output newick:<PATH>`.
+    #[arg(long)]
+    newick_output: Option<PathBuf>,
+
+    /// Path to the ctags file to generate, same as `--output tags:<PATH>`.
+    /// Each package is a tag pointing to its store path, so `vim -t <NAME>` opens it.
+    #[arg(long)]
+    tags_output: Option<PathBuf>,
+
+    /// Path to the html file to generate with a D3.js collapsible tree of the closure, same as `--output d3-tree:<PATH>`.
+    #[arg(long)]
+    d3_tree_output: Option<PathBuf>,
+
+    /// Path to the Markdown report to generate, same as `--output markdown:<PATH>`.
+    /// The report has the largest and most used packages, the duplicate packages and the critical path, for use in wikis or PR descriptions.
+    #[arg(long)]
+    report_markdown: Option<PathBuf>,
+
+    /// Path to the html file to generate with a Sigma.js rendering of the closure, same as `--output sigma:<PATH>`.
+    /// Sigma.js uses WebGL, so this is the best interactive format for closures with thousands of packages.
+    #[arg(long)]
+    sigma_output: Option<PathBuf>,
+
+    /// Path to the nix file to generate with the closure as an attribute set of packages, same as `--output nix-expr:<PATH>`.
+    #[arg(long)]
+    nix_expr_output: Option<PathBuf>,
+
+    /// Deprecated, use `--output dot:<PATH>` instead.
+    /// Path to the graphviz dot file to generate.
+    #[arg(short, long, env = "NIX_INVESTIGATION_DOT_PATH")]
+    dot_file_path: Option<PathBuf>,
+
+    /// Deprecated, use `--output csv:<PATH>` instead.
+    /// Path to the csv file to generate.
+    #[arg(short, long, env = "NIX_INVESTIGATION_CSV_PATH")]
+    csv_file_path: Option<PathBuf>,
+
+    /// Deprecated, use `--output cytoscape:<PATH>` instead.
+    /// Path to the Cytoscape.js json file to generate.
+    #[arg(long, env = "NIX_INVESTIGATION_JSON_PATH")]
+    cytoscape_output: Option<PathBuf>,
+
+    /// Extra columns to add to the csv file, given as `<NAME>=<FORMULA>` and separated by commas, e.g. `size_mb=size_bytes/1048576`.
+    /// Formulas can use the fields size_bytes, level, max_level, fan_in and fan_out, numbers, parentheses, `+`, `-`, `*`, `/`,
+    /// and the comparisons `<`, `<=`, `>`, `>=`, `==` and `!=` (which result in 1 or 0).
+    #[arg(long, value_delimiter = ',', value_parser = parse_csv_extra_column)]
+    csv_extra_columns: Vec<CsvExtraColumn>,
+
+    /// Starts the csv file with a UTF-8 byte order mark, so Excel detects its encoding.
+    #[arg(long)]
+    csv_bom: bool,
+
+    /// Ends lines in the csv file with `\r\n` instead of `\n`, for spreadsheet tools on Windows.
+    #[arg(long)]
+    csv_crlf: bool,
+
+    /// Appends the rows to the csv file instead of overwriting it, with the time of the run in an extra column, to track a closure over time.
+    /// The header is only written when the file is empty or doesn't exist yet.
+    #[arg(long)]
+    csv_append: bool,
+
+    /// Name of the column with the time of the run added by `--csv-append`.
+    #[arg(long, default_value = "run_timestamp", requires = "csv_append")]
+    csv_timestamp_col: String,
+
+    /// Fills the `unique_closure_contribution_bytes` and `shared_pct` columns in the csv file for every package, instead of only for the direct dependencies of the root.
+    /// This needs a traversal of the whole closure per package, so it's slow for large closures.
+    #[arg(long)]
+    show_shared_pct: bool,
+
+    /// Packages larger than this many bytes are reported in the SARIF file.
+    #[arg(long, default_value_t = 100 * 1024 * 1024)]
+    warn_size: usize,
+
+    /// Prints a warning for every package with at least this many direct dependencies.
+    /// The same threshold is used for the `fanout_warning` column in the csv file.
+    #[arg(long, default_value_t = 50)]
+    warn_fanout: usize,
+
+    /// Prints a warning with the deepest packages and their dependency chains if the closure has at least this many levels.
+    /// Deep chains often come from unnecessary dependencies, 15 is a good starting point.
+    #[arg(long)]
+    warn_depth: Option<usize>,
+
+    /// Exits with code 3 (after generating every output) if the `--warn-depth` warning is printed.
+    #[arg(long, requires = "warn_depth")]
+    fail_on_warn: bool,
+
+    /// Checks every package's dependencies against `nix-store --query --references` and warns about any discrepancies.
+    /// This runs nix-store once for every package in the closure, so it can take a long time.
+    #[arg(long)]
+    validate_references: bool,
+
+    /// Checks the sizes and references of some random packages against the store, e.g. to make sure a graph read with `--from-graph` is still accurate.
+    #[arg(long)]
+    verify: bool,
+
+    /// How many packages `--verify` checks.
+    #[arg(long, default_value_t = 20, requires = "verify")]
+    verify_sample_size: usize,
+
+    /// Colours the nodes in the dot file according to their level, going from blue (the root) to red (the deepest level).
+    #[arg(long, conflicts_with = "color_by_age")]
+    color_by_level: bool,
+
+    /// Queries when every package was registered in the store, adding it to the `registered_at` column in the csv file.
+    /// This runs nix-store once more for every package in the closure.
+    #[arg(long)]
+    include_registration_time: bool,
+
+    /// Queries the download size of every package from the binary cache with `nix path-info`, adding it to the `download_size_bytes` and `compression_ratio` columns in the csv file.
+    #[arg(long)]
+    include_nar_size: bool,
+
+    /// Adds the derivation of every package and the inputs of those derivations to the tree, drawn as diamonds with dashed edges in the dot file.
+    /// Only derivations still in the store are found. This runs nix-store twice for every package in the closure, so it can take a long time.
+    #[arg(long)]
+    include_build_time_deps: bool,
+
+    /// Colours the nodes in the dot file according to their registration time, going from orange (the oldest) to green (the most recent).
+    #[arg(long, requires = "include_registration_time")]
+    color_by_age: bool,
+
+    /// Deprecated, use `--output toml:<PATH>` instead.
+    /// Path to the TOML file to generate.
+    #[arg(long)]
+    toml_output: Option<PathBuf>,
+
+    /// Makes the nodes in the dot file link to this URL when rendered to SVG.
+    /// `{path}` and `{name}` are replaced with the package's store path and short name, e.g. `https://search.nixos.org/packages?query={name}`.
+    #[arg(long)]
+    dot_node_url: Option<String>,
+
+    /// Shows the package's store path and size when hovering over nodes in the dot file rendered to SVG.
+    #[arg(long)]
+    dot_tooltip: bool,
+
+    /// Draws packages with the same name (but different store path hashes) together in a cluster in the dot file.
+    #[arg(long)]
+    group_by_name: bool,
+
+    /// Draws packages from the same nixpkgs package set (e.g. python, perl, nodejs) together in a cluster in the dot file.
+    /// The package set is inferred from the start of the package name, see `--package-set-prefixes`. Other packages go into an "other" cluster.
+    #[arg(long, conflicts_with = "group_by_name")]
+    dot_cluster_by_prefix: bool,
+
+    /// Name prefixes recognised as package sets by `--dot-cluster-by-prefix`, separated by commas.
+    #[arg(
+        long,
+        value_delimiter = ',',
+        default_value = "python,perl,ruby,nodejs,lua,ghc,ocaml,php,guile,tcl,R"
+    )]
+    package_set_prefixes: Vec<String>,
+
+    /// Tuning parameter: dot files for closures with fewer packages than this skip the per-level rank groupings (and the invisible edges between them).
+    /// Small graphs are usually laid out better by graphviz without these constraints.
+    #[arg(long, default_value_t = 20)]
+    dot_invisible_threshold: usize,
+
+    /// Merges edges sharing an end into bundles in the dot file (graphviz's `concentrate`), which helps a lot with closures where many packages share dependencies.
+    #[arg(long)]
+    dot_concentrate: bool,
+
+    /// Background colour of the dot file, as `#rrggbb` or an X11 colour name. Transparent if not set.
+    #[arg(long)]
+    dot_background_color: Option<String>,
+
+    /// Colour of the node labels and outlines in the dot file, as `#rrggbb` or an X11 colour name. Black if not set.
+    #[arg(long)]
+    dot_font_color: Option<String>,
+
+    /// Colour of the edges in the dot file, as `#rrggbb` or an X11 colour name. Black if not set.
+    #[arg(long)]
+    dot_edge_color: Option<String>,
+
+    /// Colours the dot file for dark backgrounds: a `#1e1e1e` background, `#d4d4d4` labels, `#808080` edges and darker node fills.
+    /// The other `--dot-*-color` flags take precedence over these colours.
+    #[arg(long)]
+    dot_dark_mode: bool,
+
+    /// Minimum space between nodes in the same rank, in inches (graphviz's `nodesep`, 0.25 by default).
+    /// Values around 0.1 keep closures with thousands of packages compact, while 0.5 or more makes closures with a few dozen packages easier to read.
+    #[arg(long, value_name = "FLOAT")]
+    dot_nodesep: Option<f64>,
+
+    /// Minimum space between ranks, in inches (graphviz's `ranksep`, 0.5 by default).
+    /// Large closures usually need 1.0 or more for edges between levels to be distinguishable, while 0.3 is enough for small closures.
+    #[arg(long, value_name = "FLOAT")]
+    dot_ranksep: Option<f64>,
+
+    /// Tuning parameter: never adds the per-level rank groupings to the dot file, no matter the closure size.
+    /// Same as `--dot-subgraph-style none`.
+    #[arg(long)]
+    dot_no_rank: bool,
+
+    /// How packages in the same level are grouped in the dot file.
+    #[arg(long, value_enum, default_value_t = DotSubgraphStyle::Rank)]
+    dot_subgraph_style: DotSubgraphStyle,
+
+    /// Labels the edges in the dot file, which makes it noticeably larger.
+    #[arg(long, value_enum, default_value_t = DotEdgeLabels::None)]
+    dot_edge_labels: DotEdgeLabels,
+
+    /// Colors the packages that aren't in the closure of this store path in bright green in the dot file, e.g. to see what a system update added.
+    #[arg(long, visible_alias = "reference-path")]
+    color_new_packages: Option<PathBuf>,
+
+    /// Lists the packages that aren't in the closure of `--reference-path` and their total size, and draws every other package in gray in the dot file.
+    #[arg(long, requires = "color_new_packages")]
+    show_only_new: bool,
+
+    /// Shows the size of every package in the dot file labels as a percentage of the closure size.
+    /// The csv file always has these percentages in its `size_pct` column.
+    #[arg(long)]
+    normalize_sizes: bool,
+
+    /// Template for the summary line printed at the end of the analysis. Can be specified multiple times to print multiple lines.
+    /// Placeholders: `{total_bytes}`, `{total_bytes_human}`, `{package_count}`, `{max_level}`, `{root_name}`, `{leaf_count}` and `{analysis_time_ms}`.
+    #[arg(
+        long,
+        default_value = "{root_name}: {package_count} packages, {total_bytes_human} total"
+    )]
+    print_format: Vec<String>,
+
+    /// Template for the labels of the nodes in the dot file, e.g. `"{name}\n{size_human}"`.
+    /// Placeholders: `{name}`, `{size}`, `{size_human}`, `{level}`, `{path}`, `{hash}`, `{fan_in}` (packages depending on it) and `{fan_out}` (direct dependencies).
+    #[arg(long, default_value = "{name}")]
+    dot_label_template: String,
+
+    /// Draws at most this many edges going into each package in the dot file, keeping the ones from the largest packages.
+    /// Packages with suppressed edges get a `+N more` line in their label. Other outputs still have every edge.
+    #[arg(long)]
+    max_used_by: Option<usize>,
+
+    /// Truncates the labels in the dot file to this many characters, followed by `...`. The full name is kept in the tooltip of each node.
+    #[arg(long)]
+    dot_max_label_len: Option<usize>,
+
+    /// Adds a "Level N" label beside every level of the dot file, and a legend explaining what node sizes and levels mean.
+    /// Level labels need the per-level rank groupings, so they're skipped when those are.
+    #[arg(long)]
+    level_labels: bool,
+
+    /// Also generates one dot file per level next to the dot file, named `<name>_level<N>.dot`, for closures too large to view at once.
+    /// Each file has the packages at that level and their edges to the packages at the level below.
+    #[arg(long)]
+    split_by_level: bool,
+
+    /// Removes the prefix shared by the names of all packages from the labels in the dot file.
+    /// Other outputs still use the full names.
+    #[arg(long)]
+    trim_common_prefix: bool,
+
+    /// Graphviz engine recommended (through a comment) in the dot file, and used when rendering it with `--render`.
+    #[arg(long, value_enum)]
+    dot_engine: Option<DotEngine>,
+
+    /// Renders the dot file with graphviz after generating it.
+    #[arg(long)]
+    render: bool,
+
+    /// Format used when rendering the dot file.
+    #[arg(long, value_enum, default_value_t = RenderFormat::Svg)]
+    render_format: RenderFormat,
+
+    /// Path to the rendered file.
+    /// If not specified, the dot file path with the extension of the render format will be used.
+    #[arg(long)]
+    render_output: Option<PathBuf>,
+
+    /// Keeps running, and analyzes the store path again (overwriting the output files) whenever it changes, e.g. when a `result` symlink is replaced by a new build.
+    /// Press Ctrl-C to stop watching.
+    #[arg(long, requires = "store_path")]
+    watch: bool,
+
+    /// How often to check the store path for changes with `--watch`.
+    #[arg(long, default_value_t = 5, requires = "watch")]
+    watch_interval: u64,
+
+    /// Renders the dot file to svg after every analysis with `--watch`, if graphviz is available.
+    #[arg(long, requires = "watch")]
+    watch_render: bool,
+
+    /// Asks which outputs to generate, where to save them and some analysis options, then prints the equivalent flags.
+    /// Only asks about what isn't already given as flags, and is skipped when stderr isn't a terminal.
+    #[arg(long)]
+    interactive_select: bool,
+
+    /// Only keeps the package with this name, everything it depends on, and everything that depends on it.
+    /// The name can be either the package's short name or its name without the version.
+    #[arg(long)]
+    focus: Option<String>,
+
+    /// When focusing on a package, only keeps packages at most this many hops away from it, in either direction.
+    #[arg(long, requires = "focus")]
+    focus_depth: Option<usize>,
+
+    /// Prints the shortest dependency path between the two given packages.
+    /// Packages can be named the same way as with `--focus`.
+    #[arg(long, num_args = 2, value_names = ["FROM", "TO"])]
+    path: Option<Vec<String>>,
+
+    /// Prints up to 10 different dependency paths from the root to the package with this name, showing why it's in the closure.
+    /// The package can be named the same way as with `--focus`.
+    #[arg(long)]
+    why: Option<String>,
+
+    #[command(flatten)]
+    nix: NixArgs,
+
+    /// Prints the dependency chain from the root with the largest total size, and draws it in red in the dot file.
+    #[arg(long)]
+    critical_path: bool,
+
+    /// Prints the N direct dependencies of the root that add the most to the closure, counting their own size and the size of everything only they pull in.
+    #[arg(long, value_name = "N")]
+    top_closure: Option<usize>,
+
+    /// Prints the N packages closest to the root, which are the most likely to be rebuilt since they're rebuilt whenever any of their dependencies change,
+    /// along with how many packages use each of them.
+    #[arg(long, value_name = "N")]
+    hot_packages: Option<usize>,
+
+    /// Prints the N packages with the highest influence score, which is the package size times `1 + log2(fan-in + 1)`.
+    /// Packages both large and used by many others score the highest, and are usually the best ones to optimize first.
+    #[arg(long, value_name = "N")]
+    top_influence: Option<usize>,
+
+    /// Sizes nodes in the dot file by influence score (see `--top-influence`) instead of by package size.
+    #[arg(long)]
+    size_by_influence: bool,
+
+    /// Prints packages that are often in a closure by accident: development outputs (e.g. `-dev`, `-doc`), build tools (e.g. gcc, cmake) and libraries with many versions.
+    #[arg(long)]
+    audit: bool,
+
+    /// TOML file changing the heuristics of `--audit`, with any of these keys:
+    /// `dev_suffixes` and `build_tools` (arrays of strings) and `max_versions` (integer).
+    #[arg(long, requires = "audit")]
+    audit_config: Option<PathBuf>,
+
+    /// Prints how many packages and bytes each level of the closure has, as a histogram.
+    #[arg(long)]
+    print_level_distribution: bool,
+
+    /// Prints the packages that are single points of failure, i.e. the only way the root reaches some other packages, sorted by how many packages use them.
+    #[arg(long)]
+    spf_report: bool,
+
+    /// Prints every package the package with this name depends on, directly or transitively, sorted by size.
+    /// The dot file will only have these packages. The package can be named the same way as with `--focus`.
+    #[arg(long)]
+    reachable_from: Option<String>,
+
+    /// Replaces the store paths and names of all packages with `pkg_<N>` and `package_<N>` in everything printed or generated after querying the store, keeping the sizes and structure.
+    /// Meant for sharing an analysis (e.g. in bug reports) without revealing what's installed.
+    /// Can't be combined with flags that print package names while the store is still being queried (e.g. `--why`), or that only print numbers.
+    #[arg(
+        long,
+        conflicts_with_all = ["reachable_from", "validate_references", "verify"],
+        conflicts_with_all = ["path", "why", "self_analyze", "check", "count_only", "byte_count_only"]
+    )]
+    anonymize: bool,
+
+    /// Makes `--anonymize` name packages from a hash of this seed and their store path instead of their position, so the same package gets the same name in every run.
+    #[arg(long, requires = "anonymize")]
+    seed: Option<u64>,
+
+    /// Also calculates the closure size from `nix-store --query --requisites`, and warns if it doesn't match the size of the parsed tree.
+    #[arg(long)]
+    closure_size: bool,
+
+    /// Prints statistics about the closure as a json object instead of the usual summary.
+    #[arg(long)]
+    print_stats_json: bool,
+
+    /// Warns if the dot file is estimated to be larger than this many bytes.
+    #[arg(long, default_value_t = 10 * 1024 * 1024)]
+    max_dot_size: usize,
+
+    /// Skips the dot file size check.
+    #[arg(long)]
+    force: bool,
+
+    /// For every package with more than one version in the closure, prints each version with the packages that depend on it.
+    #[arg(long)]
+    deduplication_hints: bool,
+
+    /// Prints the deduplication hints as a json object.
+    #[arg(long, requires = "deduplication_hints")]
+    json: bool,
+}
+
+/// Turns a dot node ID into a store path, removing its quotes and adding the store prefix if it isn't there.
+fn dot_id_to_store_path(id: &str) -> String {
+    let id = id.trim().trim_matches('"');
+
+    if id.starts_with(&format!("{}/", store_prefix())) {
+        id.to_string()
+    } else {
+        format!("{}/{}", store_prefix(), id)
+    }
+}
+
+struct NixStoreGraph {
+    // Store paths of every node, in the order they first appear.
+    paths: Vec<String>,
+    // (from, to) store paths of every edge.
+    edges: Vec<(String, String)>,
+}
+
+/// Parses the node and edge statements of a dot file generated by `nix-store --query --graph`.
+/// This isn't a general dot parser: it expects one statement per line, as nix-store generates them.
+fn parse_nix_store_graph(contents: &str) -> GenericResult<NixStoreGraph> {
+    let mut paths: Vec<String> = Vec::new();
+    let mut seen: HashSet<String> = HashSet::new();
+    let mut edges = Vec::new();
+
+    let mut add_path = |path: &String| {
+        if seen.insert(path.clone()) {
+            paths.push(path.clone());
+        }
+    };
+
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with("digraph") || line.starts_with('}') {
+            continue;
+        }
+
+        // Attributes (e.g. labels) don't matter, since we get everything from the node IDs.
+        let statement = match line.find(" [") {
+            Some(attributes_start) => &line[..attributes_start],
+            None => line.trim_end_matches(';'),
+        };
+
+        if let Some((from, to)) = statement.split_once("->") {
+            let from = dot_id_to_store_path(from);
+            let to = dot_id_to_store_path(to);
+
+            add_path(&from);
+            add_path(&to);
+            edges.push((from, to));
+        } else if statement.starts_with('"') {
+            add_path(&dot_id_to_store_path(statement));
+        } else {
+            return Err(format!(
+                "We found an unexpected line when parsing the nix-store graph: {}",
+                line
+            )
+            .into());
+        }
+    }
+
+    Ok(NixStoreGraph { paths, edges })
+}
+
+/// Builds the package tree from a dot file generated by `nix-store --query --graph`.
+/// In these files an edge `a -> b` means that `b` references (depends on) `a`, so the root is the only package without outgoing edges.
+fn load_tree_from_graph(
+    graph_path: &PathBuf,
+    nix_extra_args: &[String],
+) -> GenericResult<PackageTree> {
+    let contents = std::fs::read_to_string(graph_path)?;
+    let NixStoreGraph { paths, edges } = parse_nix_store_graph(&contents)?;
+
+    let has_referrers: HashSet<&str> = edges.iter().map(|(from, _)| from.as_str()).collect();
+    let roots: Vec<&String> = paths
+        .iter()
+        .filter(|path| !has_referrers.contains(path.as_str()))
+        .collect();
+    let root = match roots.as_slice() {
+        [root] => *root,
+        [] => return Err("The nix-store graph doesn't have a root package".into()),
+        _ => {
+            return Err(format!(
+                "The nix-store graph has multiple root packages: {}",
+                roots
+                    .iter()
+                    .map(|root| root.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )
+            .into())
+        }
+    };
+
+    let mut tree = PackageTree::new(Package::new(root.clone(), nix_extra_args)?);
+    let mut positions: HashMap<&str, usize> = HashMap::from([(root.as_str(), 0)]);
+    for path in paths.iter().filter(|&path| path != root) {
+        let pos = tree.add_package(Package::new(path.clone(), nix_extra_args)?);
+        positions.insert(path, pos);
+    }
+
+    for (dependency, referrer) in edges.iter() {
+        if dependency != referrer {
+            tree.register_dependency(positions[referrer.as_str()], positions[dependency.as_str()]);
+        }
+    }
+
+    // Edges come in no particular order, so levels registered along the way may be stale.
+    tree.recalculate_levels();
+    {
+        let _span = tracing::info_span!("calculate_properties").entered();
+        tree.calculate_graph_properties()?;
+    }
+
+    Ok(tree)
+}
+
+/// Builds the url of a locked flake input from the `locked` attributes of its node in `flake.lock`, e.g. `github:NixOS/nixpkgs/<rev>`.
+fn flake_input_url(locked: &JsonValue) -> Option<String> {
+    let attribute = |name: &str| locked.get(name).and_then(JsonValue::as_str);
+    let input_type = attribute("type")?;
+
+    Some(match input_type {
+        "github" | "gitlab" | "sourcehut" => format!(
+            "{}:{}/{}/{}",
+            input_type,
+            attribute("owner")?,
+            attribute("repo")?,
+            attribute("rev")?
+        ),
+        "git" | "hg" => match attribute("rev") {
+            Some(rev) => format!("{}+{}?rev={}", input_type, attribute("url")?, rev),
+            None => format!("{}+{}", input_type, attribute("url")?),
+        },
+        "path" => format!("path:{}", attribute("path")?),
+        "indirect" => format!("flake:{}", attribute("id")?),
+        _ => attribute("url")?.to_string(),
+    })
+}
+
+/// Finds the node of a flake input in `flake.lock`. Inputs are either a node name, or a path of input names starting from the root node when they `follow` another input.
+fn resolve_flake_input(
+    nodes: &JsonValue,
+    root_name: &str,
+    input: &JsonValue,
+    hops_left: usize,
+) -> GenericResult<String> {
+    if hops_left == 0 {
+        return Err("The flake.lock inputs follow each other in a loop".into());
+    }
+
+    match input {
+        JsonValue::String(name) => Ok(name.clone()),
+        JsonValue::Array(input_path) => {
+            let mut current = root_name.to_string();
+            for segment in input_path {
+                let segment = segment
+                    .as_str()
+                    .ok_or("Expected input names in a flake.lock 'follows' path")?;
+                let next = nodes
+                    .get(&current)
+                    .and_then(|node| node.get("inputs"))
+                    .and_then(|inputs| inputs.get(segment))
+                    .ok_or_else(|| {
+                        format!(
+                            "The flake.lock node '{}' has no input '{}'",
+                            current, segment
+                        )
+                    })?;
+                current = resolve_flake_input(nodes, root_name, next, hops_left - 1)?;
+            }
+            Ok(current)
+        }
+        _ => Err("Expected a node name or a path of input names as a flake.lock input".into()),
+    }
+}
+
+/// Builds a tree where every package is a flake input from a `flake.lock` file, depending on its own inputs.
+/// Packages have a fake store path with the locked revision as the hash (or zeros if there's none), and a size of 0.
+fn build_tree_from_flake_lock(json: &str) -> GenericResult<PackageTree> {
+    let lock = parse_json(json)?;
+    let nodes = lock
+        .get("nodes")
+        .ok_or("The flake.lock file has no 'nodes'")?;
+    let root_name = lock
+        .get("root")
+        .and_then(JsonValue::as_str)
+        .unwrap_or("root");
+
+    let new_package = |name: &str| -> GenericResult<Package> {
+        let node = nodes
+            .get(name)
+            .ok_or_else(|| format!("The flake.lock file has no node '{}'", name))?;
+        let locked = node.get("locked");
+        let rev = locked
+            .and_then(|locked| locked.get("rev"))
+            .and_then(JsonValue::as_str)
+            .map_or_else(|| format!("{:032}", 0), str::to_string);
+
+        let mut package = Package::with_size(format!("{}/{}-{}", store_prefix(), rev, name), 0);
+        package.flake_url = locked.and_then(flake_input_url);
+        Ok(package)
+    };
+
+    let mut tree = PackageTree::new(new_package(root_name)?);
+    let mut positions: HashMap<String, usize> = HashMap::from([(root_name.to_string(), 0)]);
+    let mut queue = VecDeque::from([root_name.to_string()]);
+    while let Some(name) = queue.pop_front() {
+        let pos = positions[&name];
+        let Some(JsonValue::Object(inputs)) = nodes.get(&name).and_then(|node| node.get("inputs"))
+        else {
+            continue;
+        };
+
+        for (_, input) in inputs {
+            let input_name = resolve_flake_input(nodes, root_name, input, 64)?;
+            let input_pos = match positions.get(&input_name) {
+                Some(&input_pos) => input_pos,
+                None => {
+                    let input_pos = tree.add_package(new_package(&input_name)?);
+                    positions.insert(input_name.clone(), input_pos);
+                    queue.push_back(input_name);
+                    input_pos
+                }
+            };
+
+            if input_pos != pos && !tree.package(pos).dependencies.contains(&input_pos) {
+                tree.register_dependency(pos, input_pos);
+            }
+        }
+    }
+
+    // Inputs are added breadth-first, so levels registered along the way may be stale.
+    tree.recalculate_levels();
+    tree.calculate_graph_properties()?;
+
+    Ok(tree)
+}
+
+/// Reads a `flake.lock` file with `build_tree_from_flake_lock`. With `query_nar_size`, every input is fetched with `nix flake prefetch`
+/// (from the binary cache or its source) so its size can be queried.
+fn load_tree_from_flake_lock(
+    lock_path: &Path,
+    query_nar_size: bool,
+    nix_extra_args: &[String],
+) -> GenericResult<PackageTree> {
+    let mut tree = build_tree_from_flake_lock(&std::fs::read_to_string(lock_path)?)?;
+    if !query_nar_size {
+        return Ok(tree);
+    }
+
+    for pkg in tree.nodes.iter_mut() {
+        let Some(url) = pkg.flake_url.clone() else {
+            continue;
+        };
+
+        let prefetch_output = run_nix_store_with_retry(
+            &[
+                "flake",
+                "prefetch",
+                "--extra-experimental-features",
+                "nix-command flakes",
+                "--json",
+                &url,
+            ],
+            "nix",
+            nix_extra_args,
+        )?;
+        let prefetch = parse_json(std::str::from_utf8(&prefetch_output)?)?;
+        let store_path = prefetch
+            .get("storePath")
+            .and_then(JsonValue::as_str)
+            .ok_or("Got an unexpected output from 'nix flake prefetch --json'")?;
+
+        let mut store_object = Package::with_size(store_path.to_string(), 0);
+        store_object.query_size(nix_extra_args)?;
+        pkg.size_bytes = store_object.size_bytes;
+    }
+    tree.calculate_graph_properties()?;
+
+    Ok(tree)
+}
+
+/// Runs `nix-store --query --requisites` on `store_path` and builds the package tree from the references of every path in it.
+fn load_tree_from_requisites(
+    store_path: &Path,
+    nix_extra_args: &[String],
+) -> GenericResult<PackageTree> {
+    let store_path = store_path.to_string_lossy();
+    let requisites_output = {
+        let _span = tracing::info_span!("query_requisites").entered();
+        run_nix_store_with_retry(
+            &["--query", "--requisites", &store_path],
+            "nix-store",
+            nix_extra_args,
+        )?
+    };
+    let requisites_output = std::str::from_utf8(&requisites_output)?;
+
+    // The root must come first, but nix-store lists it last.
+    let paths: Vec<&str> = std::iter::once(store_path.as_ref())
+        .chain(requisites_output.lines().map(str::trim))
+        .filter(|path| !path.is_empty())
+        .collect();
+
+    let mut tree = {
+        let _span = tracing::info_span!("query_references").entered();
+        PackageTree::from_requisites(&paths, "nix-store", nix_extra_args)?
+    };
+    {
+        let _span = tracing::info_span!("calculate_properties").entered();
+        tree.calculate_graph_properties()?;
+    }
+
+    Ok(tree)
+}
+
+/// Runs `nix-store --query --tree` on `store_path` and builds the package tree from its output.
+fn load_tree(store_path: &Path, nix_extra_args: &[String]) -> GenericResult<PackageTree> {
+    let mut tree = parse_tree_output(store_path, nix_extra_args, &|path| {
+        Package::new(path, nix_extra_args)
+    })?;
+    {
+        let _span = tracing::info_span!("calculate_properties").entered();
+        tree.calculate_graph_properties()?;
+    }
+
+    Ok(tree)
+}
+
+/// Runs `nix-store --query --tree` on `store_path` and builds the package tree from its output, creating packages with `new_package`.
+/// `calculate_graph_properties` still needs to be called on the tree.
+fn parse_tree_output(
+    store_path: &Path,
+    nix_extra_args: &[String],
+    new_package: &dyn Fn(String) -> GenericResult<Package>,
+) -> GenericResult<PackageTree> {
+    let tree_output = {
+        let _span = tracing::info_span!("query_tree").entered();
+        let _phase = PhaseSpan::enter(Phase::QueryTree);
+        run_nix_store_with_retry(
+            &["--query", "--tree", &store_path.to_string_lossy()],
+            "nix-store",
+            nix_extra_args,
+        )?
+    };
+    let tree_output = std::str::from_utf8(&tree_output)?;
+
+    let mut lines = tree_output.lines();
+    let mut tree: PackageTree;
+    let root_path = lines
+        .next()
+        .ok_or("Got no output from 'nix-store --query --tree'!")?;
+    if root_path.starts_with("/") {
+        tree = PackageTree::new(new_package(root_path.into())?);
+    } else {
+        return Err("Got an unexpected output from 'nix-store --query --tree'!".into());
+    }
+
+    // Packages usually query their sizes while being parsed, which is timed separately.
+    let parse_start = Instant::now();
+    let size_queries_before = PhaseTimer::elapsed(Phase::QuerySizes);
+    process_lines(&mut tree, 0, lines.collect(), new_package)?;
+    let size_queries_during = PhaseTimer::elapsed(Phase::QuerySizes) - size_queries_before;
+    PhaseTimer::record(
+        Phase::ParseTree,
+        parse_start.elapsed().saturating_sub(size_queries_during),
+    );
+    if let Some(timer) = PHASE_TIMER.get() {
+        timer.lock().unwrap().parsed_packages = tree.nodes.len();
+    }
+
+    Ok(tree)
+}
+
+/// Builds the package tree of `store_path` with every size set to 0, which is much faster than `load_tree` since no size is queried.
+/// Sizes can be queried later with `PackageTree::query_sizes_for`.
+fn load_tree_without_sizes(
+    store_path: &Path,
+    nix_extra_args: &[String],
+) -> GenericResult<PackageTree> {
+    let mut tree = parse_tree_output(store_path, nix_extra_args, &|path| {
+        Ok(Package::with_size(path, 0))
+    })?;
+    // Needed for `by_level` and the name lookups.
+    tree.calculate_graph_properties()?;
+
+    Ok(tree)
+}
+
+/// Base packages that show up in almost every closure, ignored with `--ignore-system-packages`.
+const SYSTEM_PACKAGES: [&str; 15] = [
+    "glibc",
+    "linux-headers",
+    "gcc-lib",
+    "bash",
+    "coreutils",
+    "findutils",
+    "diffutils",
+    "gnused",
+    "gnugrep",
+    "gawk",
+    "gnutar",
+    "gzip",
+    "bzip2",
+    "xz",
+    "zlib",
+];
+
+/// Whether the package is one of `names`, ignoring its version. Names can include an output, so `gcc-lib` matches `gcc-13.2.0-lib`.
+fn is_system_package(pkg: &Package, names: &[String]) -> bool {
+    let base_name = pkg.name_without_version();
+    names.iter().any(|name| {
+        name == base_name
+            || name
+                .strip_prefix(base_name)
+                .and_then(|output| output.strip_prefix('-'))
+                .is_some_and(|output| pkg.symbolic_name().ends_with(&format!("-{}", output)))
+    })
+}
+
+/// Estimate of the total size of a closure from a sample of its packages.
+struct SizeEstimate {
+    sample_size: usize,
+    total_bytes: f64,
+    // Half the width of the 95% confidence interval.
+    margin_bytes: f64,
+}
+
+/// Builds the package tree of `store_path`, but only queries the size of about `sample_size` packages, sampled from every level proportionally to its package count.
+/// Every other package gets the mean size of the sampled packages in its level.
+fn load_sampled_tree(
+    store_path: &Path,
+    nix_extra_args: &[String],
+    sample_size: usize,
+) -> GenericResult<(PackageTree, SizeEstimate)> {
+    let mut tree = load_tree_without_sizes(store_path, nix_extra_args)?;
+
+    let sample = tree.stratified_sample(sample_size);
+    for &pos in sample.iter() {
+        tree.package_mut(pos).query_size(nix_extra_args)?;
+    }
+
+    let estimate = estimate_from_sample(&mut tree, &sample);
+    // Sizes changed, so graph sizes must be calculated again.
+    tree.calculate_graph_properties()?;
+
+    Ok((tree, estimate))
+}
+
+/// Estimates the total size of the closure from the sizes of the `sample` packages, and gives every other package the mean size of the sampled packages in its level.
+/// Every level must have at least one sampled package, as `stratified_sample` makes sure.
+fn estimate_from_sample(tree: &mut PackageTree, sample: &[usize]) -> SizeEstimate {
+    let mut sampled_by_level: Vec<Vec<usize>> = vec![Vec::new(); tree.by_level.len()];
+    for &pos in sample.iter() {
+        sampled_by_level[tree.package(pos).level].push(tree.package(pos).size_bytes);
+    }
+
+    // Standard stratified estimator: each level contributes its package count times the mean of its sample.
+    let mut total_bytes = 0.0;
+    let mut variance = 0.0;
+    let mut sampled: HashSet<usize> = sample.iter().copied().collect();
+    for (level, sizes) in sampled_by_level.iter().enumerate() {
+        let level_count = tree.by_level[level].len() as f64;
+        let sampled_count = sizes.len() as f64;
+        let mean = sizes.iter().sum::<usize>() as f64 / sampled_count;
+
+        total_bytes += level_count * mean;
+        if sizes.len() > 1 {
+            let sample_variance = sizes
+                .iter()
+                .map(|&size| (size as f64 - mean).powi(2))
+                .sum::<f64>()
+                / (sampled_count - 1.0);
+            variance += level_count.powi(2) * (1.0 - sampled_count / level_count) * sample_variance
+                / sampled_count;
+        }
+
+        for pos in tree.by_level[level].clone() {
+            if sampled.insert(pos) {
+                tree.package_mut(pos).size_bytes = mean.round() as usize;
+            }
+        }
+    }
+
+    SizeEstimate {
+        sample_size: sample.len(),
+        total_bytes,
+        margin_bytes: 1.96 * variance.sqrt(),
+    }
+}
+
+/// Collects stdout output as `KEY=VALUE` lines for `--machine-readable`, with arrays written as `KEY[INDEX]=VALUE`.
+#[derive(Default)]
+struct MachineOutput {
+    entries: Vec<(String, String)>,
+}
+
+impl MachineOutput {
+    fn push(&mut self, key: impl Into<String>, value: impl ToString) {
+        self.entries.push((key.into(), value.to_string()));
+    }
+
+    fn push_array<T: ToString>(&mut self, key: &str, values: impl IntoIterator<Item = T>) {
+        for (index, value) in values.into_iter().enumerate() {
+            self.push(format!("{}[{}]", key, index), value);
+        }
+    }
+
+    fn print(&self) {
+        for (key, value) in self.entries.iter() {
+            println!("{}={}", key, value);
+        }
+    }
+}
+
+/// How json output is laid out, from `--json-pretty` and `--json-indent`.
+#[derive(Clone, Copy, Debug)]
+struct JsonLayout {
+    pretty: bool,
+    indent: Option<usize>,
+}
+
+impl JsonLayout {
+    /// Spaces per nesting level, or `None` for compact json.
+    /// Without explicit flags, json printed to a terminal is pretty and json written anywhere else (pipes, files) is compact.
+    fn indent(&self, to_terminal: bool) -> Option<usize> {
+        match self.indent {
+            Some(0) => None,
+            Some(indent) => Some(indent),
+            None if self.pretty || to_terminal => Some(2),
+            None => None,
+        }
+    }
+
+    fn print(&self, json: &str) {
+        println!(
+            "{}",
+            reformat_json(json, self.indent(std::io::stdout().is_terminal()))
+        );
+    }
+}
+
+fn print_shortest_path(
+    tree: &PackageTree,
+    from_name: &str,
+    to_name: &str,
+    machine_output: Option<&mut MachineOutput>,
+) -> GenericResult<()> {
+    let from = find_focus_package(tree, from_name)?;
+    let to = find_focus_package(tree, to_name)?;
+    let path = tree.shortest_path_between(from, to);
+
+    match (path, machine_output) {
+        (Some(path), Some(machine_output)) => {
+            machine_output.push("PATH_FOUND", 1);
+            machine_output.push("PATH", format_package_path(tree, &path));
+        }
+        (None, Some(machine_output)) => machine_output.push("PATH_FOUND", 0),
+        (Some(path), None) => println!("{}", format_package_path(tree, &path)),
+        (None, None) => println!(
+            "{} doesn't depend on {}",
+            tree.package(from).short_name,
+            tree.package(to).short_name
+        ),
+    }
+
+    Ok(())
+}
+
+fn print_why(
+    tree: &PackageTree,
+    name: &str,
+    machine_output: Option<&mut MachineOutput>,
+) -> GenericResult<()> {
+    let target = find_focus_package(tree, name)?;
+    let paths = tree
+        .all_simple_paths(0, target, 10)
+        .into_iter()
+        .map(|path| format_package_path(tree, &path));
+
+    match machine_output {
+        Some(machine_output) => machine_output.push_array("WHY_PATH", paths),
+        None => paths.for_each(|path| println!("{}", path)),
+    }
+
+    Ok(())
+}
+
+/// Lists the packages (by store path) that are only in one of the two trees, largest first.
+fn print_closure_comparison(
+    first: &PackageTree,
+    second: &PackageTree,
+    mut machine_output: Option<&mut MachineOutput>,
+) {
+    let first_paths: HashSet<&str> = first.nodes.iter().map(|pkg| pkg.path.as_str()).collect();
+    let second_paths: HashSet<&str> = second.nodes.iter().map(|pkg| pkg.path.as_str()).collect();
+
+    for (description, key, tree, other_paths) in [
+        ("first", "ONLY_IN_FIRST", first, &second_paths),
+        ("second", "ONLY_IN_SECOND", second, &first_paths),
+    ] {
+        let mut only_here: Vec<&Package> = tree
+            .nodes
+            .iter()
+            .filter(|pkg| !other_paths.contains(pkg.path.as_str()))
+            .collect();
+        only_here.sort();
+        let only_here_bytes: usize = only_here.iter().map(|pkg| pkg.size_bytes).sum();
+
+        if let Some(machine_output) = machine_output.as_deref_mut() {
+            machine_output.push(format!("{}_COUNT", key), only_here.len());
+            machine_output.push(format!("{}_BYTES", key), only_here_bytes);
+            machine_output.push_array(key, only_here.iter().map(|pkg| &pkg.short_name));
+            continue;
+        }
+
+        println!(
+            "Only in the {} closure ({} packages, {} bytes):",
+            description,
+            only_here.len(),
+            only_here_bytes
+        );
+        for pkg in only_here {
+            println!("  {} ({} B)", pkg.short_name, pkg.size_bytes);
+        }
+    }
+
+    match machine_output {
+        Some(machine_output) => {
+            machine_output.push("FIRST_TOTAL_BYTES", first.sum_package_bytes());
+            machine_output.push("SECOND_TOTAL_BYTES", second.sum_package_bytes());
+        }
+        None => println!(
+            "Total bytes: {} in the first closure, {} in the second closure",
+            first.sum_package_bytes(),
+            second.sum_package_bytes()
+        ),
+    }
+}
+
+/// Prints the packages marked as new by `--reference-path`, largest first, and how much they add to the closure.
+fn print_new_packages(tree: &PackageTree, machine_output: Option<&mut MachineOutput>) {
+    let mut new_packages: Vec<&Package> = tree.nodes.iter().filter(|pkg| pkg.is_new).collect();
+    new_packages.sort_by_key(|pkg| std::cmp::Reverse(pkg.size_bytes));
+    let new_bytes: usize = new_packages.iter().map(|pkg| pkg.size_bytes).sum();
+
+    match machine_output {
+        Some(machine_output) => {
+            machine_output.push("NEW_PACKAGES_COUNT", new_packages.len());
+            machine_output.push("NEW_PACKAGES_BYTES", new_bytes);
+            machine_output.push_array(
+                "NEW_PACKAGES",
+                new_packages.iter().map(|pkg| &pkg.short_name),
+            );
+        }
+        None => {
+            println!(
+                "New packages ({} packages, {} added to the closure):",
+                new_packages.len(),
+                format_size_human(new_bytes)
+            );
+            for pkg in new_packages {
+                println!("  {} ({} B)", pkg.short_name, pkg.size_bytes);
+            }
+        }
+    }
+}
+
+fn print_stats(
+    tree: &PackageTree,
+    include_indirect_only: bool,
+    machine_output: Option<&mut MachineOutput>,
+) {
+    let summary = tree.summary();
+    let shape = tree.graph_shape();
+
+    match machine_output {
+        Some(machine_output) => {
+            machine_output.push("TOTAL_PACKAGES", summary.total_packages);
+            machine_output.push("TOTAL_BYTES", summary.total_bytes);
+            machine_output.push("MAX_LEVEL", summary.max_level);
+            machine_output.push("LEAF_COUNT", summary.leaf_count);
+            machine_output.push("GRAPH_DIAMETER", shape.diameter);
+            machine_output.push("GRAPH_RADIUS", shape.radius);
+            machine_output.push_array(
+                "CENTER",
+                shape
+                    .center
+                    .iter()
+                    .map(|&pos| &tree.package(pos).short_name),
+            );
+
+            if include_indirect_only {
+                let indirect_only = tree.nodes.iter().filter(|pkg| pkg.is_indirect_only(tree));
+                machine_output
+                    .push_array("INDIRECT_ONLY", indirect_only.map(|pkg| &pkg.short_name));
+            }
+        }
+        None => {
+            println!("Packages: {}", summary.total_packages);
+            println!("Total bytes: {}", summary.total_bytes);
+            println!("Max level: {}", summary.max_level);
+            println!("Packages without dependencies: {}", summary.leaf_count);
+            let timed_out_packages = TIMED_OUT_SIZE_QUERIES.lock().unwrap().len();
+            if timed_out_packages > 0 {
+                println!(
+                    "{} packages timed out during size query.",
+                    timed_out_packages
+                );
+            }
+            println!(
+                "Graph diameter: {} hops, center package: {}",
+                shape.diameter,
+                tree.package(shape.center[0]).short_name
+            );
+
+            if include_indirect_only {
+                println!();
+                println!("Packages only pulled in through other dependencies:");
+                for pkg in tree.nodes.iter().filter(|pkg| pkg.is_indirect_only(tree)) {
+                    println!("  {} ({} B)", pkg.short_name, pkg.size_bytes);
+                }
+            }
+        }
+    }
+}
+
+/// Prints every version of the packages that are duplicated in the closure, together with what depends on each version.
+fn print_deduplication_hints(
+    tree: &PackageTree,
+    json_layout: Option<JsonLayout>,
+    mut machine_output: Option<&mut MachineOutput>,
+) {
+    let duplicates = tree.find_duplicates();
+
+    if let Some(json_layout) = json_layout {
+        let groups: Vec<String> = duplicates
+            .iter()
+            .map(|(name, positions)| {
+                let versions: Vec<String> = positions
+                    .iter()
+                    .map(|&pos| {
+                        let pkg = tree.package(pos);
+                        let used_by: Vec<String> = tree
+                            .iter_used_by(pos)
+                            .map(|parent| format!("\"{}\"", json_escape(&parent.short_name)))
+                            .collect();
+                        format!(
+                            "        {{\"hash\": \"{}\", \"bytes\": {}, \"used_by\": [{}]}}",
+                            json_escape(pkg.hash()),
+                            pkg.size_bytes,
+                            used_by.join(", ")
+                        )
+                    })
+                    .collect();
+                format!(
+                    "    {{\"name\": \"{}\", \"versions\": [\n{}\n    ]}}",
+                    json_escape(name),
+                    versions.join(",\n")
+                )
+            })
+            .collect();
+        json_layout.print(&format!(
+            "{{\n  \"duplicates\": [\n{}\n  ]\n}}",
+            groups.join(",\n")
+        ));
+        return;
+    }
+
+    if let Some(machine_output) = machine_output.as_deref_mut() {
+        machine_output.push("DUPLICATE_COUNT", duplicates.len());
+    }
+
+    for (index, (name, positions)) in duplicates.into_iter().enumerate() {
+        if let Some(machine_output) = machine_output.as_deref_mut() {
+            machine_output.push(format!("DUPLICATE{}_NAME", index + 1), name);
+            for (version, &pos) in positions.iter().enumerate() {
+                let key = format!("DUPLICATE{}_VERSION{}", index + 1, version + 1);
+                machine_output.push(format!("{}_HASH", key), tree.package(pos).hash());
+                machine_output.push(format!("{}_SIZE", key), tree.package(pos).size_bytes);
+                machine_output.push_array(
+                    &format!("{}_USED_BY", key),
+                    tree.iter_used_by(pos).map(|parent| &parent.short_name),
+                );
+            }
+            continue;
+        }
+
+        println!("{} ({} versions):", name, positions.len());
+        for &pos in positions {
+            let pkg = tree.package(pos);
+            println!("  {} ({} B), used by:", pkg.hash(), pkg.size_bytes);
+            for parent in tree.iter_used_by(pos) {
+                println!("    {}", parent.short_name);
+            }
+        }
+    }
+}
+
+/// Prints how many packages (and bytes) each level has, with a bar proportional to the package count.
+fn print_level_distribution(tree: &PackageTree, machine_output: Option<&mut MachineOutput>) {
+    let counts = tree.level_distribution();
+    let sizes = tree.level_size_distribution();
+
+    if let Some(machine_output) = machine_output {
+        machine_output.push_array("LEVEL_PACKAGES", counts.iter().map(|&(_, count)| count));
+        machine_output.push_array("LEVEL_BYTES", sizes.iter().map(|&(_, bytes)| bytes));
+        machine_output.push(
+            "LEVEL_COUNT_STDDEV",
+            format!("{:.2}", tree.level_count_stddev()),
+        );
+        return;
+    }
+
+    const BAR_WIDTH: usize = 40;
+    let largest_count = counts
+        .iter()
+        .map(|&(_, count)| count)
+        .max()
+        .unwrap_or(0)
+        .max(1);
+
+    for (&(level, count), &(_, bytes)) in counts.iter().zip(sizes.iter()) {
+        println!(
+            "Level {}: {} package{} ({:.1} MB) {}",
+            level,
+            count,
+            if count == 1 { "" } else { "s" },
+            bytes as f64 / 1_000_000.0,
+            "#".repeat((count * BAR_WIDTH).div_ceil(largest_count))
+        );
+    }
+    println!(
+        "Standard deviation of packages per level: {:.2}",
+        tree.level_count_stddev()
+    );
+}
+
+/// Heuristics used by `--audit`, which can be changed with `--audit-config`.
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct AuditConfig {
+    // Suffixes of packages that are usually only needed for development, e.g. `-dev`.
+    dev_suffixes: Vec<String>,
+    // Names (without the version) of packages usually only needed to build other packages.
+    build_tools: Vec<String>,
+    // Packages with more versions than this in the closure are reported.
+    max_versions: usize,
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        Self {
+            dev_suffixes: vec!["-dev".to_string(), "-doc".to_string()],
+            build_tools: [
+                "gcc", "make", "gnumake", "cmake", "autoconf", "automake", "perl",
+            ]
+            .map(str::to_string)
+            .to_vec(),
+            max_versions: 2,
+        }
+    }
+}
+
+impl AuditConfig {
+    /// Reads the config from a TOML file with any of the `dev_suffixes`, `build_tools` (arrays of strings) and `max_versions` (integer) keys.
+    /// Missing keys keep their default.
+    fn from_toml(contents: &str) -> GenericResult<Self> {
+        Ok(toml::from_str(contents)?)
+    }
+}
+
+/// Flags packages that are often in a closure by accident: development outputs, build tools and libraries with too many versions.
+/// Returns `(pos, reason)` for every finding, in arena order.
+fn audit_closure(tree: &PackageTree, config: &AuditConfig) -> Vec<(usize, String)> {
+    let is_dev_package = |pkg: &Package| {
+        config
+            .dev_suffixes
+            .iter()
+            .any(|suffix| pkg.symbolic_name().ends_with(suffix.as_str()))
+    };
+    // Development outputs are expected when the closure itself is a development one.
+    let is_dev_closure = is_dev_package(tree.package(0));
+
+    let mut versions: HashMap<&str, usize> = HashMap::new();
+    for pkg in tree.nodes.iter() {
+        *versions.entry(pkg.name_without_version()).or_default() += 1;
+    }
+
+    let mut findings = Vec::new();
+    for (pos, pkg) in tree.nodes.iter().enumerate().skip(1) {
+        if !is_dev_closure && is_dev_package(pkg) {
+            findings.push((pos, "development output in a runtime closure".to_string()));
+        }
+        if config
+            .build_tools
+            .iter()
+            .any(|tool| pkg.name_without_version() == tool)
+        {
+            findings.push((pos, "build tool".to_string()));
+        }
+
+        let version_count = versions[pkg.name_without_version()];
+        if version_count > config.max_versions {
+            findings.push((
+                pos,
+                format!(
+                    "one of {} versions of {}",
+                    version_count,
+                    pkg.name_without_version()
+                ),
+            ));
+        }
+    }
+
+    findings
+}
+
+fn print_audit(
+    tree: &PackageTree,
+    config: &AuditConfig,
+    machine_output: Option<&mut MachineOutput>,
+) {
+    let findings = audit_closure(tree, config);
+    let brought_in_by = |pos: usize| {
+        tree.package(pos)
+            .used_by
+            .first()
+            .map_or("", |&parent| tree.package(parent).short_name.as_str())
+    };
+
+    if let Some(machine_output) = machine_output {
+        machine_output.push("AUDIT_COUNT", findings.len());
+        for (index, (pos, reason)) in findings.iter().enumerate() {
+            let pkg = tree.package(*pos);
+            machine_output.push(format!("AUDIT{}_NAME", index + 1), &pkg.short_name);
+            machine_output.push(format!("AUDIT{}_REASON", index + 1), reason);
+            machine_output.push(format!("AUDIT{}_SIZE", index + 1), pkg.size_bytes);
+            machine_output.push(format!("AUDIT{}_USED_BY", index + 1), brought_in_by(*pos));
+        }
+        return;
+    }
+
+    println!("{} suspicious packages found:", findings.len());
+    for (pos, reason) in findings {
+        let pkg = tree.package(pos);
+        println!(
+            "  {} ({}, {} B), brought in by {}",
+            pkg.short_name,
+            reason,
+            pkg.size_bytes,
+            brought_in_by(pos)
+        );
+    }
+}
+
+/// Prints the direct dependencies of the root that contribute the most to the closure size, counting only what would go away without them.
+fn print_top_closure(
+    tree: &PackageTree,
+    count: usize,
+    mut machine_output: Option<&mut MachineOutput>,
+) {
+    let mut contributions: Vec<(usize, usize, usize)> = tree
+        .package(0)
+        .dependencies
+        .iter()
+        .map(|&dep| {
+            (
+                dep,
+                tree.unique_closure_contribution(dep),
+                tree.closure_size_of(dep),
+            )
+        })
+        .collect();
+    contributions.sort_by_key(|&(_, unique_bytes, _)| std::cmp::Reverse(unique_bytes));
+
+    if machine_output.is_none() {
+        println!("Direct dependencies by unique closure contribution (unique bytes, closure bytes, name):");
+    }
+    for (index, (pos, unique_bytes, closure_bytes)) in
+        contributions.into_iter().take(count).enumerate()
+    {
+        let pkg = tree.package(pos);
+        match machine_output.as_deref_mut() {
+            Some(machine_output) => {
+                machine_output.push(format!("TOP_CLOSURE{}_NAME", index + 1), &pkg.short_name);
+                machine_output.push(
+                    format!("TOP_CLOSURE{}_UNIQUE_BYTES", index + 1),
+                    unique_bytes,
+                );
+                machine_output.push(
+                    format!("TOP_CLOSURE{}_CLOSURE_BYTES", index + 1),
+                    closure_bytes,
+                );
+            }
+            None => println!("{}\t{}\t{}", unique_bytes, closure_bytes, pkg.short_name),
+        }
+    }
+}
+
+fn print_hot_packages(
+    tree: &PackageTree,
+    count: usize,
+    mut machine_output: Option<&mut MachineOutput>,
+) {
+    if machine_output.is_none() {
+        println!(
+            "Packages most likely to be rebuilt when a dependency changes (level, used by, name):"
+        );
+    }
+    for (index, pos) in tree
+        .packages_by_level_ascending(count)
+        .into_iter()
+        .enumerate()
+    {
+        let pkg = tree.package(pos);
+        match machine_output.as_deref_mut() {
+            Some(machine_output) => {
+                machine_output.push(format!("HOT{}_NAME", index + 1), &pkg.short_name);
+                machine_output.push(format!("HOT{}_LEVEL", index + 1), pkg.level);
+                machine_output.push(format!("HOT{}_USED_BY", index + 1), pkg.used_by.len());
+            }
+            None => println!("{}\t{}\t{}", pkg.level, pkg.used_by.len(), pkg.short_name),
+        }
+    }
+}
+
+fn print_top_influence(
+    tree: &PackageTree,
+    count: usize,
+    mut machine_output: Option<&mut MachineOutput>,
+) {
+    for (index, (pos, score)) in tree.influence_scores().into_iter().take(count).enumerate() {
+        let pkg = tree.package(pos);
+        match machine_output.as_deref_mut() {
+            Some(machine_output) => {
+                machine_output.push(format!("TOP_INFLUENCE{}_NAME", index + 1), &pkg.short_name);
+                machine_output.push(
+                    format!("TOP_INFLUENCE{}_SCORE", index + 1),
+                    format!("{:.2}", score),
+                );
+            }
+            None => println!("{:.2}\t{}", score, pkg.short_name),
+        }
+    }
+}
+
+/// Reports which packages are shared between the closures of several profiles and which are unique to one of them,
+/// along with how much every pair of profiles overlaps. Writes the report as Markdown to `markdown_path` if given.
+fn print_profiles_report(
+    profiles: &[(String, PackageTree)],
+    markdown_path: Option<&Path>,
+    machine_output: Option<&mut MachineOutput>,
+) -> GenericResult<()> {
+    let path_sets: Vec<HashSet<&str>> = profiles
+        .iter()
+        .map(|(_, tree)| tree.nodes.iter().map(|pkg| pkg.path.as_str()).collect())
+        .collect();
+
+    let mut profile_counts: HashMap<&str, usize> = HashMap::new();
+    for paths in path_sets.iter() {
+        for &path in paths.iter() {
+            *profile_counts.entry(path).or_default() += 1;
+        }
+    }
+
+    let names = |paths: Vec<&str>| -> Vec<String> {
+        let mut names: Vec<String> = paths
+            .into_iter()
+            .map(|path| split_store_path(path).1.to_string())
+            .collect();
+        names.sort();
+        names
+    };
+    let universal = names(
+        profile_counts
+            .iter()
+            .filter(|&(_, &count)| count == profiles.len())
+            .map(|(&path, _)| path)
+            .collect(),
+    );
+    let unique_per_profile: Vec<Vec<String>> = path_sets
+        .iter()
+        .map(|paths| {
+            names(
+                paths
+                    .iter()
+                    .copied()
+                    .filter(|path| profile_counts[path] == 1)
+                    .collect(),
+            )
+        })
+        .collect();
+
+    let mut overlaps: Vec<(usize, usize, f64)> = Vec::new();
+    for first in 0..profiles.len() {
+        for second in first + 1..profiles.len() {
+            let shared = path_sets[first].intersection(&path_sets[second]).count();
+            let combined = path_sets[first].union(&path_sets[second]).count();
+            overlaps.push((
+                first,
+                second,
+                100.0 * shared as f64 / combined.max(1) as f64,
+            ));
+        }
+    }
+
+    if let Some(machine_output) = machine_output {
+        machine_output.push("PROFILES", profiles.len());
+        machine_output.push("TOTAL_UNIQUE_PACKAGES", profile_counts.len());
+        machine_output.push_array("UNIVERSAL_PACKAGES", universal.iter());
+        for (index, ((name, _), unique)) in
+            profiles.iter().zip(unique_per_profile.iter()).enumerate()
+        {
+            machine_output.push(format!("PROFILE{}_NAME", index + 1), name);
+            machine_output.push_array(
+                &format!("PROFILE{}_UNIQUE_PACKAGES", index + 1),
+                unique.iter(),
+            );
+        }
+        for (first, second, overlap) in overlaps.iter() {
+            machine_output.push(
+                format!("OVERLAP_{}_{}", first + 1, second + 1),
+                format!("{:.1}", overlap),
+            );
+        }
+        machine_output.print();
+        return Ok(());
+    }
+
+    let Some(markdown_path) = markdown_path else {
+        println!("Analyzed {} profiles:", profiles.len());
+        for (name, tree) in profiles.iter() {
+            println!("  {} ({})", name, tree.package(0).path);
+        }
+        println!(
+            "Total unique packages across all profiles: {}",
+            profile_counts.len()
+        );
+        println!("Packages in every profile ({}):", universal.len());
+        for name in universal.iter() {
+            println!("  {}", name);
+        }
+        for ((name, _), unique) in profiles.iter().zip(unique_per_profile.iter()) {
+            println!("Packages only in {} ({}):", name, unique.len());
+            for package_name in unique.iter() {
+                println!("  {}", package_name);
+            }
+        }
+        for (first, second, overlap) in overlaps {
+            println!(
+                "Overlap between {} and {}: {:.1}%",
+                profiles[first].0, profiles[second].0, overlap
+            );
+        }
+        return Ok(());
+    };
+
+    let mut report = format!(
+        "# Nix profiles\n\n| Profile | Store path | Packages |\n| --- | --- | --- |\n{}\nTotal unique packages across all profiles: {}\n",
+        profiles
+            .iter()
+            .map(|(name, tree)| format!("| {} | {} | {} |\n", name, tree.package(0).path, tree.nodes.len()))
+            .collect::<String>(),
+        profile_counts.len()
+    );
+    report.push_str(&format!(
+        "\n## Packages in Every Profile ({})\n\n",
+        universal.len()
+    ));
+    for name in universal.iter() {
+        report.push_str(&format!("- {}\n", name));
+    }
+    for ((name, _), unique) in profiles.iter().zip(unique_per_profile.iter()) {
+        report.push_str(&format!(
+            "\n## Packages Only in {} ({})\n\n",
+            name,
+            unique.len()
+        ));
+        for package_name in unique.iter() {
+            report.push_str(&format!("- {}\n", package_name));
+        }
+    }
+    report.push_str(
+        "\n## Overlap Between Profiles\n\n| Profile | Profile | Overlap |\n| --- | --- | --- |\n",
+    );
+    for (first, second, overlap) in overlaps {
+        report.push_str(&format!(
+            "| {} | {} | {:.1}% |\n",
+            profiles[first].0, profiles[second].0, overlap
+        ));
+    }
+
+    let mut file = open_output_file(markdown_path)?;
+    file.write_all(report.as_bytes())?;
+    file.flush()?;
+
+    Ok(())
+}
+
+fn print_largest_packages(
+    tree: &PackageTree,
+    count: usize,
+    mut machine_output: Option<&mut MachineOutput>,
+) {
+    let mut by_size: Vec<&Package> = tree.nodes.iter().collect();
+    by_size.sort();
+
+    for (index, pkg) in by_size.into_iter().take(count).enumerate() {
+        match machine_output.as_deref_mut() {
+            Some(machine_output) => {
+                machine_output.push(format!("TOP{}_NAME", index + 1), &pkg.short_name);
+                machine_output.push(format!("TOP{}_SIZE", index + 1), pkg.size_bytes);
+            }
+            None => println!("{}\t{}", pkg.size_bytes, pkg.short_name),
+        }
+    }
+}
+
+fn dispatch_subcommand(args: Args) -> GenericResult<AnalysisOutcome> {
+    let mut machine_output = args.machine_readable.then(MachineOutput::default);
+    let json_layout = JsonLayout {
+        pretty: args.json_pretty,
+        indent: args.json_indent,
+    };
+
+    let nix = match &args.command {
+        None => &args.analyze.nix,
+        Some(Subcommand::Analyze(analyze_args)) => &analyze_args.nix,
+        Some(
+            Subcommand::Compare { nix, .. }
+            | Subcommand::Stats { nix, .. }
+            | Subcommand::Find { nix, .. }
+            | Subcommand::Path { nix, .. }
+            | Subcommand::Why { nix, .. }
+            | Subcommand::Largest { nix, .. },
+        ) => nix,
+    };
+    nix.init_globals();
+
+    let Some(command) = args.command else {
+        return analyze(args.analyze, machine_output, json_layout);
+    };
+
+    match command {
+        Subcommand::Analyze(analyze_args) => {
+            return analyze(*analyze_args, machine_output, json_layout)
+        }
+        Subcommand::Compare {
+            first_store_path,
+            second_store_path,
+            nix,
+        } => {
+            let nix_extra_args = nix.split_extra_args()?;
+            let first = load_tree(&first_store_path, &nix_extra_args)?;
+            let second = load_tree(&second_store_path, &nix_extra_args)?;
+            print_closure_comparison(&first, &second, machine_output.as_mut());
+        }
+        Subcommand::Stats {
+            store_path,
+            json,
+            include_indirect_only,
+            nix,
+        } => {
+            let tree = load_tree(&store_path, &nix.split_extra_args()?)?;
+            if json {
+                json_layout.print(&stats_json(&tree)?);
+                return Ok(AnalysisOutcome::Done);
+            }
+
+            print_stats(&tree, include_indirect_only, machine_output.as_mut());
+        }
+        Subcommand::Find {
+            store_path,
+            pattern,
+            nix,
+        } => {
+            let tree = load_tree(&store_path, &nix.split_extra_args()?)?;
+            let found = tree.nodes.iter().filter(|pkg| pkg.path.contains(&pattern));
+
+            match machine_output.as_mut() {
+                Some(machine_output) => {
+                    machine_output.push_array("FOUND", found.map(|pkg| &pkg.path))
+                }
+                None => {
+                    for pkg in found {
+                        println!("{}\t{}\t{}", pkg.short_name, pkg.size_bytes, pkg.path);
+                    }
+                }
+            }
+        }
+        Subcommand::Path {
+            store_path,
+            from,
+            to,
+            nix,
+        } => {
+            let tree = load_tree(&store_path, &nix.split_extra_args()?)?;
+            print_shortest_path(&tree, &from, &to, machine_output.as_mut())?;
+        }
+        Subcommand::Why {
+            store_path,
+            package,
+            nix,
+        } => {
+            let tree = load_tree(&store_path, &nix.split_extra_args()?)?;
+            print_why(&tree, &package, machine_output.as_mut())?;
+        }
+        Subcommand::Largest {
+            store_path,
+            count,
+            nix,
+        } => {
+            let tree = load_tree(&store_path, &nix.split_extra_args()?)?;
+            print_largest_packages(&tree, count, machine_output.as_mut());
+        }
+    }
+
+    if let Some(machine_output) = machine_output {
+        machine_output.print();
+    }
+
+    Ok(AnalysisOutcome::Done)
+}
+
+/// Asks `question` on stderr and reads the answer from stdin, returning `default` if the answer is empty.
+fn prompt(question: &str, default: &str) -> GenericResult<String> {
+    if default.is_empty() {
+        eprint!("{}: ", question);
+    } else {
+        eprint!("{} [{}]: ", question, default);
+    }
+
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    let answer = answer.trim();
+    Ok(if answer.is_empty() { default } else { answer }.to_string())
+}
+
+/// Asks a yes/no question on stderr, defaulting to no.
+fn prompt_yes_no(question: &str) -> GenericResult<bool> {
+    Ok(prompt(&format!("{} (y/N)", question), "")?.eq_ignore_ascii_case("y"))
+}
+
+/// Guides the user through choosing the outputs and some analysis options with prompts on stderr, for `--interactive-select`.
+/// Only asks about what wasn't already given as flags, and prints the flags that would give the same result so they can be reused.
+fn interactive_configure(mut args: AnalyzeArgs) -> GenericResult<AnalyzeArgs> {
+    args.interactive_select = false;
+    let mut equivalent_flags: Vec<String> = Vec::new();
+
+    if args.output.is_empty() && args.legacy_outputs().is_empty() {
+        let formats = OutputFormat::value_variants();
+        eprintln!("Output formats:");
+        for (index, format) in formats.iter().enumerate() {
+            if let Some(value) = format.to_possible_value() {
+                eprintln!("  {}) {}", index + 1, value.get_name());
+            }
+        }
+
+        let selection = prompt("Formats to generate (comma-separated numbers)", "1")?;
+        for choice in selection.split(',').map(str::trim) {
+            let format = choice
+                .parse::<usize>()
+                .ok()
+                .and_then(|number| formats.get(number.checked_sub(1)?))
+                .ok_or_else(|| format!("'{}' isn't one of the output formats", choice))?;
+            let name = format
+                .to_possible_value()
+                .map(|value| value.get_name().to_string())
+                .unwrap_or_default();
+            let (extension, _) = format.generated_files(Path::new(""))[0];
+            let path = prompt(
+                &format!("Path to save the {} output to", name),
+                &format!("closure.{}", extension),
+            )?;
+
+            equivalent_flags.push(format!("--output {}:{}", name, path));
+            args.output.push(OutputSpec {
+                format: *format,
+                path: PathBuf::from(path),
+            });
+        }
+    }
+
+    let generates_dot = args
+        .output
+        .iter()
+        .chain(args.legacy_outputs().iter())
+        .any(|output| output.format == OutputFormat::Dot);
+    if generates_dot && !args.color_by_level && !args.color_by_age {
+        args.color_by_level = prompt_yes_no("Colour the dot file by level?")?;
+        if args.color_by_level {
+            equivalent_flags.push("--color-by-level".to_string());
+        }
+    }
+    if !args.critical_path {
+        args.critical_path = prompt_yes_no("Print the largest dependency chain?")?;
+        if args.critical_path {
+            equivalent_flags.push("--critical-path".to_string());
+        }
+    }
+
+    if !equivalent_flags.is_empty() {
+        eprintln!("Equivalent flags: {}", equivalent_flags.join(" "));
+    }
+
+    Ok(args)
+}
+
+/// Whether `binary` is an executable file in one of the directories in `PATH`.
+fn is_on_path(binary: &str) -> bool {
+    let Some(path) = std::env::var_os("PATH") else {
+        return false;
+    };
+
+    std::env::split_paths(&path).any(|dir| {
+        dir.join(binary).metadata().is_ok_and(|metadata| {
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+
+                metadata.is_file() && metadata.permissions().mode() & 0o111 != 0
+            }
+            #[cfg(not(unix))]
+            {
+                metadata.is_file()
+            }
+        })
+    })
+}
+
+/// What `--watch` compares to detect a new build: where the store path points to, and when it (or the symlink to it) was last modified.
+fn watched_state(store_path: &Path) -> Option<(PathBuf, SystemTime, SystemTime)> {
+    Some((
+        std::fs::canonicalize(store_path).ok()?,
+        std::fs::symlink_metadata(store_path)
+            .ok()?
+            .modified()
+            .ok()?,
+        std::fs::metadata(store_path).ok()?.modified().ok()?,
+    ))
+}
+
+/// Analyzes the store path every time it changes, until the process is interrupted.
+/// Errors (e.g. the store path disappearing while a new build replaces it) are logged, and the next change is waited for.
+fn watch(
+    args: AnalyzeArgs,
+    machine_readable: bool,
+    json_layout: JsonLayout,
+) -> GenericResult<AnalysisOutcome> {
+    let store_path = args
+        .store_path
+        .clone()
+        .ok_or("--watch needs a store path")?;
+
+    let mut run_args = args.clone();
+    run_args.watch = false;
+    if args.watch_render {
+        let engine = args.dot_engine.unwrap_or(DotEngine::Dot);
+        if is_on_path(engine.binary_name()) {
+            run_args.render = true;
+            run_args.render_format = RenderFormat::Svg;
+        } else {
+            tracing::warn!(
+                "'{}' isn't in PATH, the dot file won't be rendered",
+                engine.binary_name()
+            );
+        }
+    }
+
+    let mut last_state = None;
+    loop {
+        let state = watched_state(&store_path);
+        if state.is_some() && state != last_state {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            eprintln!("[{}] Re-analyzing...", unix_timestamp_to_iso8601(now));
+
+            if let Err(error) = analyze(
+                run_args.clone(),
+                machine_readable.then(MachineOutput::default),
+                json_layout,
+            ) {
+                tracing::error!("{}", error);
+            }
+            last_state = state;
+        }
+
+        std::thread::sleep(Duration::from_secs(args.watch_interval));
+    }
+}
+
+/// How an analysis that didn't fail ended, so `main` can pick the exit code.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum AnalysisOutcome {
+    Done,
+    // The `--warn-depth` warning was printed with `--fail-on-warn`, which exits with code 3.
+    DepthWarning,
+}
+
+impl AnalysisOutcome {
+    fn new(depth_warning: bool, args: &AnalyzeArgs) -> Self {
+        if depth_warning && args.fail_on_warn {
+            AnalysisOutcome::DepthWarning
+        } else {
+            AnalysisOutcome::Done
+        }
+    }
+}
+
+/// Warns with the dependency chains of the deepest packages if the closure has at least `warn_depth` levels, returning whether it did.
+/// Looks at the level of every package instead of `by_level`, so it also works on trees that skipped `calculate_graph_properties`.
+fn warn_about_depth(tree: &PackageTree, warn_depth: Option<usize>) -> bool {
+    let max_level = tree.nodes.iter().map(|pkg| pkg.level).max().unwrap_or(0);
+    if warn_depth.is_none_or(|warn_depth| max_level < warn_depth) {
+        return false;
+    }
+
+    tracing::warn!(
+        "the closure is {} levels deep, the deepest packages are:",
+        max_level
+    );
+    for pos in tree
+        .deepest_packages(5)
+        .into_iter()
+        .filter(|&pos| tree.package(pos).level == max_level)
+    {
+        tracing::warn!(
+            "  {}",
+            format_package_path(tree, &tree.longest_chain_to(pos))
+        );
+    }
+
+    true
+}
+
+fn analyze(
+    args: AnalyzeArgs,
+    mut machine_output: Option<MachineOutput>,
+    json_layout: JsonLayout,
+) -> GenericResult<AnalysisOutcome> {
+    // Prompts can't be answered without a terminal, e.g. when running in CI.
+    let args = if args.interactive_select && std::io::stderr().is_terminal() {
+        interactive_configure(args)?
+    } else {
+        args
+    };
+
+    if args.watch {
+        return watch(args, machine_output.is_some(), json_layout);
+    }
+
+    let analysis_start = Instant::now();
+    let _benchmark = args.benchmark.then(BenchmarkReport::start);
+    let _ = OUTPUT_ENCODING.set(args.output_encoding);
+    let nix_extra_args = args.nix.split_extra_args()?;
+
+    if args.all_profiles {
+        let profiles = find_all_profiles();
+        if profiles.is_empty() {
+            return Err("Couldn't find any nix profile on this system".into());
+        }
+
+        // Only which packages are in each profile matters, so sizes aren't queried.
+        let profiles = profiles
+            .into_iter()
+            .map(|(name, store_path)| {
+                Ok((name, load_tree_without_sizes(&store_path, &nix_extra_args)?))
+            })
+            .collect::<GenericResult<Vec<_>>>()?;
+        print_profiles_report(
+            &profiles,
+            args.report_markdown.as_deref(),
+            machine_output.as_mut(),
+        )?;
+        return Ok(AnalysisOutcome::Done);
+    }
+
+    if args.list_system_packages {
+        for name in SYSTEM_PACKAGES {
+            println!("{}", name);
+        }
+        return Ok(AnalysisOutcome::Done);
+    }
+
+    if args.print_env {
+        for name in ENV_VARS {
+            match std::env::var(name) {
+                Ok(value) => eprintln!("{}={}", name, value),
+                Err(_) => eprintln!("{} is not set", name),
+            }
+        }
+    }
+
+    let load_tree = if args.from_requisites {
+        load_tree_from_requisites
+    } else {
+        load_tree
+    };
+
+    // When focusing on a package, most of the closure is usually thrown away, so sizes are only queried for what's left.
+    // Some flags need the sizes of the whole closure before focusing, though.
+    let query_sizes_lazily = args.focus.is_some()
+        && !args.from_requisites
+        && !args.closure_size
+        && !args.byte_count_only;
+
+    let mut size_estimate = None;
+    let mut tree = if let Some(graph_path) = &args.from_graph {
+        load_tree_from_graph(graph_path, &nix_extra_args)?
+    } else if let Some(lock_path) = &args.from_flake_lock {
+        load_tree_from_flake_lock(lock_path, args.query_narsize, &nix_extra_args)?
+    } else {
+        let store_path = if args.self_analyze {
+            let own_store_path = get_own_store_path()?;
+            match machine_output.as_mut() {
+                Some(machine_output) => {
+                    machine_output.push("OWN_STORE_PATH", own_store_path.display())
+                }
+                None => println!("Analyzing own store path: {}", own_store_path.display()),
+            }
+            own_store_path
+        } else {
+            // clap makes sure we have a store path if we're not analyzing ourselves or reading a graph.
+            args.store_path.clone().unwrap()
+        };
+
+        if args.count_only {
+            let tree = parse_tree_output(&store_path, &nix_extra_args, &|path| {
+                Ok(Package::with_size(path, 0))
+            })?;
+            println!("{}", tree.nodes.len());
+            let depth_warning = warn_about_depth(&tree, args.warn_depth);
+            return Ok(AnalysisOutcome::new(depth_warning, &args));
+        }
+
+        match args.sample {
+            Some(sample_size) => {
+                let (tree, estimate) =
+                    load_sampled_tree(&store_path, &nix_extra_args, sample_size)?;
+                size_estimate = Some(estimate);
+                tree
+            }
+            None if query_sizes_lazily => load_tree_without_sizes(&store_path, &nix_extra_args)?,
+            None => load_tree(&store_path, &nix_extra_args)?,
+        }
+    };
+
+    if args.byte_count_only {
+        println!("{}", tree.sum_package_bytes());
+        let depth_warning = warn_about_depth(&tree, args.warn_depth);
+        return Ok(AnalysisOutcome::new(depth_warning, &args));
+    }
+
+    if args.include_build_time_deps {
+        add_build_time_deps(&mut tree, &nix_extra_args)?;
+        tree.calculate_graph_properties()?;
+    }
+
+    if args.check {
+        let mut issues = tree.check_consistency();
+        if args.validate_references {
+            let closure_size =
+                query_closure_size(&tree.package(0).path, "nix-store", &nix_extra_args)?;
+            if closure_size != tree.sum_package_bytes() {
+                issues.push(format!(
+                    "The closure size from 'nix-store --query --requisites' ({} bytes) doesn't match the size of the parsed tree ({} bytes)",
+                    closure_size,
+                    tree.sum_package_bytes()
+                ));
+            }
+        }
+
+        for issue in issues.iter() {
+            println!("{}", issue);
+        }
+        // A deep closure isn't an inconsistency, so it's only a warning, which exits with code 3 under `--fail-on-warn` like outside of `--check`.
+        let depth_warning = warn_about_depth(&tree, args.warn_depth);
+        if !issues.is_empty() {
+            return Err(format!("Found {} inconsistencies in the tree", issues.len()).into());
+        }
+
+        println!("The tree is consistent ({} packages)", tree.nodes.len());
+        return Ok(AnalysisOutcome::new(depth_warning, &args));
+    }
+
+    if let Some(estimate) = size_estimate {
+        match machine_output.as_mut() {
+            Some(machine_output) => {
+                machine_output.push("ESTIMATED_TOTAL_BYTES", estimate.total_bytes.round());
+                machine_output.push("ESTIMATED_MARGIN_BYTES", estimate.margin_bytes.round());
+                machine_output.push("SAMPLE_SIZE", estimate.sample_size);
+            }
+            None => println!(
+                "Estimated total size: {:.0} ± {:.0} bytes (based on {}-package sample with 95% CI)",
+                estimate.total_bytes, estimate.margin_bytes, estimate.sample_size
+            ),
+        }
+    }
+
+    if args.closure_size {
+        let closure_size = query_closure_size(&tree.package(0).path, "nix-store", &nix_extra_args)?;
+        match machine_output.as_mut() {
+            Some(machine_output) => machine_output.push("CLOSURE_SIZE", closure_size),
+            None => println!(
+                "Closure size from 'nix-store --query --requisites': {}",
+                closure_size
+            ),
+        }
+
+        if closure_size != tree.sum_package_bytes() {
+            tracing::warn!(
+                "the closure size from 'nix-store --query --requisites' ({} bytes) doesn't match the size of the parsed tree ({} bytes)",
+                closure_size,
+                tree.sum_package_bytes()
+            );
+        }
+    }
+
+    if let Some(names) = &args.path {
+        print_shortest_path(&tree, &names[0], &names[1], machine_output.as_mut())?;
+    }
+
+    if let Some(name) = &args.why {
+        print_why(&tree, name, machine_output.as_mut())?;
+    }
+
+    if let Some(name) = &args.focus {
+        let center = find_focus_package(&tree, name)?;
+        let keep = match args.focus_depth {
+            Some(radius) => tree.subgraph_within_radius(center, radius),
+            None => tree.focus_subgraph(center),
+        };
+
+        tree.reindex(&keep);
+        if query_sizes_lazily {
+            let positions: Vec<usize> = (0..tree.nodes.len()).collect();
+            tree.query_sizes_for(&positions, &nix_extra_args)?;
+        }
+        tree.calculate_graph_properties()?;
+    }
+
+    if args.ignore_system_packages {
+        let names: Vec<String> = SYSTEM_PACKAGES
+            .iter()
+            .map(|name| name.to_string())
+            .chain(args.system_package.iter().cloned())
+            .collect();
+        let system_packages: HashSet<usize> = tree
+            .nodes
+            .iter()
+            .enumerate()
+            // The root is never ignored, since that would leave nothing to analyze.
+            .skip(1)
+            .filter(|(_, pkg)| is_system_package(pkg, &names))
+            .map(|(pos, _)| pos)
+            .collect();
+
+        let collapsed_count = tree.collapse_packages(&system_packages, "(system)");
+        tracing::info!(
+            "collapsed {} system packages (and packages only they depend on) into '(system)'",
+            collapsed_count
+        );
+        tree.calculate_graph_properties()?;
+    }
+
+    if let Some(previous_store_path) = &args.color_new_packages {
+        // Only the paths of the reference closure are compared, so its sizes aren't queried.
+        let previous_tree = load_tree_without_sizes(previous_store_path, &nix_extra_args)?;
+        let previous_paths: HashSet<&str> = previous_tree
+            .nodes
+            .iter()
+            .map(|pkg| pkg.path.as_str())
+            .collect();
+
+        for pkg in tree.nodes.iter_mut() {
+            pkg.is_new = !previous_paths.contains(pkg.path.as_str());
+        }
+    }
+
+    if args.include_registration_time {
+        for pkg in tree.nodes.iter_mut() {
+            pkg.query_registration_time(&nix_extra_args)?;
+        }
+    }
+
+    if args.include_nar_size {
+        query_download_sizes(&mut tree, &nix_extra_args)?;
+    }
+
+    if args.anonymize {
+        tree.anonymize(args.seed);
+    }
+
+    let high_fanout_packages = tree.high_fanout_packages(args.warn_fanout);
+    for &(pos, fanout_count) in high_fanout_packages.iter() {
+        let pkg = tree.package(pos);
+        tracing::warn!(
+            "{} has {} direct dependencies: {}",
+            pkg.short_name,
+            fanout_count,
+            pkg.dependencies
+                .iter()
+                .map(|&dep| tree.package(dep).short_name.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+
+    let depth_warning = warn_about_depth(&tree, args.warn_depth);
+
+    if let Some(count) = args.hot_packages {
+        print_hot_packages(&tree, count, machine_output.as_mut());
+    }
+
+    if let Some(count) = args.top_influence {
+        print_top_influence(&tree, count, machine_output.as_mut());
+    }
+
+    if let Some(count) = args.top_closure {
+        print_top_closure(&tree, count, machine_output.as_mut());
+    }
+
+    if args.show_only_new {
+        print_new_packages(&tree, machine_output.as_mut());
+    }
+
+    if args.critical_path {
+        let critical_path = tree.critical_path();
+        let critical_path_bytes: usize = critical_path
+            .iter()
+            .map(|&pos| tree.package(pos).size_bytes)
+            .sum();
+
+        match machine_output.as_mut() {
+            Some(machine_output) => {
+                machine_output.push("CRITICAL_PATH", format_package_path(&tree, &critical_path));
+                machine_output.push("CRITICAL_PATH_BYTES", critical_path_bytes);
+            }
+            None => println!(
+                "Critical path ({} bytes): {}",
+                critical_path_bytes,
+                format_package_path(&tree, &critical_path)
+            ),
+        }
+    }
+
+    if args.audit {
+        let audit_config = match &args.audit_config {
+            Some(config_path) => AuditConfig::from_toml(&std::fs::read_to_string(config_path)?)?,
+            None => AuditConfig::default(),
+        };
+        print_audit(&tree, &audit_config, machine_output.as_mut());
+    }
+
+    if args.print_level_distribution {
+        print_level_distribution(&tree, machine_output.as_mut());
+    }
+
+    if args.spf_report {
+        let mut articulation_points = tree.find_articulation_points();
+        articulation_points.sort_by_key(|&pos| std::cmp::Reverse(tree.package(pos).used_by.len()));
+
+        match machine_output.as_mut() {
+            Some(machine_output) => {
+                machine_output.push("SPF_COUNT", articulation_points.len());
+                machine_output.push_array(
+                    "SPF",
+                    articulation_points
+                        .iter()
+                        .map(|&pos| &tree.package(pos).short_name),
+                );
+            }
+            None => {
+                println!(
+                    "{} packages are single points of failure (removing them disconnects other packages from the root):",
+                    articulation_points.len()
+                );
+                for pos in articulation_points {
+                    let pkg = tree.package(pos);
+                    println!(
+                        "  {} (used by {} packages)",
+                        pkg.short_name,
+                        pkg.used_by.len()
+                    );
+                }
+            }
+        }
+    }
+
+    if args.deduplication_hints {
+        print_deduplication_hints(
+            &tree,
+            args.json.then_some(json_layout),
+            machine_output.as_mut(),
+        );
+    }
+
+    // Only used for the dot file, all other outputs still get the whole tree.
+    let mut reachable_tree = None;
+    if let Some(name) = &args.reachable_from {
+        let start = find_focus_package(&tree, name)?;
+        let mut reachable: Vec<usize> = tree.reachable_from(start).into_iter().collect();
+        reachable.sort_by_key(|&pos| std::cmp::Reverse(tree.package(pos).size_bytes));
+        let reachable_bytes: usize = reachable
+            .iter()
+            .map(|&pos| tree.package(pos).size_bytes)
+            .sum();
+
+        match machine_output.as_mut() {
+            Some(machine_output) => {
+                machine_output.push("REACHABLE_COUNT", reachable.len());
+                machine_output.push("REACHABLE_BYTES", reachable_bytes);
+                machine_output.push_array(
+                    "REACHABLE",
+                    reachable.iter().map(|&pos| &tree.package(pos).short_name),
+                );
+            }
+            None => {
+                println!(
+                    "{} packages ({} bytes) are reachable from {}:",
+                    reachable.len(),
+                    reachable_bytes,
+                    tree.package(start).short_name
+                );
+                for &pos in reachable.iter() {
+                    let pkg = tree.package(pos);
+                    println!("  {} ({} B)", pkg.short_name, pkg.size_bytes);
+                }
+            }
+        }
+
+        let mut subtree = tree.induced_subtree(&reachable.into_iter().collect());
+        subtree.calculate_graph_properties()?;
+        reachable_tree = Some(subtree);
+    }
+
+    if args.validate_references {
+        for error in validate_tree_against_references(&tree, "nix-store", &nix_extra_args)? {
+            tracing::warn!("{}", error.describe(&tree));
+        }
+    }
+
+    if args.verify {
+        for error in
+            tree.verify_against_store(args.verify_sample_size, "nix-store", &nix_extra_args)?
+        {
+            tracing::warn!("{}", error.describe());
+        }
+    }
+
+    let mut outputs = args.output.clone();
+    outputs.extend(args.legacy_outputs());
+
+    let has_dot_output = outputs
+        .iter()
+        .any(|output| output.format == OutputFormat::Dot);
+    if args.render && !has_dot_output {
+        return Err("--render needs a dot file, use --output dot:<path> to generate one".into());
+    }
+
+    let mut zip_output = args.zip_output.as_ref().map(|_| ZipOutput::new(&tree));
+
+    for OutputSpec { format, path } in outputs.iter() {
+        let _phase = PhaseSpan::enter(Phase::Generate(*format));
+        match format {
+            OutputFormat::Dot => {
+                let dot_tree = reachable_tree.as_ref().unwrap_or(&tree);
+
+                let estimated_dot_size = estimate_dot_size_bytes(dot_tree);
+                if !args.force && estimated_dot_size > args.max_dot_size {
+                    tracing::warn!(
+                        "the dot file is estimated to be around {} bytes, which graphviz may struggle with. Consider using --focus and --focus-depth to reduce the graph size, or --force to skip this check.",
+                        estimated_dot_size
+                    );
+                }
+                if args
+                    .dot_max_label_len
+                    .is_some_and(|max_label_len| max_label_len < 10)
+                {
+                    tracing::warn!("labels truncated to less than 10 characters may be unreadable");
+                }
+
+                let dot_options = DotOptions {
+                    color_by_level: args.color_by_level,
+                    color_by_age: args.color_by_age,
+                    node_url: args.dot_node_url.clone(),
+                    tooltip: args.dot_tooltip,
+                    group_by_name: args.group_by_name,
+                    package_set_prefixes: args
+                        .dot_cluster_by_prefix
+                        .then(|| args.package_set_prefixes.clone()),
+                    subgraph_style: if args.dot_no_rank
+                        || dot_tree.nodes.len() < args.dot_invisible_threshold
+                    {
+                        DotSubgraphStyle::None
+                    } else {
+                        args.dot_subgraph_style
+                    },
+                    trim_common_prefix: args.trim_common_prefix,
+                    gray_existing: args.show_only_new,
+                    label_template: args.dot_label_template.clone(),
+                    max_used_by: args.max_used_by,
+                    max_label_len: args.dot_max_label_len,
+                    level_labels: args.level_labels,
+                    size_by_influence: args.size_by_influence,
+                    concentrate: args.dot_concentrate,
+                    nodesep: args.dot_nodesep,
+                    ranksep: args.dot_ranksep,
+                    normalize_sizes: args.normalize_sizes,
+                    edge_labels: args.dot_edge_labels,
+                    background_color: args
+                        .dot_background_color
+                        .clone()
+                        .or_else(|| args.dot_dark_mode.then(|| "#1e1e1e".to_string())),
+                    font_color: args
+                        .dot_font_color
+                        .clone()
+                        .or_else(|| args.dot_dark_mode.then(|| "#d4d4d4".to_string())),
+                    edge_color: args
+                        .dot_edge_color
+                        .clone()
+                        .or_else(|| args.dot_dark_mode.then(|| "#808080".to_string())),
+                    dark_mode: args.dot_dark_mode,
+                    highlighted_edges: if args.critical_path {
+                        dot_tree
+                            .critical_path()
+                            .windows(2)
+                            .map(|edge| (edge[0], edge[1]))
+                            .collect()
+                    } else {
+                        HashSet::new()
+                    },
+                };
+                {
+                    let _span = tracing::info_span!("write_dot").entered();
+                    generate_dot_file(dot_tree, path, &dot_options)?;
+                }
+
+                if let Some(engine) = args.dot_engine {
+                    add_dot_engine_comment(path, engine)?;
+                }
+
+                if args.split_by_level {
+                    for level in 0..dot_tree.by_level.len() {
+                        generate_dot_file_for_level(
+                            dot_tree,
+                            level,
+                            &level_dot_file_path(path, level),
+                        )?;
+                    }
+                }
+
+                if args.render {
+                    let render_output = args
+                        .render_output
+                        .clone()
+                        .unwrap_or_else(|| path.with_extension(args.render_format.extension()));
+                    render_dot_file(
+                        path,
+                        args.dot_engine.unwrap_or(DotEngine::Dot),
+                        args.render_format,
+                        &render_output,
+                    )?;
+                }
+            }
+            OutputFormat::Csv => generate_package_list(
+                &tree,
+                path,
+                args.warn_fanout,
+                &args.csv_extra_columns,
+                &CsvOptions {
+                    bom: args.csv_bom,
+                    crlf: args.csv_crlf,
+                    timestamp_column: args.csv_append.then_some(args.csv_timestamp_col.as_str()),
+                    all_unique_contributions: args.show_shared_pct,
+                },
+            )?,
+            OutputFormat::Cytoscape => {
+                generate_cytoscape_file(&tree, path, json_layout.indent(false))?
+            }
+            OutputFormat::Toml => generate_toml_file(&tree, path)?,
+            OutputFormat::Sarif => generate_sarif_file(
+                &tree,
+                path,
+                args.warn_size,
+                args.warn_fanout,
+                json_layout.indent(false),
+            )?,
+            OutputFormat::Newick => generate_newick_file(&tree, path)?,
+            OutputFormat::Tags => generate_tags_file(&tree, path)?,
+            OutputFormat::D3Tree => generate_d3_tree_file(&tree, path)?,
+            OutputFormat::Markdown => generate_markdown_report(&tree, path)?,
+            OutputFormat::Sigma => generate_sigma_file(&tree, path)?,
+            OutputFormat::NixExpr => generate_nix_expr(&tree, path)?,
+        }
+
+        if let Some(zip_output) = zip_output.as_mut() {
+            for (extension, generated_path) in format.generated_files(path) {
+                zip_output.add(
+                    &tree.package(0).short_name,
+                    extension,
+                    std::fs::read(generated_path)?,
+                );
+            }
+        }
+    }
+
+    if let (Some(zip_output), Some(zip_path)) = (zip_output, &args.zip_output) {
+        zip_output.write(zip_path)?;
+    }
+
+    if args.print_stats_json {
+        json_layout.print(&stats_json(&tree)?);
+        return Ok(AnalysisOutcome::new(depth_warning, &args));
+    }
+
+    let age_summaries = [
+        ("Oldest", "OLDEST", tree.oldest_package()),
+        ("Newest", "NEWEST", tree.newest_package()),
+    ];
+
+    // Only packages the binary cache knows about are counted, otherwise the compression would look better than it is.
+    let total_download_bytes = args.include_nar_size.then(|| {
+        tree.nodes
+            .iter()
+            .filter_map(|pkg| pkg.download_size_bytes)
+            .sum::<usize>()
+    });
+
+    let timed_out_packages = TIMED_OUT_SIZE_QUERIES.lock().unwrap().len();
+
+    if let Some(mut machine_output) = machine_output {
+        machine_output.push("TOTAL_BYTES", tree.sum_package_bytes());
+        if let Some(download_bytes) = total_download_bytes {
+            machine_output.push("TOTAL_DOWNLOAD_BYTES", download_bytes);
+        }
+        machine_output.push("HIGH_FANOUT_PACKAGES", high_fanout_packages.len());
+        machine_output.push("FANOUT_THRESHOLD", args.warn_fanout);
+        machine_output.push("TIMED_OUT_PACKAGES", timed_out_packages);
+        if args.warn_depth.is_some() {
+            machine_output.push("DEPTH_WARNING", depth_warning as u8);
+        }
+
+        for (_, key, pos) in age_summaries {
+            if let Some(pos) = pos {
+                let pkg = tree.package(pos);
+                machine_output.push(format!("{}_NAME", key), &pkg.short_name);
+                machine_output.push(
+                    format!("{}_AGE_DAYS", key),
+                    pkg.age().unwrap_or_default().as_secs() / 86400,
+                );
+            }
+        }
+
+        machine_output.print();
+        return Ok(AnalysisOutcome::new(depth_warning, &args));
+    }
+
+    if args.normalize_sizes {
+        println!(
+            "All sizes shown as percentage of total closure size ({} bytes)",
+            tree.sum_package_bytes()
+        );
+    }
+    let summary = tree.summary();
+    for template in args.print_format.iter() {
+        println!(
+            "{}",
+            format_summary_line(
+                template,
+                &summary,
+                &tree.package(0).short_name,
+                analysis_start.elapsed()
+            )
+        );
+    }
+    if let Some(download_bytes) = total_download_bytes {
+        let installed_bytes: usize = tree
+            .nodes
+            .iter()
+            .filter(|pkg| pkg.download_size_bytes.is_some())
+            .map(|pkg| pkg.size_bytes)
+            .sum();
+        println!(
+            "Total installed: {}, Total download: {} ({:.0}% compression)",
+            format_size_human(installed_bytes),
+            format_size_human(download_bytes),
+            100.0 * (1.0 - download_bytes as f64 / installed_bytes.max(1) as f64)
+        );
+    }
+    println!(
+        "{} packages have unusually high fanout (>= {} direct dependencies)",
+        high_fanout_packages.len(),
+        args.warn_fanout
+    );
+    if timed_out_packages > 0 {
+        println!(
+            "{} packages timed out during size query.",
+            timed_out_packages
+        );
+    }
+
+    for (description, _, pos) in age_summaries {
+        if let Some(pos) = pos {
+            let pkg = tree.package(pos);
+            println!(
+                "{} package: {} (registered {} days ago)",
+                description,
+                pkg.short_name,
+                pkg.age().unwrap_or_default().as_secs() / 86400
+            );
+        }
+    }
+
+    Ok(AnalysisOutcome::new(depth_warning, &args))
+}
+
+fn main() -> ExitCode {
+    // Only warnings and errors are logged unless `RUST_LOG` says otherwise, and every span logs how long it took when it closes.
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            tracing_subscriber::EnvFilter::builder()
+                .with_default_directive(tracing::level_filters::LevelFilter::WARN.into())
+                .from_env_lossy(),
+        )
+        .with_span_events(tracing_subscriber::fmt::format::FmtSpan::CLOSE)
+        .with_writer(std::io::stderr)
+        .with_ansi(std::io::stderr().is_terminal())
+        .with_target(false)
+        // Not `without_time`, which also drops the span timings.
+        .with_timer(())
+        .init();
+
+    // Exit codes are returned instead of calling `std::process::exit`, which would skip destructors like the one printing the `--benchmark` report.
+    match dispatch_subcommand(Args::parse()) {
+        Ok(AnalysisOutcome::Done) => ExitCode::SUCCESS,
+        Ok(AnalysisOutcome::DepthWarning) => ExitCode::from(3),
+        Err(error) => {
+            eprintln!("Error: {:?}", error);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A path in the temporary directory that's unique to this process and `name`.
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("nix-tree-sizes-{}-{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn toml_output_round_trips() {
+        let tree = PackageTree::builder()
+            .root("pkg-a", 1000)
+            .depends_on("pkg-a", "pkg-b", 500)
+            .depends_on("pkg-a", "pkg-\"quoted\"", 300)
+            .depends_on("pkg-b", "pkg-\"quoted\"", 300)
+            .build()
+            .unwrap();
+        let path = temp_path("round-trip.toml");
+        generate_toml_file(&tree, &path).unwrap();
+        let output: TomlOutput = toml::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(output.metadata.total_packages, 3);
+        assert_eq!(output.metadata.total_bytes, 1800);
+        assert_eq!(output.metadata.max_level, 2);
+
+        assert_eq!(output.packages.len(), tree.nodes.len());
+        for (read, pkg) in output.packages.iter().zip(tree.nodes.iter()) {
+            assert_eq!(read.path, pkg.path);
+            assert_eq!(read.short_name, pkg.short_name);
+            assert_eq!(read.size_bytes, pkg.size_bytes);
+            assert_eq!(read.level, pkg.level);
+            assert_eq!(read.dependencies, pkg.dependencies);
+            assert_eq!(read.used_by, pkg.used_by);
+        }
+    }
+
+    #[test]
+    fn ascii_toml_output_only_uses_escapes() {
+        let document = toml::to_string(&TomlMetadata {
+            total_packages: 1,
+            total_bytes: 0,
+            max_level: 0,
+        })
+        .unwrap()
+            + "quoted = 'café \"😀\"'\nplain = \"café\"\n";
+        let escaped = toml_ascii_only(&document).unwrap();
+        assert!(escaped.is_ascii());
+        assert!(escaped.contains("plain = \"caf\\u00e9\""));
+
+        let table: toml::Table = toml::from_str(&escaped).unwrap();
+        assert_eq!(table["quoted"].as_str(), Some("café \"😀\""));
+        assert_eq!(table["plain"].as_str(), Some("café"));
+        assert_eq!(table["total_packages"].as_integer(), Some(1));
+    }
+
+    #[test]
+    fn display_shows_an_indented_tree() {
+        let tree = PackageTree::builder()
+            .root("pkg-a", 1000)
+            .depends_on("pkg-a", "pkg-c", 200)
+            .depends_on("pkg-a", "pkg-b", 500)
+            .depends_on("pkg-b", "pkg-c", 200)
+            .build()
+            .unwrap();
+
+        assert_eq!(
+            tree.to_string(),
+            "pkg-a (1000 B)\n  pkg-b (500 B)\n    pkg-c (200 B)\n  pkg-c (*)\n"
+        );
+    }
+
+    #[test]
+    fn all_simple_paths_finds_both_sides_of_a_diamond() {
+        let tree = PackageTree::builder()
+            .root("pkg-a", 1000)
+            .depends_on("pkg-a", "pkg-b", 500)
+            .depends_on("pkg-a", "pkg-c", 300)
+            .depends_on("pkg-b", "pkg-d", 200)
+            .depends_on("pkg-c", "pkg-d", 200)
+            .build()
+            .unwrap();
+
+        let mut paths = tree.all_simple_paths(0, 3, 10);
+        paths.sort();
+        assert_eq!(paths, vec![vec![0, 1, 3], vec![0, 2, 3]]);
+        assert_eq!(tree.all_simple_paths(0, 3, 1).len(), 1);
+        assert_eq!(tree.all_simple_paths(3, 0, 10), Vec::<Vec<usize>>::new());
+    }
+
+    #[test]
+    fn single_package_tree_has_one_level() {
+        let mut tree = PackageTree::new(Package::with_size(
+            format!("/nix/store/{:032}-hello.txt", 0),
+            14,
+        ));
+        process_lines(&mut tree, 0, VecDeque::new(), &|path| {
+            Ok(Package::with_size(path, 0))
+        })
+        .unwrap();
+        tree.calculate_graph_properties().unwrap();
+
+        assert_eq!(tree.nodes.len(), 1);
+        assert_eq!(tree.by_level, vec![vec![0]]);
+        assert_eq!(tree.package(0).graph_size, 0.5);
+        assert_eq!(tree.sum_package_bytes(), 14);
+    }
+
+    #[test]
+    fn csv_formulas_follow_operator_precedence() {
+        let tree = PackageTree::builder()
+            .root("pkg-a", 1000)
+            .depends_on("pkg-a", "pkg-b", 500)
+            .depends_on("pkg-b", "pkg-c", 200)
+            .depends_on("pkg-a", "pkg-c", 200)
+            .build()
+            .unwrap();
+        let evaluate = |formula: &str, pos: usize| {
+            FormulaParser::parse(formula)
+                .unwrap()
+                .evaluate(&tree, tree.package(pos))
+        };
+
+        assert_eq!(evaluate("1 + 2 * 3", 0), 7.0);
+        assert_eq!(evaluate("(1 + 2) * 3", 0), 9.0);
+        assert_eq!(evaluate("10 - 4 - 3", 0), 3.0);
+        assert_eq!(evaluate("-2 * -3", 0), 6.0);
+        assert_eq!(evaluate("1 + 2 < 4", 0), 1.0);
+        assert_eq!(evaluate("size_bytes / 100", 1), 5.0);
+        assert_eq!(evaluate("fan_out", 0), 2.0);
+        assert_eq!(evaluate("fan_in", 2), 2.0);
+        assert_eq!(evaluate("level == max_level", 2), 1.0);
+        assert_eq!(evaluate("level != 0", 0), 0.0);
+    }
+
+    #[test]
+    fn invalid_csv_formulas_are_rejected() {
+        for formula in ["size", "1 +", "(1 + 2", "1 2", "1 $ 2", "1..2", "1 < 2 < 3"] {
+            assert!(
+                FormulaParser::parse(formula).is_err(),
+                "'{}' should be rejected",
+                formula
+            );
+        }
+
+        let column = parse_csv_extra_column("size_mb = size_bytes / 1048576").unwrap();
+        assert_eq!(column.name, "size_mb");
+        assert!(parse_csv_extra_column("=size_bytes").is_err());
+        assert!(parse_csv_extra_column("size_bytes").is_err());
+    }
+
+    #[test]
+    fn miscalculated_level_is_an_error() {
+        let mut tree = PackageTree::builder()
+            .root("pkg-a", 1000)
+            .depends_on("pkg-a", "pkg-b", 500)
+            .build()
+            .unwrap();
+
+        tree.package_mut(1).level = 5;
+        let error = tree.calculate_graph_properties().unwrap_err();
+        assert!(error.to_string().contains("is at level 5"));
+
+        // Would overflow when sizing `by_level` without the check.
+        tree.package_mut(1).level = usize::MAX;
+        assert!(tree.calculate_graph_properties().is_err());
+    }
+
+    #[test]
+    fn builder_assigns_positions_and_levels() {
+        let tree = PackageTree::builder()
+            .root("pkg-a", 1000)
+            .depends_on("pkg-a", "pkg-b", 500)
+            .depends_on("pkg-b", "pkg-c", 200)
+            .depends_on("pkg-a", "pkg-c", 200)
+            .build()
+            .unwrap();
+
+        assert_eq!(tree.nodes.len(), 3);
+        assert_eq!(tree.package(0).short_name, "pkg-a");
+        assert_eq!(tree.package(1).size_bytes, 500);
+        assert_eq!(tree.package(2).used_by, vec![1, 0]);
+        assert_eq!(tree.package(2).level, 2);
+        assert_eq!(tree.by_level, vec![vec![0], vec![1], vec![2]]);
+    }
+
+    #[test]
+    fn builder_rejects_undefined_parents() {
+        assert!(PackageTree::builder()
+            .root("pkg-a", 1000)
+            .depends_on("pkg-b", "pkg-c", 200)
+            .build()
+            .is_err());
+        assert!(PackageTree::builder()
+            .depends_on("pkg-a", "pkg-b", 500)
+            .build()
+            .is_err());
+    }
+
+    #[test]
+    fn articulation_points_disconnect_other_packages() {
+        // pkg-b is the only way to reach pkg-c, and the root is the only link between pkg-b and pkg-d.
+        let tree = PackageTree::builder()
+            .root("pkg-a", 1000)
+            .depends_on("pkg-a", "pkg-b", 500)
+            .depends_on("pkg-b", "pkg-c", 200)
+            .depends_on("pkg-a", "pkg-d", 100)
+            .build()
+            .unwrap();
+        assert_eq!(tree.find_articulation_points(), vec![0, 1]);
+
+        // Packages on the cycle can be avoided by going the other way around it, but pkg-d is the only way to reach pkg-e.
+        let tree = PackageTree::builder()
+            .root("pkg-a", 1000)
+            .depends_on("pkg-a", "pkg-b", 500)
+            .depends_on("pkg-a", "pkg-c", 300)
+            .depends_on("pkg-b", "pkg-d", 200)
+            .depends_on("pkg-c", "pkg-d", 200)
+            .depends_on("pkg-d", "pkg-e", 100)
+            .build()
+            .unwrap();
+        assert_eq!(tree.find_articulation_points(), vec![3]);
+    }
+
+    #[test]
+    fn packages_are_equal_by_path_and_ordered_by_size() {
+        let package = |pos: usize, size_bytes: usize| {
+            Package::with_size(format!("/nix/store/{:032}-pkg", pos), size_bytes)
+        };
+
+        // PartialEq and Eq only look at the path.
+        assert!(package(1, 100) == package(1, 200));
+        assert!(package(1, 100) != package(2, 100));
+
+        // Hash agrees with Eq, so a HashSet deduplicates by path.
+        let unique: HashSet<Package> = [package(1, 100), package(1, 200), package(2, 100)]
+            .into_iter()
+            .collect();
+        assert_eq!(unique.len(), 2);
+
+        // PartialOrd and Ord put the largest packages first.
+        let mut packages = [package(1, 100), package(2, 300), package(3, 200)];
+        packages.sort();
+        let sizes: Vec<usize> = packages.iter().map(|pkg| pkg.size_bytes).collect();
+        assert_eq!(sizes, vec![300, 200, 100]);
+        assert_eq!(
+            package(1, 100).partial_cmp(&package(2, 300)),
+            Some(std::cmp::Ordering::Greater)
+        );
+
+        // Same size compares as equal for Ord, so a BTreeSet refuses a different package with the same size.
+        let mut by_size = std::collections::BTreeSet::new();
+        assert!(by_size.insert(package(1, 100)));
+        assert!(!by_size.insert(package(2, 100)));
+    }
+
+    #[test]
+    fn reindex_compacts_the_arena_after_pruning() {
+        let mut tree = PackageTree::builder()
+            .root("pkg-a", 1000)
+            .depends_on("pkg-a", "pkg-b", 500)
+            .depends_on("pkg-a", "pkg-c", 300)
+            .depends_on("pkg-c", "pkg-d", 200)
+            .depends_on("pkg-b", "pkg-e", 100)
+            .depends_on("pkg-c", "pkg-e", 100)
+            .build()
+            .unwrap();
+
+        // Prunes pkg-c along with pkg-d, which is only reachable through it.
+        let keep: HashSet<usize> = (0..tree.nodes.len())
+            .filter(|&pos| !["pkg-c", "pkg-d"].contains(&tree.package(pos).short_name.as_str()))
+            .collect();
+        tree.reindex(&keep);
+        tree.calculate_graph_properties().unwrap();
+
+        let names: Vec<&str> = tree
+            .nodes
+            .iter()
+            .map(|pkg| pkg.short_name.as_str())
+            .collect();
+        assert_eq!(names, vec!["pkg-a", "pkg-b", "pkg-e"]);
+        assert_eq!(tree.package(0).dependencies, vec![1]);
+        assert_eq!(tree.package(1).dependencies, vec![2]);
+        assert_eq!(tree.package(2).used_by, vec![1]);
+        assert_eq!(tree.by_level, vec![vec![0], vec![1], vec![2]]);
+        assert_eq!(tree.find_path_pos(&tree.package(2).path), 2);
+    }
+
+    #[test]
+    fn nix_store_graph_is_parsed_from_its_node_ids() {
+        let graph = parse_nix_store_graph(
+            r##"digraph G {
+"aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-hello-2.12" [label = "hello-2.12", shape = box, style = filled, fillcolor = "#ff0000"];
+"bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-glibc-2.39" -> "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-hello-2.12" [color = "black"];
+"bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-glibc-2.39" [label = "glibc-2.39", shape = box, style = filled, fillcolor = "#ff0000"];
+"/nix/store/cccccccccccccccccccccccccccccccc-libidn2-2.3.7" -> "/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-glibc-2.39";
+}
+"##,
+        )
+        .unwrap();
+
+        assert_eq!(
+            graph.paths,
+            vec![
+                "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-hello-2.12",
+                "/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-glibc-2.39",
+                "/nix/store/cccccccccccccccccccccccccccccccc-libidn2-2.3.7",
+            ]
+        );
+        assert_eq!(
+            graph.edges,
+            vec![
+                (
+                    "/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-glibc-2.39".to_string(),
+                    "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-hello-2.12".to_string()
+                ),
+                (
+                    "/nix/store/cccccccccccccccccccccccccccccccc-libidn2-2.3.7".to_string(),
+                    "/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-glibc-2.39".to_string()
+                ),
+            ]
+        );
+
+        assert!(parse_nix_store_graph("digraph G {\nrankdir = LR;\n}\n").is_err());
+    }
+
+    #[test]
+    fn critical_path_is_the_heaviest_path_to_a_leaf() {
+        let tree = PackageTree::builder()
+            .root("pkg-a", 100)
+            .depends_on("pkg-a", "pkg-b", 500)
+            .depends_on("pkg-a", "pkg-c", 300)
+            .depends_on("pkg-b", "pkg-d", 10)
+            .depends_on("pkg-c", "pkg-d", 10)
+            .depends_on("pkg-c", "pkg-e", 400)
+            .build()
+            .unwrap();
+        // pkg-b is the largest dependency of the root, but the path through pkg-c and pkg-e is heavier.
+        assert_eq!(tree.critical_path(), vec![0, 2, 4]);
+
+        let tree = PackageTree::builder().root("pkg-a", 100).build().unwrap();
+        assert_eq!(tree.critical_path(), vec![0]);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn dot_file_can_be_written_to_a_fifo() {
+        let path = temp_path("graph.dot.fifo");
+        let _ = std::fs::remove_file(&path);
+        if !Command::new("mkfifo")
+            .arg(&path)
+            .status()
+            .is_ok_and(|status| status.success())
+        {
+            eprintln!("skipping, mkfifo isn't available");
+            return;
+        }
+        assert!(is_fifo(&path));
+
+        // Opening a fifo for writing blocks until something opens it for reading.
+        let reader = {
+            let path = path.clone();
+            std::thread::spawn(move || std::fs::read_to_string(path).unwrap())
+        };
+        let tree = PackageTree::builder()
+            .root("pkg-a", 1000)
+            .depends_on("pkg-a", "pkg-b", 500)
+            .build()
+            .unwrap();
+        generate_dot_file(&tree, &path, &DotOptions::default()).unwrap();
+        let contents = reader.join().unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(contents.starts_with("digraph"));
+        assert!(contents.trim_end().ends_with('}'));
+    }
+
+    #[test]
+    fn stratified_sample_takes_packages_from_every_level() {
+        let mut builder = PackageTree::builder().root("pkg-root", 1000);
+        for index in 0..8 {
+            builder = builder.depends_on("pkg-root", &format!("pkg-{}", index), 100);
+        }
+        let tree = builder
+            .depends_on("pkg-0", "pkg-deep", 100)
+            .build()
+            .unwrap();
+
+        let sample = tree.stratified_sample(5);
+        let sampled_levels: Vec<usize> = tree
+            .by_level
+            .iter()
+            .map(|positions| positions.iter().filter(|pos| sample.contains(pos)).count())
+            .collect();
+        // 5 of 10 packages, rounded up per level, and at least one from each level.
+        assert_eq!(sampled_levels, vec![1, 4, 1]);
+    }
+
+    #[test]
+    fn sample_estimate_uses_the_mean_of_each_level() {
+        let mut tree = PackageTree::builder()
+            .root("pkg-a", 1000)
+            .depends_on("pkg-a", "pkg-b", 100)
+            .depends_on("pkg-a", "pkg-c", 300)
+            .depends_on("pkg-a", "pkg-d", 0)
+            .depends_on("pkg-a", "pkg-e", 0)
+            .build()
+            .unwrap();
+
+        let estimate = estimate_from_sample(&mut tree, &[0, 1, 2]);
+
+        assert_eq!(estimate.sample_size, 3);
+        assert_eq!(estimate.total_bytes, 1000.0 + 4.0 * 200.0);
+        // Only the second level has a sample variance: 4² * (1 - 2/4) * 20000 / 2.
+        assert!((estimate.margin_bytes - 1.96 * 80000f64.sqrt()).abs() < 1e-9);
+        assert_eq!(tree.package(3).size_bytes, 200);
+        assert_eq!(tree.package(4).size_bytes, 200);
+        // Sampled packages keep their real size.
+        assert_eq!(tree.package(1).size_bytes, 100);
+    }
+
+    #[test]
+    fn store_paths_are_split_with_a_non_standard_prefix() {
+        let hash = "0123456789abcdfghijklmnpqrsvwxyz";
+
+        assert_eq!(
+            split_store_path_in(
+                &format!("/tmp/nix-store/{}-openssl-3.0.13", hash),
+                "/tmp/nix-store"
+            ),
+            (hash, "openssl-3.0.13")
+        );
+        assert_eq!(
+            split_store_path_in(
+                &format!("/tmp/nix-store/{}-openssl-3.0.13/bin/openssl", hash),
+                "/tmp/nix-store"
+            ),
+            (hash, "openssl-3.0.13")
+        );
+        // The prefix must match a whole directory, not just the start of one.
+        assert_ne!(
+            split_store_path_in(
+                &format!("/tmp/nix-store-2/{}-openssl-3.0.13", hash),
+                "/tmp/nix-store"
+            ),
+            (hash, "openssl-3.0.13")
+        );
+    }
+
+    #[test]
+    fn every_dot_subgraph_style_renders() {
+        if Command::new("dot").arg("-V").output().is_err() {
+            eprintln!("skipping, graphviz isn't available");
+            return;
+        }
+
+        let tree = PackageTree::builder()
+            .root("pkg-a", 1000)
+            .depends_on("pkg-a", "pkg-b", 500)
+            .depends_on("pkg-a", "pkg-c", 300)
+            .depends_on("pkg-b", "pkg-c", 300)
+            .build()
+            .unwrap();
+        for style in [
+            DotSubgraphStyle::Rank,
+            DotSubgraphStyle::Cluster,
+            DotSubgraphStyle::None,
+        ] {
+            let path = temp_path(&format!("subgraph-style-{:?}.dot", style));
+            let options = DotOptions {
+                subgraph_style: style,
+                ..Default::default()
+            };
+            generate_dot_file(&tree, &path, &options).unwrap();
+            let output = Command::new("dot")
+                .arg("-Tsvg")
+                .arg(&path)
+                .output()
+                .unwrap();
+            std::fs::remove_file(&path).unwrap();
+
+            assert!(output.status.success(), "dot failed for {:?}", style);
+            assert!(!output.stdout.is_empty(), "empty svg for {:?}", style);
+        }
+    }
+
+    #[test]
+    fn lru_cache_evicts_the_least_recently_used_entry() {
+        let mut cache = LruCache::new(2);
+        cache.put("a".to_string(), 1);
+        cache.put("b".to_string(), 2);
+        // Using "a" makes "b" the least recently used entry.
+        assert_eq!(cache.get("a"), Some(1));
+        cache.put("c".to_string(), 3);
+
+        assert_eq!(cache.get("b"), None);
+        assert_eq!(cache.get("a"), Some(1));
+        assert_eq!(cache.get("c"), Some(3));
+
+        // Updating an entry that's already there doesn't evict anything.
+        cache.put("c".to_string(), 4);
+        assert_eq!(cache.get("a"), Some(1));
+        assert_eq!(cache.get("c"), Some(4));
+    }
+
+    #[test]
+    fn toml_escape_never_produces_surrogates() {
+        assert_eq!(toml_escape("a\"b\\c\u{7f}", false), "a\\\"b\\\\c\\u007f");
+        assert_eq!(toml_escape("café 😀", false), "café 😀");
+        assert_eq!(toml_escape("café 😀", true), "caf\\u00e9 \\U0001f600");
+    }
+
+    fn tree_for_removal() -> PackageTree {
+        PackageTree::builder()
+            .root("pkg-a", 1000)
+            .depends_on("pkg-a", "pkg-b", 500)
+            .depends_on("pkg-b", "pkg-c", 200)
+            .depends_on("pkg-a", "pkg-d", 100)
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn remove_package_rewires_parents_to_dependencies() {
+        let mut tree = tree_for_removal();
+        tree.remove_package(1, RemoveMode::Rewire).unwrap();
+
+        let names: Vec<&str> = tree
+            .nodes
+            .iter()
+            .map(|pkg| pkg.short_name.as_str())
+            .collect();
+        assert_eq!(names, vec!["pkg-a", "pkg-c", "pkg-d"]);
+        assert_eq!(tree.package(0).dependencies, vec![1, 2]);
+        assert_eq!(tree.package(1).used_by, vec![0]);
+        assert_eq!(tree.package(1).level, 1);
+    }
+
+    #[test]
+    fn remove_package_disconnects_dependencies() {
+        let mut tree = tree_for_removal();
+        tree.remove_package(1, RemoveMode::Disconnect).unwrap();
+
+        let names: Vec<&str> = tree
+            .nodes
+            .iter()
+            .map(|pkg| pkg.short_name.as_str())
+            .collect();
+        assert_eq!(names, vec!["pkg-a", "pkg-c", "pkg-d"]);
+        assert_eq!(tree.package(0).dependencies, vec![2]);
+        assert!(tree.package(1).used_by.is_empty());
+        assert_eq!(tree.package(1).level, 0);
+    }
+
+    #[test]
+    fn remove_package_rejects_the_root_and_invalid_positions() {
+        let mut tree = tree_for_removal();
+        assert!(tree.remove_package(0, RemoveMode::Rewire).is_err());
+        assert!(tree.remove_package(4, RemoveMode::Disconnect).is_err());
+        assert_eq!(tree.nodes.len(), 4);
+    }
+
+    #[test]
+    fn dot_output_doesnt_depend_on_dependency_order() {
+        // Same packages and edges, with the dependencies registered in a different order, as when nix-store prints them in a different order.
+        let build = |order: [usize; 3]| {
+            let mut tree = PackageTree::new(Package::with_size(
+                format!("{}/{:032}-root", store_prefix(), 0),
+                1000,
+            ));
+            for name in ["libc", "lib", "app"] {
+                tree.add_package(Package::with_size(
+                    format!("{}/{:032}-{}", store_prefix(), tree.nodes.len(), name),
+                    100,
+                ));
+            }
+            for dep in order {
+                tree.register_dependency(0, dep);
+            }
+            for parent in order.into_iter().filter(|&pos| pos != 1) {
+                tree.register_dependency(parent, 1);
+            }
+            tree.recalculate_levels();
+            tree.calculate_graph_properties().unwrap();
+            tree
+        };
+
+        let mut outputs = Vec::new();
+        for (index, order) in [[1, 2, 3], [3, 2, 1]].into_iter().enumerate() {
+            let dot_path = temp_path(&format!("dependency-order-{}.dot", index));
+            generate_dot_file(&build(order), &dot_path, &DotOptions::default()).unwrap();
+            outputs.push(std::fs::read_to_string(&dot_path).unwrap());
+            std::fs::remove_file(&dot_path).unwrap();
+        }
+
+        assert_eq!(outputs[0], outputs[1]);
+    }
+
+    #[test]
+    fn long_labels_are_truncated() {
+        let name = "a".repeat(100);
+        assert_eq!(truncate_label(&name, 20), format!("{}...", "a".repeat(20)));
+        assert_eq!(truncate_label(&name, 100), name);
+        // Half of an escape sequence isn't left at the end.
+        assert_eq!(truncate_label("pkg\\nsize", 4), "pkg...");
+
+        let tree = PackageTree::builder()
+            .root("pkg-a", 1000)
+            .depends_on("pkg-a", &name, 500)
+            .build()
+            .unwrap();
+        let options = DotOptions {
+            label_template: "{name}".to_string(),
+            max_label_len: Some(20),
+            ..Default::default()
+        };
+        let path = temp_path("truncated-labels.dot");
+        generate_dot_file(&tree, &path, &options).unwrap();
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(contents.contains(&format!("label = \"{}...\"", "a".repeat(20))));
+        // The full name is kept in the tooltip.
+        assert!(contents.contains(&format!("tooltip = \"{}\"", name)));
+    }
+
+    #[test]
+    fn graph_shape_is_exact_for_small_trees() {
+        let tree = PackageTree::builder()
+            .root("pkg-a", 1000)
+            .depends_on("pkg-a", "pkg-b", 500)
+            .depends_on("pkg-b", "pkg-c", 200)
+            .depends_on("pkg-c", "pkg-d", 100)
+            .build()
+            .unwrap();
+
+        assert_eq!(
+            tree.graph_shape(),
+            GraphShape {
+                diameter: 3,
+                radius: 2,
+                center: vec![1, 2],
+            }
+        );
+    }
+
+    #[test]
+    fn graph_shape_approximation_is_deterministic() {
+        let mut builder = PackageTree::builder().root("pkg-0", 100);
+        for index in 1..=EXACT_ECCENTRICITY_MAX_NODES + 100 {
+            builder = builder.depends_on(
+                &format!("pkg-{}", index - 1),
+                &format!("pkg-{}", index),
+                100,
+            );
+        }
+        let tree = builder.build().unwrap();
+
+        let shape = tree.graph_shape();
+        assert_eq!(shape.diameter, EXACT_ECCENTRICITY_MAX_NODES + 100);
+        assert_eq!(shape, tree.graph_shape());
+    }
+
+    fn traversal_names(tree: &PackageTree, order: impl Iterator<Item = usize>) -> Vec<&str> {
+        order
+            .map(|pos| tree.package(pos).short_name.as_str())
+            .collect()
+    }
+
+    fn tree_for_traversal() -> PackageTree {
+        PackageTree::builder()
+            .root("pkg-a", 100)
+            .depends_on("pkg-a", "pkg-b", 100)
+            .depends_on("pkg-a", "pkg-d", 100)
+            .depends_on("pkg-b", "pkg-c", 100)
+            .depends_on("pkg-d", "pkg-c", 100)
+            .depends_on("pkg-d", "pkg-e", 100)
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn bfs_visits_each_level_before_the_next() {
+        let tree = tree_for_traversal();
+        let a = tree.find_by_exact_name("pkg-a").unwrap();
+        let c = tree.find_by_exact_name("pkg-c").unwrap();
+
+        assert_eq!(
+            traversal_names(&tree, tree.bfs_from(a)),
+            vec!["pkg-a", "pkg-b", "pkg-d", "pkg-c", "pkg-e"]
+        );
+        assert_eq!(
+            traversal_names(&tree, tree.reverse_bfs_from(c)),
+            vec!["pkg-c", "pkg-b", "pkg-d", "pkg-a"]
+        );
+
+        let d = tree.find_by_exact_name("pkg-d").unwrap();
+        assert_eq!(
+            traversal_names(&tree, tree.bfs_from(a).skipping([d])),
+            vec!["pkg-a", "pkg-b", "pkg-c"]
+        );
+    }
+
+    #[test]
+    fn bfs_visits_record_depth_and_parents() {
+        let tree = tree_for_traversal();
+        let [a, b, c, d, e] = ["pkg-a", "pkg-b", "pkg-c", "pkg-d", "pkg-e"]
+            .map(|name| tree.find_by_exact_name(name).unwrap());
+
+        let visits: Vec<(usize, usize, Option<usize>)> = tree
+            .bfs_from(a)
+            .visits()
+            .map(|visit| (visit.pos, visit.depth, visit.reached_from))
+            .collect();
+        assert_eq!(
+            visits,
+            vec![
+                (a, 0, None),
+                (b, 1, Some(a)),
+                (d, 1, Some(a)),
+                (c, 2, Some(b)),
+                (e, 2, Some(d))
+            ]
+        );
+
+        assert_eq!(
+            traversal_names(&tree, tree.undirected_bfs_from(e)),
+            vec!["pkg-e", "pkg-d", "pkg-c", "pkg-a", "pkg-b"]
+        );
+        assert_eq!(tree.subgraph_within_radius(e, 1), HashSet::from([e, d]));
+        assert_eq!(
+            tree.subgraph_within_radius(e, 2),
+            HashSet::from([e, d, c, a])
+        );
+    }
+
+    #[test]
+    fn shortest_path_follows_dependencies() {
+        let tree = tree_for_traversal();
+        let [a, c, d, e] =
+            ["pkg-a", "pkg-c", "pkg-d", "pkg-e"].map(|name| tree.find_by_exact_name(name).unwrap());
+
+        assert_eq!(tree.shortest_path_between(a, e), Some(vec![a, d, e]));
+        assert_eq!(tree.shortest_path_between(d, d), Some(vec![d]));
+        // Only `dependencies` edges are followed.
+        assert_eq!(tree.shortest_path_between(c, a), None);
+        assert_eq!(tree.shortest_path_between(e, c), None);
+    }
+
+    #[test]
+    fn nix_extra_args_keep_quoted_values() {
+        let args = Args::try_parse_from([
+            "nix-tree-sizes",
+            "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-root",
+            "--nix-extra-args",
+            "--option binary-caches \"\"",
+            "--nix-extra-args",
+            "--store 'ssh://my machine'",
+        ])
+        .unwrap();
+        assert_eq!(
+            args.analyze.nix.split_extra_args().unwrap(),
+            [
+                "--option",
+                "binary-caches",
+                "",
+                "--store",
+                "ssh://my machine"
+            ]
+        );
+
+        let args = Args::try_parse_from([
+            "nix-tree-sizes",
+            "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-root",
+            "--nix-extra-args",
+            "--option binary-caches \"",
+        ])
+        .unwrap();
+        assert!(args.analyze.nix.split_extra_args().is_err());
+    }
+
+    #[test]
+    fn stats_json_is_valid_json() {
+        let tree = PackageTree::builder()
+            .root("pkg-a", 1000)
+            .depends_on("pkg-a", "pkg-\"b\"", 500)
+            .depends_on("pkg-\"b\"", "pkg-c", 200)
+            .depends_on("pkg-a", "pkg-c", 200)
+            .build()
+            .unwrap();
+        let stats: serde_json::Value = serde_json::from_str(&stats_json(&tree).unwrap()).unwrap();
+
+        assert_eq!(stats["total_packages"], 3);
+        assert_eq!(stats["total_bytes"], 1700);
+        assert_eq!(stats["most_referenced_name"], "pkg-c");
+        assert_eq!(stats["most_referenced_fanin"], 2);
+        assert_eq!(stats["top_5_by_size"][1]["name"], "pkg-\"b\"");
+        assert_eq!(stats["top_5_by_size"][1]["bytes"], 500);
+        assert_eq!(stats["level_distribution"][2]["count"], 1);
+    }
+
+    #[test]
+    fn zip_output_lists_every_file_once() {
+        let mut zip_output = ZipOutput { files: Vec::new() };
+        zip_output.add("root", "dot", b"123456789".to_vec());
+        zip_output.add("root", "csv", b"a,b\n".to_vec());
+        zip_output.add("root", "dot", b"digraph {}".to_vec());
+
+        let mut archive =
+            zip::ZipArchive::new(std::io::Cursor::new(zip_output.to_bytes().unwrap())).unwrap();
+        let names: Vec<String> = archive
+            .file_names()
+            .map(|name| name.unwrap().to_string())
+            .collect();
+        assert_eq!(names, ["root.dot", "root.csv", "root-2.dot"]);
+
+        let mut file = archive.by_name("root.dot").unwrap();
+        // The standard check value of CRC-32.
+        assert_eq!(file.crc32(), 0xcbf43926);
+        let mut contents = String::new();
+        std::io::Read::read_to_string(&mut file, &mut contents).unwrap();
+        assert_eq!(contents, "123456789");
+    }
+
+    #[test]
+    fn audit_config_keeps_defaults_for_missing_keys() {
+        let config = AuditConfig::from_toml(
+            "# Only arrays and integers.\nbuild_tools = [\n  \"gcc\",\n  \"rustc\", # comment\n]\nmax_versions = 3\n",
+        )
+        .unwrap();
+        assert_eq!(config.build_tools, ["gcc", "rustc"]);
+        assert_eq!(config.max_versions, 3);
+        assert_eq!(config.dev_suffixes, AuditConfig::default().dev_suffixes);
+
+        assert!(AuditConfig::from_toml("max_version = 3").is_err());
+        assert!(AuditConfig::from_toml("build_tools = [\"gcc\"").is_err());
+    }
+
+    #[test]
+    fn output_env_vars_dont_conflict_with_count_only() {
+        std::env::set_var("NIX_INVESTIGATION_DOT_PATH", "/tmp/closure.dot");
+        let parse = |flags: &[&str]| {
+            Args::try_parse_from(
+                [
+                    "nix-tree-sizes",
+                    "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-root",
+                ]
+                .iter()
+                .chain(flags),
+            )
+        };
+        let count_only = parse(&["--count-only"]);
+        let check = parse(&["--check"]);
+        let byte_count_only = parse(&["--byte-count-only"]);
+        let explicit_output = parse(&["--count-only", "--output", "csv:/tmp/closure.csv"]);
+        std::env::remove_var("NIX_INVESTIGATION_DOT_PATH");
+
+        assert!(count_only.is_ok());
+        assert!(check.is_ok());
+        assert!(byte_count_only.is_ok());
+        assert!(explicit_output.is_err());
+    }
+
+    #[test]
+    fn package_set_cluster_labels_are_escaped() {
+        let tree = PackageTree::builder()
+            .root("pkg-a", 1000)
+            .depends_on("pkg-a", "py\"3-requests-2.28.0", 500)
+            .build()
+            .unwrap();
+        let options = DotOptions {
+            package_set_prefixes: Some(vec!["py\"3".to_string()]),
+            ..Default::default()
+        };
+        let path = temp_path("package-set-clusters.dot");
+        generate_dot_file(&tree, &path, &options).unwrap();
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(contents.contains("subgraph cluster_py_3 {\nlabel = \"py\\\"3\";\n"));
+        assert!(contents.contains("label = \"other\";"));
+    }
+
+    #[test]
+    fn sanitize_for_encoding_normalizes_to_nfc() {
+        // `e` followed by a combining acute accent.
+        let decomposed = "cafe\u{301}";
+        assert_eq!(sanitize_for_encoding(decomposed, false), "café");
+        assert_eq!(sanitize_for_encoding(decomposed, true), "caf\\u00e9");
+        assert_eq!(sanitize_for_encoding("😀", true), "\\ud83d\\ude00");
+    }
+
+    #[test]
+    fn anonymize_conflicts_with_flags_printing_real_names() {
+        let parse = |flags: &[&str]| {
+            Args::try_parse_from(
+                [
+                    "nix-tree-sizes",
+                    "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-root",
+                    "--anonymize",
+                ]
+                .iter()
+                .chain(flags),
+            )
+        };
+        assert!(parse(&[]).is_ok());
+        assert!(parse(&["--why", "glibc"]).is_err());
+        assert!(parse(&["--path", "bash", "glibc"]).is_err());
+        assert!(parse(&["--check"]).is_err());
+        assert!(Args::try_parse_from(["nix-tree-sizes", "--self-analyze", "--anonymize"]).is_err());
+    }
 }